@@ -6,15 +6,23 @@ use dialoguer::theme::ColorfulTheme;
 use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
 use iroh::{Endpoint, RelayMode, SecretKey};
 use qs_core::{
-    common::FilesAvailable,
-    receive::{ReceiveError, Receiver, ReceiverArgs},
-    send::{SendError, Sender, SenderArgs},
-    QuicSendError, QS_ALPN, QS_PROTO_VERSION,
+    alpns,
+    common::{ConnectionState, FileName, FilesAvailable, SymlinkPolicy, DEFAULT_RECONNECT_TIMEOUT},
+    compression::{AcceptedCompression, Compression, CompressionAlgorithm},
+    pause::Pause,
+    rate_limit::{parse_byte_size, RateLimit},
+    receive::{
+        AcceptFiles, CollisionPolicy, DiskSpace, FileAction, ReceiveError, Receiver, ReceiverArgs,
+    },
+    send::{SendError, Sender, SenderArgs, DEFAULT_CONCURRENCY},
+    server::{Server, ServerArgs, ServerEvent},
+    QuicSendError, QS_PROTO_VERSION,
 };
 use std::{
     cell::RefCell,
-    io::{self, Write},
-    path::PathBuf,
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
     rc::Rc,
     str::FromStr,
     time::Duration,
@@ -37,16 +45,143 @@ struct Args {
 enum Mode {
     #[clap(name = "send", about = "Send files", aliases = &["s"])]
     Send {
+        /// Files/directories to send, or `-` to stream stdin
+        #[clap(
+            name = "files or directories",
+            required_unless_present_any = ["text", "clipboard"],
+            conflicts_with_all = ["text", "clipboard"]
+        )]
+        files: Vec<PathBuf>,
+
+        /// Send this text instead of files
+        #[clap(long, conflicts_with = "clipboard")]
+        text: Option<String>,
+
+        /// Send the text on the clipboard instead of files
+        #[clap(long)]
+        clipboard: bool,
+
+        /// How symlinks inside of directories are sent (follow, preserve or skip)
+        #[clap(long, default_value_t = SymlinkPolicy::Follow)]
+        symlinks: SymlinkPolicy,
+
+        /// Number of files that are sent at the same time
+        #[clap(long, short = 'j', default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+
+        /// Compression of the sent data (none, gzip or zstd)
+        #[clap(long, default_value_t = CompressionAlgorithm::Zstd)]
+        compression: CompressionAlgorithm,
+
+        /// Compression level, the default level of the algorithm if not set
+        #[clap(long)]
+        compression_level: Option<i32>,
+
+        /// Also compress files that don't seem to be compressible
+        #[clap(long)]
+        no_adaptive: bool,
+
+        /// Maximum upload speed per second (e.g. 20MiB or 500K)
+        #[clap(long, value_parser = parse_byte_size)]
+        limit: Option<u64>,
+
+        /// Seconds to wait for the receiver to reconnect if the connection is lost, 0 to disable
+        #[clap(long, default_value_t = DEFAULT_RECONNECT_TIMEOUT.as_secs())]
+        reconnect_timeout: u64,
+    },
+    #[clap(
+        name = "serve",
+        about = "Send files to every receiver that connects with the same ticket"
+    )]
+    Serve {
         /// Files/directories to send
         #[clap(name = "files or directories", required = true)]
         files: Vec<PathBuf>,
+
+        /// How symlinks inside of directories are sent (follow, preserve or skip)
+        #[clap(long, default_value_t = SymlinkPolicy::Follow)]
+        symlinks: SymlinkPolicy,
+
+        /// Number of files that are sent to each receiver at the same time
+        #[clap(long, short = 'j', default_value_t = DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+
+        /// Compression of the sent data (none, gzip or zstd)
+        #[clap(long, default_value_t = CompressionAlgorithm::Zstd)]
+        compression: CompressionAlgorithm,
+
+        /// Compression level, the default level of the algorithm if not set
+        #[clap(long)]
+        compression_level: Option<i32>,
+
+        /// Also compress files that don't seem to be compressible
+        #[clap(long)]
+        no_adaptive: bool,
+
+        /// Maximum upload speed per second of all receivers together (e.g. 20MiB or 500K)
+        #[clap(long, value_parser = parse_byte_size)]
+        limit: Option<u64>,
+
+        /// Number of receivers served at the same time, further receivers are turned away
+        #[clap(long)]
+        max_peers: Option<usize>,
+
+        /// Stop once the files were received completely this many times
+        #[clap(long)]
+        max_downloads: Option<usize>,
+
+        /// Share the directory instead of sending it, receivers list it with `qs ls`
+        /// and request paths from it with `qs get`
+        #[clap(long)]
+        share: bool,
+    },
+    #[clap(name = "ls", about = "List a directory shared with `qs serve --share`")]
+    Ls {
+        /// The code to connect to the sender
+        code: String,
+
+        /// Path in the shared directory, the shared directory itself if not set
+        #[clap(default_value = "")]
+        path: String,
+    },
+    #[clap(
+        name = "get",
+        about = "Receive files from a directory shared with `qs serve --share`"
+    )]
+    Get {
+        /// The code to connect to the sender
+        code: String,
+
+        /// Paths in the shared directory
+        #[clap(name = "remote paths", required = true)]
+        paths: Vec<String>,
+
+        /// What to do with files that already exist (resume, overwrite, rename, skip or fail)
+        #[clap(long, short, default_value_t = CollisionPolicy::Resume)]
+        collision: CollisionPolicy,
+
+        /// Custom output directory
+        #[clap(long, short, default_value = ".")]
+        output: PathBuf,
+
+        /// Don't apply the modification times and permissions of the sent files
+        #[clap(long)]
+        no_metadata: bool,
+
+        /// Maximum download speed per second (e.g. 20MiB or 500K)
+        #[clap(long, value_parser = parse_byte_size)]
+        limit: Option<u64>,
     },
     #[clap(name = "receive", about = "Receive files", aliases = &["r"])]
     Receive {
-        /// Overwrite files instead of resuming
-        #[clap(long, short = 'f')]
+        /// Overwrite files instead of resuming, same as `--collision overwrite`
+        #[clap(long, short = 'f', conflicts_with = "collision")]
         overwrite: bool,
 
+        /// What to do with files that already exist (resume, overwrite, rename, skip or fail)
+        #[clap(long, short, default_value_t = CollisionPolicy::Resume)]
+        collision: CollisionPolicy,
+
         /// Custom output directory
         #[clap(long, short, default_value = ".")]
         output: PathBuf,
@@ -54,9 +189,51 @@ enum Mode {
         /// The code to connect to the sender
         code: Option<String>,
 
+        /// `-` to write the data the sender streams from its stdin to stdout
+        #[clap(value_parser = ["-"])]
+        stdout: Option<String>,
+
         /// Automatically accept the files
         #[clap(long, short = 'y')]
         auto_accept: bool,
+
+        /// Put text the sender sends on the clipboard instead of printing it
+        #[clap(long)]
+        clipboard: bool,
+
+        /// Don't apply the modification times and permissions of the sent files
+        #[clap(long)]
+        no_metadata: bool,
+
+        /// Receive the files even if there is not enough free disk space
+        #[clap(long)]
+        ignore_free_space: bool,
+
+        /// Maximum download speed per second (e.g. 20MiB or 500K)
+        #[clap(long, value_parser = parse_byte_size)]
+        limit: Option<u64>,
+
+        /// Seconds to try to reconnect to the sender if the connection is lost, 0 to disable
+        #[clap(long, default_value_t = DEFAULT_RECONNECT_TIMEOUT.as_secs())]
+        reconnect_timeout: u64,
+
+        /// Don't keep a resume journal in the output directory, resume from the file sizes only
+        #[clap(long)]
+        no_journal: bool,
+
+        /// Only receive the files and directories matching the pattern, can be repeated.
+        /// `*` and `?` match within a name, patterns without a `/` match names at any depth
+        /// (e.g. `*.jpg` or `photos/2024`)
+        #[clap(long)]
+        include: Vec<String>,
+
+        /// Compression algorithms the sender may use, the preferred one first (none, gzip or zstd)
+        #[clap(long, value_delimiter = ',', default_values_t = AcceptedCompression::default().algorithms)]
+        accept_compression: Vec<CompressionAlgorithm>,
+
+        /// Highest compression level the sender may use, e.g. to limit memory usage
+        #[clap(long)]
+        max_compression_level: Option<i32>,
     },
 }
 
@@ -66,8 +243,17 @@ enum AppError {
     QuicSendCore(#[from] qs_core::QuicSendError),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("--share needs a single directory")]
+    ShareNeedsDirectory,
+    #[error("stdin (`-`) can not be sent together with files")]
+    StdinWithFiles,
+    #[error("clipboard error: {0}")]
+    Clipboard(String),
 }
 
+/// Name of the stream `qs send -` sends
+const STDIN_NAME: &str = "stdin";
+
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     let args: Args = Args::parse();
@@ -75,6 +261,8 @@ async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt()
         .with_max_level(Level::from_str(&args.log_level.to_string()).unwrap())
+        // stdout may carry the received data, see `qs receive -`
+        .with_writer(std::io::stderr)
         .init();
 
     // Make sure colors work correctly in cmd.exe.
@@ -86,9 +274,13 @@ async fn main() -> color_eyre::Result<()> {
     tracing::debug!("qs {}", QS_PROTO_VERSION);
 
     // Check if the files even exist
-    if let Mode::Send { files, .. } = &args.mode {
+    if let Mode::Send { files, .. } | Mode::Serve { files, .. } = &args.mode {
+        if files.len() > 1 && files.iter().any(|f| is_stdin(f)) {
+            return Err(AppError::StdinWithFiles.into());
+        }
+
         for file in files {
-            if !file.exists() {
+            if !file.exists() && !matches!(args.mode, Mode::Send { .. } if is_stdin(file)) {
                 return Err(QuicSendError::Send(SendError::FileDoesNotExists(file.clone())).into());
             }
         }
@@ -98,7 +290,7 @@ async fn main() -> color_eyre::Result<()> {
 
     let endpoint = Endpoint::builder()
         .secret_key(secret_key)
-        .alpns(vec![QS_ALPN.to_vec()])
+        .alpns(alpns())
         .relay_mode(RelayMode::Default)
         .bind()
         .await
@@ -106,29 +298,51 @@ async fn main() -> color_eyre::Result<()> {
 
     let progress_bars: Rc<RefCell<Option<CliProgressBars>>> = Rc::new(RefCell::new(None));
     let rc_clone = Rc::clone(&progress_bars);
+    let pause = Pause::default();
 
     match args.mode {
-        Mode::Send { files } => {
-            let node_addr = endpoint.node_addr().await.map_err(|e| {
-                AppError::QuicSendCore(QuicSendError::Send(SendError::NodeAddr(e.to_string())))
-            })?;
-
-            let serialized =
-                bincode::serde::encode_to_vec(node_addr, bincode::config::standard()).unwrap();
-            let ticket: String = BASE64_STANDARD_NO_PAD.encode(&serialized);
-
-            println!(
-                "Ticket (copied to your clipboard):\n\n{}\n",
-                ticket.bright_white()
-            );
-            println!("on the other peer, run the following command:\n");
-            println!("{}", "qs receive <ticket>".yellow());
-
-            if let Ok(mut ctx) = ClipboardContext::new() {
-                let _ = ctx.set_contents(ticket);
-            }
+        Mode::Send {
+            files,
+            text,
+            clipboard,
+            symlinks,
+            concurrency,
+            compression,
+            compression_level,
+            no_adaptive,
+            limit,
+            reconnect_timeout,
+        } => {
+            let stream = files.first().is_some_and(|f| is_stdin(f));
+            let text = match (text, clipboard) {
+                (Some(text), _) => Some(text),
+                (None, true) => Some(read_clipboard()?),
+                (None, false) => None,
+            };
 
-            let sender_args = SenderArgs { files };
+            share_ticket(
+                &endpoint,
+                if stream {
+                    "qs receive <ticket> -"
+                } else {
+                    "qs receive <ticket>"
+                },
+            )
+            .await?;
+
+            let sender_args = SenderArgs {
+                files,
+                symlinks,
+                concurrency,
+                compression: Compression {
+                    algorithm: compression,
+                    level: compression_level,
+                    adaptive: !no_adaptive,
+                },
+                rate_limit: RateLimit::new(limit),
+                pause: pause.clone(),
+                reconnect_timeout: Duration::from_secs(reconnect_timeout),
+            };
             let mut sender = Sender::connect(endpoint, sender_args).await?;
 
             // Give iroh some time to switch the connection to direct
@@ -137,6 +351,65 @@ async fn main() -> color_eyre::Result<()> {
             tracing::debug!("connected with type: {:?}", conn_type);
             println!("Connection type: {}", connection_type_info_msg(conn_type));
 
+            if let Some(text) = text {
+                sender
+                    .send_text(
+                        text,
+                        || {
+                            print!("Waiting for the other peer to accept the text...");
+                            io::stdout().flush().unwrap();
+                        },
+                        |accepted| {
+                            if accepted {
+                                println!("\r{}", " ".repeat(50));
+                                println!("{}", "The text was received".green());
+                            }
+                        },
+                    )
+                    .await
+                    .map_err(QuicSendError::Send)?;
+                return Ok(());
+            }
+
+            if stream {
+                let stream_bar: RefCell<Option<ProgressBar>> = RefCell::new(None);
+
+                // stdin is the data, so the transfer can only be paused by the receiver
+                sender
+                    .send_reader(
+                        STDIN_NAME.into(),
+                        &mut tokio::io::stdin(),
+                        || {
+                            print!("Waiting for the other peer to accept the stream...");
+                            io::stdout().flush().unwrap();
+                        },
+                        // The data only follows once the stream is accepted
+                        &mut |last_sent| {
+                            stream_bar
+                                .borrow_mut()
+                                .get_or_insert_with(|| {
+                                    println!("\r{}", " ".repeat(50));
+                                    stream_progress_bar(STDIN_NAME)
+                                })
+                                .inc(last_sent);
+                        },
+                        // In the CLI we don't handle the interruption as the user can just Ctrl+C
+                        &mut || true,
+                        &mut |state| {
+                            if let Some(pb) = &*stream_bar.borrow() {
+                                stream_connection_state(pb, state);
+                            }
+                        },
+                    )
+                    .await
+                    .map_err(QuicSendError::Send)?;
+
+                if let Some(pb) = stream_bar.into_inner() {
+                    pb.finish();
+                }
+                return Ok(());
+            }
+
             sender
                 .send_files(
                     || {
@@ -147,23 +420,188 @@ async fn main() -> color_eyre::Result<()> {
                     |initial_progress| {
                         println!("\r{}", " ".repeat(49));
                         *rc_clone.borrow_mut() = Some(CliProgressBars::new(initial_progress));
+                        toggle_pause_on_enter(pause.clone());
                     },
-                    &mut |last_sent| {
+                    &mut |index, last_sent| {
                         if let Some(pb) = &mut *rc_clone.borrow_mut() {
-                            pb.update(last_sent);
+                            pb.update(index, last_sent);
                         }
                     },
                     // In the CLI we don't handle the interruption as the user can just Ctrl+C
                     &mut || true,
+                    &mut |state| {
+                        if let Some(pb) = &mut *rc_clone.borrow_mut() {
+                            pb.connection_state(state);
+                        }
+                    },
                 )
                 .await
                 .map_err(QuicSendError::Send)?;
         }
+        Mode::Serve {
+            files,
+            symlinks,
+            concurrency,
+            compression,
+            compression_level,
+            no_adaptive,
+            limit,
+            max_peers,
+            max_downloads,
+            share,
+        } => {
+            let share = match (share, files.as_slice()) {
+                (false, _) => None,
+                (true, [dir]) if dir.is_dir() => Some(dir.clone()),
+                (true, _) => return Err(AppError::ShareNeedsDirectory.into()),
+            };
+
+            share_ticket(
+                &endpoint,
+                if share.is_some() {
+                    "qs ls <ticket> [path]\nqs get <ticket> <path>"
+                } else {
+                    "qs receive <ticket>"
+                },
+            )
+            .await?;
+
+            let server_args = ServerArgs {
+                sender: SenderArgs {
+                    files,
+                    symlinks,
+                    concurrency,
+                    compression: Compression {
+                        algorithm: compression,
+                        level: compression_level,
+                        adaptive: !no_adaptive,
+                    },
+                    rate_limit: RateLimit::new(limit),
+                    pause: Pause::default(),
+                    reconnect_timeout: Duration::ZERO,
+                },
+                max_peers,
+                max_downloads,
+                share,
+            };
+            let mut server = Server::new(endpoint, server_args);
+
+            println!("Waiting for receivers, press Ctrl+C to stop");
+
+            let mut progress = ServeProgress::new();
+            let downloads = server
+                .serve(
+                    &mut |event| progress.event(event),
+                    // In the CLI we don't handle the interruption as the user can just Ctrl+C
+                    &mut || true,
+                )
+                .await;
+
+            println!("The files were received {} times", downloads);
+            server.close().await;
+        }
+        Mode::Ls { code, path } => {
+            let receiver_args = ReceiverArgs {
+                collision: CollisionPolicy::default(),
+                preserve_metadata: false,
+                ignore_free_space: false,
+                rate_limit: RateLimit::new(None),
+                pause: pause.clone(),
+                reconnect_timeout: Duration::ZERO,
+                journal: false,
+                compression: AcceptedCompression::default(),
+            };
+            let mut receiver =
+                Receiver::connect(endpoint, parse_ticket(&code)?, receiver_args).await?;
+
+            let entries = receiver
+                .list_dir(&path)
+                .await
+                .map_err(QuicSendError::Receive)?;
+            receiver.close().await;
+
+            let longest_name = entries
+                .iter()
+                .map(|e| e.name.as_str().len())
+                .max()
+                .unwrap_or(0)
+                + 1;
+
+            for entry in entries {
+                match entry.size {
+                    Some(size) => println!(
+                        "{:<width$} {:>10}",
+                        format!("{} ", entry.name).blue(),
+                        HumanBytes(size).to_string().red(),
+                        width = longest_name
+                    ),
+                    None => println!("{}", format!("{}/", entry.name).blue()),
+                }
+            }
+        }
+        Mode::Get {
+            code,
+            paths,
+            collision,
+            output,
+            no_metadata,
+            limit,
+        } => {
+            let receiver_args = ReceiverArgs {
+                collision,
+                preserve_metadata: !no_metadata,
+                ignore_free_space: false,
+                rate_limit: RateLimit::new(limit),
+                pause: pause.clone(),
+                reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+                journal: true,
+                compression: AcceptedCompression::default(),
+            };
+            let mut receiver =
+                Receiver::connect(endpoint, parse_ticket(&code)?, receiver_args).await?;
+
+            receiver
+                .receive_shared(
+                    paths,
+                    |initial_progress| {
+                        let initial_progress = print_actions(initial_progress);
+                        *progress_bars.borrow_mut() = Some(CliProgressBars::new(&initial_progress));
+                        toggle_pause_on_enter(pause.clone());
+                    },
+                    // The requested paths are accepted right away
+                    |_, _| Some(AcceptFiles::all(output.clone())),
+                    &mut |index, last_received| {
+                        if let Some(pb) = &mut *progress_bars.borrow_mut() {
+                            pb.update(index, last_received);
+                        }
+                    },
+                    // In the CLI we don't handle the interruption as the user can just Ctrl+C
+                    &mut || true,
+                    &mut |state| {
+                        if let Some(pb) = &mut *progress_bars.borrow_mut() {
+                            pb.connection_state(state);
+                        }
+                    },
+                )
+                .await
+                .map_err(QuicSendError::Receive)?;
+        }
         Mode::Receive {
             overwrite,
+            collision,
             output,
             code,
+            stdout,
             auto_accept,
+            clipboard,
+            no_metadata,
+            ignore_free_space,
+            limit,
+            reconnect_timeout,
+            no_journal,
+            include,
+            accept_compression,
+            max_compression_level,
         } => {
             let ticket = match code {
                 Some(code) => code,
@@ -172,51 +610,158 @@ async fn main() -> color_eyre::Result<()> {
                     .interact()?,
             };
 
-            let node_addr = BASE64_STANDARD_NO_PAD
-                .decode(ticket.as_bytes())
-                .map_err(|_| {
-                    AppError::QuicSendCore(QuicSendError::Receive(ReceiveError::InvalidCode))
-                })?;
+            let node_addr = parse_ticket(&ticket)?;
 
-            let node_addr: iroh::NodeAddr =
-                bincode::serde::decode_from_slice(&node_addr, bincode::config::standard())
-                    .map_err(|_| {
-                        AppError::QuicSendCore(QuicSendError::Receive(ReceiveError::InvalidCode))
-                    })?
-                    .0;
-
-            let receiver_args = ReceiverArgs { resume: !overwrite };
+            let receiver_args = ReceiverArgs {
+                collision: if overwrite {
+                    CollisionPolicy::Overwrite
+                } else {
+                    collision
+                },
+                preserve_metadata: !no_metadata,
+                ignore_free_space,
+                rate_limit: RateLimit::new(limit),
+                pause: pause.clone(),
+                reconnect_timeout: Duration::from_secs(reconnect_timeout),
+                journal: !no_journal,
+                compression: AcceptedCompression {
+                    algorithms: accept_compression,
+                    max_level: max_compression_level,
+                },
+            };
             let mut receiver = Receiver::connect(endpoint, node_addr, receiver_args).await?;
 
             // Give iroh some time to switch the connection to direct
             std::thread::sleep(Duration::from_secs(4));
             let conn_type = receiver.connection_type().await;
             tracing::debug!("connected with type: {:?}", conn_type);
+
+            // stdout is the data, everything else goes to stderr
+            if stdout.is_some() {
+                eprintln!("Connection type: {}", connection_type_info_msg(conn_type));
+
+                let stream_bar: RefCell<Option<ProgressBar>> = RefCell::new(None);
+                receiver
+                    .receive_stream(
+                        &mut tokio::io::stdout(),
+                        |name| {
+                            eprintln!(
+                                "{}",
+                                "A stream can not be resumed, it starts over if the connection is lost"
+                                    .dimmed()
+                            );
+
+                            let accepted = auto_accept || accept_stream(name);
+                            if accepted {
+                                *stream_bar.borrow_mut() = Some(stream_progress_bar(name.as_str()));
+                                toggle_pause_on_enter(pause.clone());
+                            }
+                            accepted
+                        },
+                        &mut |last_received| {
+                            if let Some(pb) = &*stream_bar.borrow() {
+                                pb.inc(last_received);
+                            }
+                        },
+                        // In the CLI we don't handle the interruption as the user can just Ctrl+C
+                        &mut || true,
+                        &mut |state| {
+                            if let Some(pb) = &*stream_bar.borrow() {
+                                stream_connection_state(pb, state);
+                            }
+                        },
+                    )
+                    .await
+                    .map_err(QuicSendError::Receive)?;
+
+                if let Some(pb) = stream_bar.into_inner() {
+                    pb.finish();
+                }
+                return Ok(());
+            }
+
             println!("Connection type: {}", connection_type_info_msg(conn_type));
 
+            receiver.on_text(move |content| {
+                // The text comes from the peer, it must not control the terminal
+                let printable = escape_control_chars(content);
+
+                if !auto_accept && !accept_text(&printable) {
+                    return false;
+                }
+
+                if clipboard {
+                    match ClipboardContext::new()
+                        .and_then(|mut ctx| ctx.set_contents(content.to_string()))
+                    {
+                        Ok(()) => println!("{}", "Copied the text to your clipboard".green()),
+                        Err(e) => {
+                            tracing::warn!("failed to set the clipboard: {}", e);
+                            println!("{}", printable);
+                        }
+                    }
+                } else {
+                    println!("{}", printable);
+                }
+
+                true
+            });
+
             receiver
                 .receive_files(
                     |initial_progress| {
-                        *progress_bars.borrow_mut() = Some(CliProgressBars::new(initial_progress));
+                        let initial_progress = print_actions(initial_progress);
+                        *progress_bars.borrow_mut() = Some(CliProgressBars::new(&initial_progress));
+                        toggle_pause_on_enter(pause.clone());
                     },
-                    |files_offered| {
+                    |files_offered, required_space| {
+                        let mut selection = (!include.is_empty()).then(|| {
+                            select_files(files_offered, &|path| {
+                                include.iter().any(|p| matches_pattern(p, path))
+                            })
+                        });
+
                         if auto_accept {
                             println!("auto accepting files");
                             tracing::debug!("auto accepting files");
-                            Some(output.clone())
-                        } else if accept_files(files_offered) {
-                            Some(output.clone())
-                        } else {
-                            None
+                            return Some(AcceptFiles {
+                                output_path: output.clone(),
+                                selection,
+                            });
+                        }
+
+                        loop {
+                            // The space depends on the selection, so it's computed again after choosing
+                            let space = required_space(&output, selection.as_deref())
+                                .unwrap_or_else(|e| {
+                                    tracing::warn!("failed to get the free disk space: {}", e);
+                                    Vec::new()
+                                });
+
+                            match accept_files(files_offered, selection.as_deref(), &space) {
+                                Decision::Accept => {
+                                    return Some(AcceptFiles {
+                                        output_path: output.clone(),
+                                        selection,
+                                    })
+                                }
+                                Decision::Reject => return None,
+                                Decision::Choose => selection = Some(choose_files(files_offered)),
+                            }
                         }
                     },
-                    &mut |last_received| {
+                    &mut |index, last_received| {
                         if let Some(pb) = &mut *progress_bars.borrow_mut() {
-                            pb.update(last_received);
+                            pb.update(index, last_received);
                         }
                     },
                     // In the CLI we don't handle the interruption as the user can just Ctrl+C
                     &mut || true,
+                    &mut |state| {
+                        if let Some(pb) = &mut *progress_bars.borrow_mut() {
+                            pb.connection_state(state);
+                        }
+                    },
                 )
                 .await
                 .map_err(QuicSendError::Receive)?;
@@ -226,20 +771,149 @@ async fn main() -> color_eyre::Result<()> {
     Ok(())
 }
 
-/// Ask the receiver if they want to accept the files
-fn accept_files(files_offered: &[FilesAvailable]) -> bool {
+/// What the receiver answered to the offer
+enum Decision {
+    Accept,
+    Reject,
+    /// Pick the files to receive
+    Choose,
+}
+
+/// Print the actions taken for existing files,
+/// and return the initial progress without them (name, current, total)
+fn print_actions(initial_progress: &[(String, u64, u64, FileAction)]) -> Vec<(String, u64, u64)> {
+    for (name, _, _, action) in initial_progress {
+        match action {
+            FileAction::Rename(path) => {
+                println!("{} exists, receiving to {}", name, path.display())
+            }
+            FileAction::Skip => println!("{} exists, skipping", name),
+            FileAction::Excluded => println!("{} is not selected", name),
+            _ => {}
+        }
+    }
+
+    initial_progress
+        .iter()
+        .map(|(name, current, total, _)| (name.clone(), *current, *total))
+        .collect()
+}
+
+/// If `path` stands for stdin (`-`)
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Ask the receiver if they want to accept the stream, it is written to stdout
+fn accept_stream(name: &FileName) -> bool {
+    eprintln!(
+        "The sender streams {} of unknown size, it is written to stdout",
+        name.to_string().blue()
+    );
+
+    dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Do you want to receive it?")
+        .default(true)
+        .interact()
+        .unwrap_or(false)
+}
+
+/// Ask the receiver if they want to accept the text, it is shown once accepted
+fn accept_text(content: &str) -> bool {
+    println!(
+        "The sender sends a text ({} lines, {})",
+        content.lines().count(),
+        HumanBytes(content.len() as u64).to_string().red()
+    );
+
+    dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Do you want to receive it?")
+        .default(true)
+        .interact()
+        .unwrap_or(false)
+}
+
+/// Escape the control characters of `text` except for line breaks and tabs,
+/// e.g. ANSI escape sequences that change the terminal or set its clipboard (OSC 52)
+fn escape_control_chars(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\n' | '\t' => c.to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// The text on the clipboard
+fn read_clipboard() -> Result<String, AppError> {
+    ClipboardContext::new()
+        .and_then(|mut ctx| ctx.get_contents())
+        .map_err(|e| AppError::Clipboard(e.to_string()))
+}
+
+/// Decode the address of the sender from its ticket
+fn parse_ticket(ticket: &str) -> Result<iroh::NodeAddr, AppError> {
+    let invalid = || AppError::QuicSendCore(QuicSendError::Receive(ReceiveError::InvalidCode));
+
+    let node_addr = BASE64_STANDARD_NO_PAD
+        .decode(ticket.as_bytes())
+        .map_err(|_| invalid())?;
+
+    let (node_addr, _) = bincode::serde::decode_from_slice(&node_addr, bincode::config::standard())
+        .map_err(|_| invalid())?;
+
+    Ok(node_addr)
+}
+
+/// Print the ticket of `endpoint` and copy it to the clipboard,
+/// `command` is what the other peer runs with it
+async fn share_ticket(endpoint: &Endpoint, command: &str) -> Result<(), AppError> {
+    let node_addr = endpoint.node_addr().await.map_err(|e| {
+        AppError::QuicSendCore(QuicSendError::Send(SendError::NodeAddr(e.to_string())))
+    })?;
+
+    let serialized = bincode::serde::encode_to_vec(node_addr, bincode::config::standard()).unwrap();
+    let ticket: String = BASE64_STANDARD_NO_PAD.encode(&serialized);
+
+    println!(
+        "Ticket (copied to your clipboard):\n\n{}\n",
+        ticket.bright_white()
+    );
+    println!("on the other peer, run the following command:\n");
+    println!("{}", command.yellow());
+
+    if let Ok(mut ctx) = ClipboardContext::new() {
+        let _ = ctx.set_contents(ticket);
+    }
+
+    Ok(())
+}
+
+/// Ask the receiver if they want to accept the files,
+/// only the `selection` of them if there is one
+fn accept_files(
+    files_offered: &[FilesAvailable],
+    selection: Option<&[Option<FilesAvailable>]>,
+    space: &[DiskSpace],
+) -> Decision {
     println!("The following files will be received:\n");
 
-    let longest_name = files_offered
+    let files: Vec<&FilesAvailable> = match selection {
+        Some(selection) => selection.iter().flatten().collect(),
+        None => files_offered.iter().collect(),
+    };
+
+    let longest_name = files
         .iter()
-        .map(|f| f.name().len())
+        .map(|f| f.name().as_str().len())
         .max()
         .unwrap_or(0)
         + 1;
 
-    let total_size = files_offered.iter().map(|f| f.size()).sum::<u64>();
+    let total_size = files.iter().map(|f| f.size()).sum::<u64>();
 
-    for file in files_offered {
+    for file in files {
         let size = file.size();
         let size_human_bytes = HumanBytes(size).to_string();
         let name = file.name();
@@ -256,16 +930,152 @@ fn accept_files(files_offered: &[FilesAvailable]) -> bool {
         );
     }
 
-    println!("\nTotal size: {}", HumanBytes(total_size).to_string().red());
+    let free = space
+        .iter()
+        .map(|s| HumanBytes(s.available).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
 
-    dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+    if free.is_empty() {
+        println!("\nTotal size: {}", HumanBytes(total_size).to_string().red());
+    } else {
+        println!(
+            "\nTotal size: {} ({} free)",
+            HumanBytes(total_size).to_string().red(),
+            free.green()
+        );
+    }
+
+    for s in space.iter().filter(|s| !s.is_sufficient()) {
+        println!(
+            "{}",
+            format!(
+                "Not enough free space in {}, {} needed",
+                s.path.display(),
+                HumanBytes(s.needed)
+            )
+            .yellow()
+        );
+    }
+
+    let choice = dialoguer::Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Do you want to receive these files?")
+        .items(&["Yes", "No", "Choose files"])
+        .default(0)
         .interact()
-        .unwrap_or_default()
+        .unwrap_or(1);
+
+    match choice {
+        0 => Decision::Accept,
+        2 => Decision::Choose,
+        _ => Decision::Reject,
+    }
+}
+
+/// Let the receiver pick files and directories from the offered tree,
+/// a picked directory is received as a whole
+fn choose_files(files_offered: &[FilesAvailable]) -> Vec<Option<FilesAvailable>> {
+    fn list(
+        file: &FilesAvailable,
+        path: PathBuf,
+        depth: usize,
+        items: &mut Vec<(PathBuf, String)>,
+    ) {
+        let label = match file {
+            FilesAvailable::Dir { name, .. } => format!("{}/", name),
+            FilesAvailable::File { name, size, .. } => format!("{} ({})", name, HumanBytes(*size)),
+            FilesAvailable::Symlink { name, .. } => name.to_string(),
+        };
+        items.push((path.clone(), format!("{}{}", "  ".repeat(depth), label)));
+
+        if let FilesAvailable::Dir { files, .. } = file {
+            for file in files {
+                list(file, path.join(file.name()), depth + 1, items);
+            }
+        }
+    }
+
+    let mut items = Vec::new();
+    for file in files_offered {
+        list(file, PathBuf::new().join(file.name()), 0, &mut items);
+    }
+
+    let chosen = dialoguer::MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select the files to receive (space to select, enter to confirm)")
+        .items(&items.iter().map(|(_, label)| label).collect::<Vec<_>>())
+        .interact()
+        .unwrap_or_default();
+
+    let chosen: Vec<&PathBuf> = chosen.into_iter().map(|i| &items[i].0).collect();
+    select_files(files_offered, &|path| chosen.contains(&&path.to_path_buf()))
+}
+
+/// The part of each offered file/dir that is included
+fn select_files(
+    files_offered: &[FilesAvailable],
+    include: &dyn Fn(&Path) -> bool,
+) -> Vec<Option<FilesAvailable>> {
+    files_offered
+        .iter()
+        .map(|f| f.select(f.name().as_ref(), include))
+        .collect()
+}
+
+/// Match a `--include` pattern against the path of an offered file, relative to the output directory
+fn matches_pattern(pattern: &str, path: &Path) -> bool {
+    fn wildcard(pattern: &[char], name: &[char]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some('*'), _) => {
+                wildcard(&pattern[1..], name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+            }
+            (Some('?'), Some(_)) => wildcard(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => wildcard(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+
+    let matches = |pattern: &str, name: &std::ffi::OsStr| {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.to_string_lossy().chars().collect();
+        wildcard(&pattern, &name)
+    };
+
+    let pattern = pattern.trim_matches('/');
+    if !pattern.contains('/') {
+        return path.file_name().is_some_and(|name| matches(pattern, name));
+    }
+
+    let parts: Vec<&str> = pattern.split('/').collect();
+    parts.len() == path.components().count()
+        && parts
+            .iter()
+            .zip(path.components())
+            .all(|(pattern, component)| matches(pattern, component.as_os_str()))
+}
+
+/// Pause or resume the transfer every time enter is pressed
+fn toggle_pause_on_enter(pause: Pause) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+
+    // stdout may be the received data
+    eprintln!("{}", "Press enter to pause or resume the transfer".dimmed());
+
+    std::thread::spawn(move || {
+        for line in io::stdin().lines() {
+            if line.is_err() {
+                break;
+            }
+            pause.toggle();
+        }
+    });
 }
 
 /// Send and receive progress bars
 struct CliProgressBars {
+    multi_progress: MultiProgress,
     /// Per file/dir progress bars
     progerss_bars: Vec<ProgressBar>,
     /// Only used when multiple files are sent
@@ -331,34 +1141,165 @@ impl CliProgressBars {
         };
 
         Self {
+            multi_progress: mp,
             progerss_bars: bars,
             total_bar,
         }
     }
 
-    /// Update the progress bars
-    /// it is expected that the files will be downloaded in order
-    pub fn update(&mut self, mut progress: u64) {
+    /// Update the progress bar of the file/dir at `index`
+    pub fn update(&mut self, index: usize, progress: u64) {
         if let Some(pb) = &self.total_bar {
             pb.inc(progress);
         }
 
-        for pb in &self.progerss_bars {
-            let remaining = pb
-                .length()
-                .unwrap_or_default()
-                .saturating_sub(pb.position());
+        if let Some(pb) = self.progerss_bars.get(index) {
+            pb.inc(progress);
+        }
+    }
 
-            if remaining == 0 {
-                continue;
+    /// Show a lost connection, and reset the progress once the transfer is resumed
+    pub fn connection_state(&mut self, state: ConnectionState) {
+        match state {
+            ConnectionState::Lost => {
+                let _ = self
+                    .multi_progress
+                    .println("Connection lost, reconnecting...".yellow().to_string());
+            }
+            ConnectionState::Reconnecting { attempt } => {
+                tracing::debug!("reconnect attempt {}", attempt);
             }
+            ConnectionState::Resumed { progress } => {
+                let _ = self
+                    .multi_progress
+                    .println("Reconnected, resuming the transfer".green().to_string());
 
-            let this_bar_progress = progress.min(remaining);
-            pb.inc(this_bar_progress);
-            progress -= this_bar_progress;
+                for (pb, (_, current, _)) in self.progerss_bars.iter().zip(&progress) {
+                    pb.set_position(*current);
+                }
 
-            if progress == 0 {
-                break;
+                if let Some(pb) = &self.total_bar {
+                    pb.set_position(progress.iter().map(|(_, current, _)| current).sum());
+                }
+            }
+            ConnectionState::Paused(state) => {
+                let msg = if state.local {
+                    "Transfer paused, press enter to resume".yellow()
+                } else if state.remote {
+                    "The other peer paused the transfer".yellow()
+                } else {
+                    "Transfer resumed".green()
+                };
+                let _ = self.multi_progress.println(msg.to_string());
+            }
+        }
+    }
+}
+
+/// Progress bar of a stream with unknown size, drawn to stderr
+fn stream_progress_bar(name: &str) -> ProgressBar {
+    let style = ProgressStyle::default_spinner()
+        .template("{spinner:.green} {prefix} {bytes} ({binary_bytes_per_sec})")
+        .unwrap();
+
+    let pb = ProgressBar::new_spinner();
+    pb.set_prefix(name.to_string());
+    pb.set_style(style);
+    pb
+}
+
+/// Show when one of the peers pauses or resumes a stream
+fn stream_connection_state(pb: &ProgressBar, state: ConnectionState) {
+    if let ConnectionState::Paused(state) = state {
+        let msg = if state.local {
+            "Transfer paused, press enter to resume".yellow()
+        } else if state.remote {
+            "The other peer paused the transfer".yellow()
+        } else {
+            "Transfer resumed".green()
+        };
+        pb.println(msg.to_string());
+    }
+}
+
+/// One progress bar per receiver of `qs serve`
+struct ServeProgress {
+    multi_progress: MultiProgress,
+    /// Progress bar of each connected receiver
+    bars: HashMap<usize, ProgressBar>,
+}
+
+impl ServeProgress {
+    fn new() -> Self {
+        Self {
+            multi_progress: MultiProgress::new(),
+            bars: HashMap::new(),
+        }
+    }
+
+    fn println(&self, msg: impl ToString) {
+        let _ = self.multi_progress.println(msg.to_string());
+    }
+
+    /// Show what happens with the receivers, see [qs_core::server::Server::serve]
+    fn event(&mut self, event: ServerEvent) {
+        match event {
+            ServerEvent::Connected { peer, node_id } => {
+                self.println(format!(
+                    "Receiver {} connected ({})",
+                    peer,
+                    node_id.fmt_short()
+                ));
+            }
+            ServerEvent::Busy { node_id } => self.println(
+                format!("Turned away {}, too many receivers", node_id.fmt_short()).yellow(),
+            ),
+            ServerEvent::Decision { peer, accepted } => {
+                if !accepted {
+                    self.println(format!("Receiver {} rejected the files", peer).yellow());
+                }
+            }
+            ServerEvent::InitialProgress { peer, progress } => {
+                let style = ProgressStyle::default_bar()
+                    .template(
+                        "{spinner:.green} {prefix} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+                    )
+                    .unwrap()
+                    .progress_chars("#>-");
+
+                let total = progress.iter().map(|(_, _, total)| total).sum();
+                let pb = self.multi_progress.add(ProgressBar::new(total));
+                pb.set_prefix(format!("Receiver {}", peer));
+                pb.set_style(style);
+                pb.set_position(progress.iter().map(|(_, current, _)| current).sum());
+                pb.reset_eta();
+                self.bars.insert(peer, pb);
+            }
+            ServerEvent::Sent { peer, bytes, .. } => {
+                if let Some(pb) = self.bars.get(&peer) {
+                    pb.inc(bytes);
+                }
+            }
+            ServerEvent::Connection { peer, state } => {
+                if let ConnectionState::Paused(state) = state {
+                    let msg = if state.remote {
+                        format!("Receiver {} paused the transfer", peer).yellow()
+                    } else {
+                        format!("Receiver {} resumed the transfer", peer).green()
+                    };
+                    self.println(msg);
+                }
+            }
+            ServerEvent::Finished { peer, result } => {
+                if let Some(pb) = self.bars.remove(&peer) {
+                    pb.finish();
+                }
+
+                match result {
+                    Ok(true) => self.println(format!("Receiver {} is done", peer).green()),
+                    Ok(false) => {}
+                    Err(e) => self.println(format!("Receiver {} failed: {}", peer, e).red()),
+                }
             }
         }
     }
@@ -376,3 +1317,109 @@ fn connection_type_info_msg(connection_type: Option<iroh::endpoint::ConnectionTy
 
     "???".red().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use qs_core::common::FileMeta;
+
+    fn file(name: &str, size: u64) -> FilesAvailable {
+        FilesAvailable::File {
+            name: name.into(),
+            meta: FileMeta::default(),
+            size,
+        }
+    }
+
+    fn dir(name: &str, files: Vec<FilesAvailable>) -> FilesAvailable {
+        FilesAvailable::Dir {
+            name: name.into(),
+            meta: FileMeta::default(),
+            files,
+        }
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        // Without a separator only the name is matched, at any depth
+        assert!(matches_pattern("*.jpg", Path::new("a.jpg")));
+        assert!(matches_pattern("*.jpg", Path::new("photos/2024/a.jpg")));
+        assert!(!matches_pattern("*.jpg", Path::new("a.jpg.txt")));
+        assert!(matches_pattern("?.txt", Path::new("docs/a.txt")));
+        assert!(!matches_pattern("?.txt", Path::new("docs/ab.txt")));
+
+        // With a separator the whole path is matched
+        assert!(matches_pattern("photos/2024", Path::new("photos/2024")));
+        assert!(matches_pattern("/photos/2024", Path::new("photos/2024")));
+        assert!(matches_pattern("photos/2024/", Path::new("photos/2024")));
+        assert!(matches_pattern(
+            "photos/*/a.jpg",
+            Path::new("photos/2024/a.jpg")
+        ));
+        assert!(!matches_pattern("photos/2024", Path::new("photos/2023")));
+        assert!(!matches_pattern(
+            "photos/2024",
+            Path::new("backup/photos/2024")
+        ));
+    }
+
+    #[test]
+    fn test_escape_control_chars() {
+        // An OSC 52 sequence would set the clipboard of the terminal
+        assert_eq!(
+            escape_control_chars("\x1b]52;c;aGVsbG8=\x07"),
+            "\\u{1b}]52;c;aGVsbG8=\\u{7}"
+        );
+        assert_eq!(escape_control_chars("fake\rreal"), "fake\\rreal");
+
+        // Line breaks and tabs are kept
+        assert_eq!(escape_control_chars("a\n\tb"), "a\n\tb");
+    }
+
+    #[test]
+    fn test_select_files() {
+        let offered = vec![
+            dir(
+                "photos",
+                vec![
+                    dir("2023", vec![file("c.jpg", 30)]),
+                    dir("2024", vec![file("a.jpg", 10), file("b.png", 20)]),
+                ],
+            ),
+            file("notes.txt", 5),
+        ];
+        let select =
+            |pattern: &str| select_files(&offered, &|path: &Path| matches_pattern(pattern, path));
+
+        // A selected directory is taken as a whole
+        assert_eq!(
+            select("photos/2024"),
+            vec![
+                Some(dir(
+                    "photos",
+                    vec![dir("2024", vec![file("a.jpg", 10), file("b.png", 20)])]
+                )),
+                None,
+            ]
+        );
+
+        assert_eq!(
+            select("*.jpg"),
+            vec![
+                Some(dir(
+                    "photos",
+                    vec![
+                        dir("2023", vec![file("c.jpg", 30)]),
+                        dir("2024", vec![file("a.jpg", 10)]),
+                    ]
+                )),
+                None,
+            ]
+        );
+
+        // Nothing in the nested directories matches
+        assert_eq!(select("photos/*.png"), vec![None, None]);
+        assert_eq!(select("notes.txt"), vec![None, Some(file("notes.txt", 5))]);
+    }
+}
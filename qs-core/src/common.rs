@@ -1,32 +1,154 @@
-use std::path::Path;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
 
-use async_compression::tokio::write::{GzipDecoder, GzipEncoder};
+use crate::{
+    compression::{compress_packet, decompress_packet, Compression},
+    packets::ErrorKind,
+    pause::{Pause, PauseState},
+    rate_limit::RateLimit,
+    version::{Capabilities, Hello},
+    BUF_SIZE,
+};
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Name of a file or directory as it is sent to the other peer
+///
+/// Names that are not valid UTF-8 keep their original bytes,
+/// their display form escapes the invalid bytes as `%XX`.
+/// The receiver uses the original bytes where the platform allows it (unix),
+/// otherwise the display form.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Encode, Decode, Serialize, Deserialize)]
+pub struct FileName {
+    /// Display form of the name
+    name: String,
+    /// Original bytes, if the name is not valid UTF-8
+    raw: Option<Vec<u8>>,
+}
+
+impl FileName {
+    /// Create the name from a local file name, this never fails
+    pub fn from_os_str(name: &std::ffi::OsStr) -> Self {
+        if let Some(name) = name.to_str() {
+            return name.into();
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let bytes = name.as_bytes();
+            let mut escaped = String::new();
+            for chunk in bytes.utf8_chunks() {
+                escaped.push_str(chunk.valid());
+                for byte in chunk.invalid() {
+                    escaped.push_str(&format!("%{:02X}", byte));
+                }
+            }
+
+            Self {
+                name: escaped,
+                raw: Some(bytes.to_vec()),
+            }
+        }
+
+        // Only unix names can be arbitrary bytes
+        #[cfg(not(unix))]
+        name.to_string_lossy().as_ref().into()
+    }
+
+    /// Display form of the name
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// Local name of the file, see [FileName]
+    pub fn as_path(&self) -> &Path {
+        #[cfg(unix)]
+        if let Some(raw) = &self.raw {
+            use std::os::unix::ffi::OsStrExt;
+            return Path::new(std::ffi::OsStr::from_bytes(raw));
+        }
+
+        Path::new(&self.name)
+    }
+
+    /// Check if the name can safely be joined onto a directory,
+    /// see [is_valid_file_name]
+    pub fn is_valid(&self) -> bool {
+        is_valid_file_name(&self.name)
+            && is_valid_file_name(&self.as_path().as_os_str().to_string_lossy())
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            raw: None,
+        }
+    }
+}
+
+impl AsRef<Path> for FileName {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl std::fmt::Display for FileName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// Name of the file or directory at `path`, also for paths like `.` that end in no name
+fn path_name(path: &Path) -> std::io::Result<FileName> {
+    match path.file_name() {
+        Some(name) => Ok(FileName::from_os_str(name)),
+        None => match path.canonicalize()?.file_name() {
+            Some(name) => Ok(FileName::from_os_str(name)),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("path has no name: {}", path.display()),
+            )),
+        },
+    }
+}
 
 /// Tree structure that represents the files that
 /// are being sent/received
 #[derive(Debug, PartialEq, Clone, Encode, Decode, Hash)]
 pub enum FileSendRecvTree {
     File {
-        name: String,
+        name: FileName,
+        meta: FileMeta,
         skip: u64,
         size: u64,
     },
     Dir {
-        name: String,
+        name: FileName,
+        meta: FileMeta,
         files: Vec<FileSendRecvTree>,
     },
+    Symlink {
+        name: FileName,
+        target: String,
+    },
 }
 
 impl FileSendRecvTree {
     /// Name of the file or directory
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &FileName {
         match self {
             FileSendRecvTree::File { name, .. } => name,
             FileSendRecvTree::Dir { name, .. } => name,
+            FileSendRecvTree::Symlink { name, .. } => name,
         }
     }
 
@@ -35,6 +157,7 @@ impl FileSendRecvTree {
         match self {
             FileSendRecvTree::File { size, .. } => *size,
             FileSendRecvTree::Dir { files, .. } => files.iter().map(|f| f.size()).sum(),
+            FileSendRecvTree::Symlink { .. } => 0,
         }
     }
 
@@ -44,7 +167,168 @@ impl FileSendRecvTree {
         match self {
             FileSendRecvTree::File { skip, .. } => *skip,
             FileSendRecvTree::Dir { files, .. } => files.iter().map(|f| f.skip()).sum(),
+            FileSendRecvTree::Symlink { .. } => 0,
+        }
+    }
+
+    /// Metadata of the file or directory, symlinks have none
+    pub fn meta(&self) -> Option<&FileMeta> {
+        match self {
+            FileSendRecvTree::File { meta, .. } => Some(meta),
+            FileSendRecvTree::Dir { meta, .. } => Some(meta),
+            FileSendRecvTree::Symlink { .. } => None,
+        }
+    }
+
+    /// Append all files of this tree to `files` in transfer order,
+    /// `path` is the local path of this file or directory
+    /// and `entry` the index of the tree in the transfer
+    pub fn flatten(&self, path: &Path, entry: usize, files: &mut Vec<TransferFile>) {
+        let mut stack = vec![(path.to_path_buf(), self)];
+
+        while let Some((path, tree)) = stack.pop() {
+            match tree {
+                FileSendRecvTree::File {
+                    meta, skip, size, ..
+                } => files.push(TransferFile {
+                    path,
+                    entry,
+                    meta: *meta,
+                    skip: *skip,
+                    size: *size,
+                }),
+                FileSendRecvTree::Dir {
+                    files: children, ..
+                } => {
+                    // Reversed, so the files are popped in their original order
+                    for file in children.iter().rev() {
+                        stack.push((path.join(file.name()), file));
+                    }
+                }
+                FileSendRecvTree::Symlink { .. } => {}
+            }
+        }
+    }
+
+    /// The part of the tree that is also part of `selected` (see [FilesAvailable::select]),
+    /// unselected directories and symlinks are not created even though they carry no data
+    /// # Returns
+    /// - [std::option::Option::None] if nothing of the tree is selected
+    pub fn retain_selected(self, selected: &FilesAvailable) -> Option<FileSendRecvTree> {
+        if self.name() != selected.name() {
+            return None;
+        }
+
+        match (self, selected) {
+            (file @ FileSendRecvTree::File { .. }, FilesAvailable::File { .. }) => Some(file),
+            (link @ FileSendRecvTree::Symlink { .. }, FilesAvailable::Symlink { .. }) => Some(link),
+            (
+                FileSendRecvTree::Dir { name, meta, files },
+                FilesAvailable::Dir {
+                    files: selected, ..
+                },
+            ) => {
+                let files = files
+                    .into_iter()
+                    .filter_map(|file| {
+                        let selected = selected.iter().find(|s| s.name() == file.name())?;
+                        file.retain_selected(selected)
+                    })
+                    .collect();
+
+                Some(FileSendRecvTree::Dir { name, meta, files })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A single file of a transfer, the sender and receiver identify it
+/// by its index in the list built with [FileSendRecvTree::flatten]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TransferFile {
+    /// Local path of the file
+    pub path: PathBuf,
+    /// Index of the top-level file or directory this file belongs to
+    pub entry: usize,
+    pub meta: FileMeta,
+    pub skip: u64,
+    pub size: u64,
+}
+
+/// The limits and callbacks the data of a transfer is sent or received with
+///
+/// [RateLimit] and [Pause] are shared with their clones, so the ones of the
+/// [crate::send::SenderArgs] or [crate::receive::ReceiverArgs] can be used.
+pub struct TransferContext<P, C> {
+    /// Limit of the transfer speed
+    pub rate_limit: RateLimit,
+    /// The transfer waits while it is paused
+    pub pause: Pause,
+    /// Callback with the bytes that were sent or received
+    pub progress: P,
+    /// Callback to check if the transfer should continue
+    pub should_continue: C,
+}
+
+/// Metadata of a file or directory that is preserved during the transfer
+#[derive(
+    Debug, PartialEq, Eq, Clone, Copy, Default, Encode, Decode, Hash, Serialize, Deserialize,
+)]
+pub struct FileMeta {
+    /// Modification time since the unix epoch
+    pub mtime: Option<Duration>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+}
+
+impl FileMeta {
+    /// Collect the metadata that is preserved
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Self { mtime, mode }
+    }
+
+    /// Apply the metadata to the file or directory at `path`,
+    /// a modification time that is already set is left alone (the file may be read-only)
+    pub fn apply(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(mtime) = self.mtime {
+            let mtime = UNIX_EPOCH + mtime;
+            let current = std::fs::metadata(path)?.modified().ok();
+            if current != Some(mtime) {
+                if path.is_dir() {
+                    // Directories can't be opened as a file on windows
+                    #[cfg(unix)]
+                    std::fs::File::open(path)?.set_modified(mtime)?;
+                } else {
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_modified(mtime)?;
+                }
+            }
         }
+
+        // Set the permissions last, they might make the file read-only
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        Ok(())
     }
 }
 
@@ -52,42 +336,331 @@ impl FileSendRecvTree {
 #[derive(Debug, PartialEq, Clone, Encode, Decode, Hash, Serialize, Deserialize)]
 pub enum FilesAvailable {
     File {
-        name: String,
+        name: FileName,
+        meta: FileMeta,
         size: u64,
     },
     Dir {
-        name: String,
+        name: FileName,
+        meta: FileMeta,
         files: Vec<FilesAvailable>,
     },
+    /// A symlink that is recreated on the receiver,
+    /// `target` is relative to the directory containing the link
+    Symlink { name: FileName, target: String },
+}
+
+/// How symlinks inside of directories are handled by the sender
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Send the file or directory the link points to
+    #[default]
+    Follow,
+    /// Send the link itself, the receiver recreates it
+    Preserve,
+    /// Leave links out of the transfer
+    Skip,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!(
+                "invalid symlink policy {:?}, expected follow, preserve or skip",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SymlinkPolicy::Follow => write!(f, "follow"),
+            SymlinkPolicy::Preserve => write!(f, "preserve"),
+            SymlinkPolicy::Skip => write!(f, "skip"),
+        }
+    }
 }
+
 /// Get the available files
-pub fn get_files_available(path: &Path) -> std::io::Result<FilesAvailable> {
-    if path.is_file() {
-        Ok(FilesAvailable::File {
-            name: path.file_name().unwrap().to_str().unwrap().to_string(),
-            size: path.metadata()?.len(),
-        })
+///
+/// `path` itself is always followed, `symlinks` applies to the links inside of directories.
+/// Links that would lead back into one of their parent directories are left out.
+pub fn get_files_available(
+    path: &Path,
+    symlinks: SymlinkPolicy,
+) -> std::io::Result<FilesAvailable> {
+    collect_files_available(path, path.metadata()?, symlinks, &mut Vec::new())
+}
+
+/// Recursive part of [get_files_available],
+/// `ancestors` are the canonical paths of the directories that are currently being collected
+fn collect_files_available(
+    path: &Path,
+    metadata: std::fs::Metadata,
+    symlinks: SymlinkPolicy,
+    ancestors: &mut Vec<PathBuf>,
+) -> std::io::Result<FilesAvailable> {
+    if metadata.is_file() {
+        return Ok(FilesAvailable::File {
+            name: path_name(path)?,
+            meta: FileMeta::from_metadata(&metadata),
+            size: metadata.len(),
+        });
+    }
+
+    ancestors.push(path.canonicalize()?);
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        let mut metadata = path.symlink_metadata()?;
+        if metadata.is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Preserve => {
+                    files.push(read_symlink(&path)?);
+                    continue;
+                }
+                SymlinkPolicy::Follow => {
+                    metadata = path.metadata()?;
+                    if metadata.is_dir() && ancestors.contains(&path.canonicalize()?) {
+                        tracing::warn!("skipping symlink loop at {}", path.display());
+                        continue;
+                    }
+                }
+            }
+        }
+
+        files.push(collect_files_available(
+            &path, metadata, symlinks, ancestors,
+        )?);
+    }
+
+    ancestors.pop();
+
+    Ok(FilesAvailable::Dir {
+        name: path_name(path)?,
+        meta: FileMeta::from_metadata(&metadata),
+        files,
+    })
+}
+
+/// Read the symlink at `path` without following it
+fn read_symlink(path: &Path) -> std::io::Result<FilesAvailable> {
+    let target = std::fs::read_link(path)?;
+    let target = target.to_str().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("symlink target is not valid UTF-8: {}", path.display()),
+        )
+    })?;
+
+    Ok(FilesAvailable::Symlink {
+        name: path_name(path)?,
+        target: target.to_string(),
+    })
+}
+
+/// Extension of the sidecar files that are being received
+pub const PARTIAL_EXTENSION: &str = "qs-part";
+
+/// Path of the sidecar file that `path` is received into,
+/// it will be renamed to `path` once the file is complete
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Inverse of [partial_path],
+/// returns [std::option::Option::None] if `path` is not a partial file
+fn final_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    let suffix = format!(".{}", PARTIAL_EXTENSION);
+
+    #[cfg(unix)]
+    let name = {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(name.as_bytes().strip_suffix(suffix.as_bytes())?)
+    };
+    #[cfg(not(unix))]
+    let name = name.to_str()?.strip_suffix(&suffix)?;
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(path.with_file_name(name))
+}
+
+/// Path of the local file that holds the data of `path`,
+/// this is `path` itself if it exists, otherwise its [partial_path]
+pub fn local_data_path(path: &Path) -> PathBuf {
+    if path.exists() {
+        path.to_path_buf()
     } else {
-        let mut files = Vec::new();
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-            files.push(get_files_available(&path)?);
+        partial_path(path)
+    }
+}
+
+/// Check if `name` can safely be joined onto a directory,
+/// i.e. it is a single normal path component on every platform
+pub fn is_valid_file_name(name: &str) -> bool {
+    if name.is_empty() || name == "." || name == ".." {
+        return false;
+    }
+
+    // Separators of all platforms, not just the current one
+    if name.contains(['/', '\\', '\0']) {
+        return false;
+    }
+
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    )
+}
+
+/// Check if a symlink `target` stays inside of the output directory,
+/// `depth` is the number of directories between the output directory and the link
+pub fn is_contained_symlink_target(target: &str, depth: usize) -> bool {
+    // Backslashes are separators on windows
+    if target.is_empty() || target.contains(['\\', '\0']) {
+        return false;
+    }
+
+    let mut depth = depth;
+    for component in Path::new(target).components() {
+        match component {
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return false,
         }
+    }
 
-        Ok(FilesAvailable::Dir {
-            name: path.file_name().unwrap().to_str().unwrap().to_string(),
-            files,
+    true
+}
+
+/// Find the first name that is used by several entries of `files`,
+/// they would be received to the same path (e.g. `a/x` and `b/x` are both sent as `x`)
+pub fn find_duplicate_name(files: &[FilesAvailable]) -> Option<&FileName> {
+    let mut names = HashSet::new();
+    files
+        .iter()
+        .map(|f| f.name())
+        .find(|name| !names.insert(*name))
+}
+
+/// Find the first symlink in `files` that points outside of the output directory,
+/// either by itself (see [is_contained_symlink_target])
+/// or by resolving through another offered symlink
+/// # Returns
+/// * `Some((name, target))` of the offending link
+pub fn find_escaping_symlink(files: &[FilesAvailable]) -> Option<(&FileName, &str)> {
+    let mut links = Vec::new();
+    for file in files {
+        file.collect_symlinks(Path::new(""), &mut links);
+    }
+
+    let link_paths: HashSet<PathBuf> = links.iter().map(|(dir, name, _)| dir.join(name)).collect();
+
+    links
+        .into_iter()
+        .find(|(dir, _, target)| {
+            !is_contained_symlink_target(target, dir.components().count())
+                || passes_through_symlink(dir, target, &link_paths)
         })
+        .map(|(_, name, target)| (name, target))
+}
+
+/// Check if `target`, resolved from the directory `dir`, continues past one of `links`.
+/// The link would be followed, which [is_contained_symlink_target] does not account for
+/// (e.g. `x -> ..` and `y -> x/../..`)
+fn passes_through_symlink(dir: &Path, target: &str, links: &HashSet<PathBuf>) -> bool {
+    let mut path = dir.to_path_buf();
+    let mut components = Path::new(target).components().peekable();
+
+    while let Some(component) = components.next() {
+        match component {
+            std::path::Component::Normal(name) => {
+                path.push(name);
+                if components.peek().is_some() && links.contains(&path) {
+                    return true;
+                }
+            }
+            std::path::Component::ParentDir => {
+                path.pop();
+            }
+            _ => {}
+        }
     }
+
+    false
+}
+
+/// Get the files that are (partially) present on the receiver,
+/// partial files are listed under their final name
+pub fn get_files_received(path: &Path) -> std::io::Result<FilesAvailable> {
+    if path.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        return read_symlink(path);
+    }
+
+    let partial = partial_path(path);
+    if !path.exists() && partial.is_file() {
+        let metadata = partial.metadata()?;
+        return Ok(FilesAvailable::File {
+            name: path_name(path)?,
+            meta: FileMeta::from_metadata(&metadata),
+            size: metadata.len(),
+        });
+    }
+
+    if path.is_file() {
+        return get_files_available(path, SymlinkPolicy::Preserve);
+    }
+
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        match final_path(&path) {
+            // Already listed under the final name
+            Some(final_path) if final_path.exists() => continue,
+            Some(final_path) => files.push(get_files_received(&final_path)?),
+            None => files.push(get_files_received(&path)?),
+        }
+    }
+
+    Ok(FilesAvailable::Dir {
+        name: path_name(path)?,
+        meta: FileMeta::from_metadata(&path.metadata()?),
+        files,
+    })
 }
 
 impl FilesAvailable {
     /// Name of the file or directory
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &FileName {
         match self {
             FilesAvailable::File { name, .. } => name,
             FilesAvailable::Dir { name, .. } => name,
+            FilesAvailable::Symlink { name, .. } => name,
         }
     }
 
@@ -96,75 +669,106 @@ impl FilesAvailable {
         match self {
             FilesAvailable::File { size, .. } => *size,
             FilesAvailable::Dir { files, .. } => files.iter().map(|f| f.size()).sum(),
+            FilesAvailable::Symlink { .. } => 0,
+        }
+    }
+
+    /// Metadata of the file or directory, symlinks have none
+    pub fn meta(&self) -> Option<&FileMeta> {
+        match self {
+            FilesAvailable::File { meta, .. } => Some(meta),
+            FilesAvailable::Dir { meta, .. } => Some(meta),
+            FilesAvailable::Symlink { .. } => None,
+        }
+    }
+
+    /// Find the first name in the tree that is not a single, plain path component
+    /// (e.g. `..`, `a/b` or an absolute path), see [FileName::is_valid]
+    pub fn find_invalid_name(&self) -> Option<&FileName> {
+        if !self.name().is_valid() {
+            return Some(self.name());
+        }
+
+        match self {
+            FilesAvailable::File { .. } | FilesAvailable::Symlink { .. } => None,
+            FilesAvailable::Dir { files, .. } => files.iter().find_map(|f| f.find_invalid_name()),
+        }
+    }
+
+    /// Collect the symlinks of the tree as `(directory, name, target)`,
+    /// `dir` is the directory of this entry relative to the output directory
+    fn collect_symlinks<'a>(
+        &'a self,
+        dir: &Path,
+        links: &mut Vec<(PathBuf, &'a FileName, &'a str)>,
+    ) {
+        match self {
+            FilesAvailable::File { .. } => {}
+            FilesAvailable::Dir { name, files, .. } => {
+                let dir = dir.join(name);
+                for file in files {
+                    file.collect_symlinks(&dir, links);
+                }
+            }
+            FilesAvailable::Symlink { name, target } => {
+                links.push((dir.to_path_buf(), name, target));
+            }
         }
     }
 
     /// Convert the tree to a [FileSendRecvTree]
     pub fn to_send_recv_tree(&self) -> FileSendRecvTree {
         match self {
-            FilesAvailable::File { name, size } => FileSendRecvTree::File {
-                name: name.to_string(),
+            FilesAvailable::File { name, meta, size } => FileSendRecvTree::File {
+                name: name.clone(),
+                meta: *meta,
                 skip: 0,
                 size: *size,
             },
-            FilesAvailable::Dir { name, files } => FileSendRecvTree::Dir {
-                name: name.to_string(),
+            FilesAvailable::Dir { name, meta, files } => FileSendRecvTree::Dir {
+                name: name.clone(),
+                meta: *meta,
                 files: files.iter().map(|f| f.to_send_recv_tree()).collect(),
             },
+            FilesAvailable::Symlink { name, target } => FileSendRecvTree::Symlink {
+                name: name.clone(),
+                target: target.to_string(),
+            },
         }
     }
 
     /// Fully/partially remove skipped files from the tree
     /// - Returns [std::option::Option::None] if the tree is fully skipped
-    /// - panics if the tree roots do not match
-    pub fn remove_skipped(&self, to_skip: &FilesToSkip) -> Option<FileSendRecvTree> {
+    /// - Returns an error if the trees do not match, see [FilesAvailable::validate_skip]
+    pub fn remove_skipped(
+        &self,
+        to_skip: &FilesToSkip,
+    ) -> Result<Option<FileSendRecvTree>, InvalidSkipList> {
+        self.validate_skip(to_skip)?;
+
         match (self, to_skip) {
-            (
-                FilesAvailable::File { name, size },
-                FilesToSkip::File {
-                    name: skip_name,
-                    skip,
-                },
-            ) => {
-                if name == skip_name && size <= skip {
-                    None
+            (FilesAvailable::File { name, meta, size }, FilesToSkip::File { skip, .. }) => {
+                if size <= skip {
+                    Ok(None)
                 } else {
-                    Some(FileSendRecvTree::File {
+                    Ok(Some(FileSendRecvTree::File {
                         name: name.clone(),
+                        meta: *meta,
                         skip: *skip,
                         size: *size,
-                    })
+                    }))
                 }
             }
             (
-                FilesAvailable::Dir { name, files },
+                FilesAvailable::Dir { name, meta, files },
                 FilesToSkip::Dir {
-                    name: skip_name,
-                    files: skip_files,
+                    files: skip_files, ..
                 },
             ) => {
-                if name != skip_name {
-                    panic!("Tree roots do not match");
-                }
-
                 let mut remaining_files = Vec::new();
                 for file in files {
-                    if let Some(skip_file) = skip_files.iter().find(|sf| match (file, sf) {
-                        (
-                            FilesAvailable::File { name, .. },
-                            FilesToSkip::File {
-                                name: skip_name, ..
-                            },
-                        ) => name == skip_name,
-                        (
-                            FilesAvailable::Dir { name, .. },
-                            FilesToSkip::Dir {
-                                name: skip_name, ..
-                            },
-                        ) => name == skip_name,
-                        _ => false,
-                    }) {
-                        if let Some(remaining) = file.remove_skipped(skip_file) {
+                    if let Some(skip_file) = skip_files.iter().find(|sf| file.matches(sf)) {
+                        if let Some(remaining) = file.remove_skipped(skip_file)? {
                             remaining_files.push(remaining);
                         }
                     } else {
@@ -173,46 +777,288 @@ impl FilesAvailable {
                 }
 
                 if remaining_files.is_empty() {
-                    None
+                    Ok(None)
                 } else {
-                    Some(FileSendRecvTree::Dir {
+                    Ok(Some(FileSendRecvTree::Dir {
                         name: name.clone(),
+                        meta: *meta,
                         files: remaining_files,
+                    }))
+                }
+            }
+            _ => unreachable!("validated above"),
+        }
+    }
+
+    /// Check that `to_skip` describes a part of this tree,
+    /// i.e. the names and kinds match and no file skips more bytes than its size
+    pub fn validate_skip(&self, to_skip: &FilesToSkip) -> Result<(), InvalidSkipList> {
+        if !self.matches(to_skip) {
+            return Err(InvalidSkipList::Mismatch {
+                expected: self.name().to_string(),
+                got: to_skip.name().to_string(),
+            });
+        }
+
+        match (self, to_skip) {
+            (FilesAvailable::File { name, size, .. }, FilesToSkip::File { skip, .. }) => {
+                if skip > size {
+                    return Err(InvalidSkipList::SkipExceedsSize {
+                        name: name.to_string(),
+                        skip: *skip,
+                        size: *size,
+                    });
+                }
+            }
+            (
+                FilesAvailable::Dir { files, .. },
+                FilesToSkip::Dir {
+                    files: skip_files, ..
+                },
+            ) => {
+                for skip_file in skip_files {
+                    match files.iter().find(|f| f.matches(skip_file)) {
+                        Some(file) => file.validate_skip(skip_file)?,
+                        None => {
+                            return Err(InvalidSkipList::UnknownFile(skip_file.name().to_string()))
+                        }
+                    }
+                }
+            }
+            _ => unreachable!("checked by matches"),
+        }
+
+        Ok(())
+    }
+
+    /// Check if `to_skip` has the same name and kind (file or directory)
+    fn matches(&self, to_skip: &FilesToSkip) -> bool {
+        match (self, to_skip) {
+            (
+                FilesAvailable::File { name, .. },
+                FilesToSkip::File {
+                    name: skip_name, ..
+                },
+            ) => name == skip_name,
+            (
+                FilesAvailable::Dir { name, .. },
+                FilesToSkip::Dir {
+                    name: skip_name, ..
+                },
+            ) => name == skip_name,
+            _ => false,
+        }
+    }
+
+    /// The part of the tree the receiver selected, `path` is the path of this file or directory
+    /// relative to the output directory. A selected directory is taken as a whole,
+    /// otherwise only its selected files are kept.
+    /// # Returns
+    /// - [std::option::Option::None] if nothing is selected
+    pub fn select(&self, path: &Path, include: &dyn Fn(&Path) -> bool) -> Option<FilesAvailable> {
+        if include(path) {
+            return Some(self.clone());
+        }
+
+        match self {
+            FilesAvailable::Dir { name, meta, files } => {
+                let selected: Vec<FilesAvailable> = files
+                    .iter()
+                    .filter_map(|f| f.select(&path.join(f.name()), include))
+                    .collect();
+
+                (!selected.is_empty()).then(|| FilesAvailable::Dir {
+                    name: name.clone(),
+                    meta: *meta,
+                    files: selected,
+                })
+            }
+            FilesAvailable::File { .. } | FilesAvailable::Symlink { .. } => None,
+        }
+    }
+
+    /// The files of the tree that are not part of `selected` (see [FilesAvailable::select]),
+    /// they are fully skipped without verifying anything.
+    /// Only files carry data, the receiver drops everything else that is not selected
+    /// with [FileSendRecvTree::retain_selected]
+    /// # Returns
+    /// - [std::option::Option::None] if everything is selected
+    pub fn excluded(&self, selected: Option<&FilesAvailable>) -> Option<FilesToSkip> {
+        match (self, selected) {
+            (FilesAvailable::File { name, size, .. }, None) => Some(FilesToSkip::File {
+                name: name.clone(),
+                skip: *size,
+                prefix_hash: None,
+            }),
+            (FilesAvailable::Dir { name, files, .. }, selected) => {
+                let selected = match selected {
+                    Some(FilesAvailable::Dir { files, .. }) => files.as_slice(),
+                    _ => &[],
+                };
+
+                let excluded: Vec<FilesToSkip> = files
+                    .iter()
+                    .filter_map(|file| {
+                        let selected = selected
+                            .iter()
+                            .find(|s| s.name() == file.name() && s.is_dir() == file.is_dir());
+                        file.excluded(selected)
                     })
+                    .collect();
+
+                (!excluded.is_empty()).then(|| FilesToSkip::Dir {
+                    name: name.clone(),
+                    files: excluded,
+                })
+            }
+            // Symlinks carry no data
+            _ => None,
+        }
+    }
+
+    /// Bytes of the files that `to_skip` skips as a whole, because the receiver
+    /// did not select them or already has them. The sender does not know which of the two,
+    /// so it leaves them out of the progress like the receiver does with the excluded files.
+    /// `to_skip` has to be valid for this tree (see [FilesAvailable::validate_skip])
+    pub fn fully_skipped(&self, to_skip: &FilesToSkip) -> u64 {
+        match (self, to_skip) {
+            (FilesAvailable::File { size, .. }, FilesToSkip::File { skip, .. }) if skip >= size => {
+                *size
+            }
+            (
+                FilesAvailable::Dir { files, .. },
+                FilesToSkip::Dir {
+                    files: skip_files, ..
+                },
+            ) => skip_files
+                .iter()
+                .filter_map(|skip| {
+                    files
+                        .iter()
+                        .find(|f| f.matches(skip))
+                        .map(|f| f.fully_skipped(skip))
+                })
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self, FilesAvailable::Dir { .. })
+    }
+
+    /// Local paths of all files in the tree, in the order of [FileSendRecvTree::flatten],
+    /// `path` is the local path of this file or directory
+    pub fn file_paths(&self, path: &Path) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        self.collect_file_paths(path, &mut paths);
+        paths
+    }
+
+    fn collect_file_paths(&self, path: &Path, paths: &mut Vec<PathBuf>) {
+        match self {
+            FilesAvailable::File { .. } => paths.push(path.to_path_buf()),
+            FilesAvailable::Dir { files, .. } => {
+                for file in files {
+                    file.collect_file_paths(&path.join(file.name()), paths);
                 }
             }
-            _ => panic!("Tree roots do not match"),
+            FilesAvailable::Symlink { .. } => {}
+        }
+    }
+
+    /// Build the files to skip from recorded progress, `progress` yields one item
+    /// for every file in the order of [FilesAvailable::file_paths].
+    /// `skip(path, size, progress)` returns the bytes to skip and their hash.
+    /// # Returns
+    /// - [std::option::Option::None] if no files can be skipped
+    pub fn skippable_from<T, F>(
+        &self,
+        path: &Path,
+        progress: &mut impl Iterator<Item = T>,
+        skip: &F,
+    ) -> Option<FilesToSkip>
+    where
+        F: Fn(&Path, u64, T) -> Option<(u64, [u8; blake3::OUT_LEN])>,
+    {
+        match self {
+            FilesAvailable::File { name, size, .. } => {
+                let (skip, hash) = skip(path, *size, progress.next()?)?;
+                Some(FilesToSkip::File {
+                    name: name.clone(),
+                    skip,
+                    prefix_hash: Some(hash),
+                })
+            }
+            FilesAvailable::Dir { name, files, .. } => {
+                // Every child takes its items, also if nothing of it is skipped
+                let skippable_files: Vec<FilesToSkip> = files
+                    .iter()
+                    .filter_map(|f| f.skippable_from(&path.join(f.name()), progress, skip))
+                    .collect();
+
+                (!skippable_files.is_empty()).then(|| FilesToSkip::Dir {
+                    name: name.clone(),
+                    files: skippable_files,
+                })
+            }
+            FilesAvailable::Symlink { .. } => None,
         }
     }
 
     /// Compare two trees and return the files that can be skipped.
     /// (e.g. compare local and remote files, returning those that can be skipped during transfer).
     /// it is expected that ``self`` is larger than ``local_files``
+    /// Local files that are larger than the offered ones are not skipped.
+    /// The prefix hashes are not set, see [FilesToSkip::hash_prefixes]
     /// # Returns
     /// - [std::option::Option::None] if no files can be skipped
     pub fn get_skippable(&self, local_files: &FilesAvailable) -> Option<FilesToSkip> {
+        self.skippable_with(local_files, &|size, local_size| {
+            (local_size <= size).then_some(local_size)
+        })
+    }
+
+    /// Compare two trees and return the files that already exist locally,
+    /// they are fully skipped without verifying their content
+    /// # Returns
+    /// - [std::option::Option::None] if no files exist locally
+    pub fn get_existing(&self, local_files: &FilesAvailable) -> Option<FilesToSkip> {
+        self.skippable_with(local_files, &|size, _| Some(size))
+    }
+
+    /// Walk both trees and decide for every file with a local counterpart
+    /// how many bytes to skip, `skip(size, local_size)`
+    fn skippable_with(
+        &self,
+        local_files: &FilesAvailable,
+        skip: &dyn Fn(u64, u64) -> Option<u64>,
+    ) -> Option<FilesToSkip> {
         match (self, local_files) {
             (
-                FilesAvailable::File { name, .. },
+                FilesAvailable::File { name, size, .. },
                 FilesAvailable::File {
                     name: local_name,
                     size: local_size,
+                    ..
                 },
             ) => {
-                if name == local_name {
-                    Some(FilesToSkip::File {
-                        name: name.clone(),
-                        skip: *local_size,
-                    })
-                } else {
-                    None
+                if name != local_name {
+                    return None;
                 }
+
+                skip(*size, *local_size).map(|skip| FilesToSkip::File {
+                    name: name.clone(),
+                    skip,
+                    prefix_hash: None,
+                })
             }
             (
-                FilesAvailable::Dir { name, files },
+                FilesAvailable::Dir { name, files, .. },
                 FilesAvailable::Dir {
                     name: local_name,
                     files: local_files,
+                    ..
                 },
             ) => {
                 if name != local_name {
@@ -236,7 +1082,7 @@ impl FilesAvailable {
                         ) => name == local_name,
                         _ => false,
                     }) {
-                        if let Some(skippable) = file.get_skippable(remote_file) {
+                        if let Some(skippable) = file.skippable_with(remote_file, skip) {
                             skippable_files.push(skippable);
                         }
                     }
@@ -256,22 +1102,37 @@ impl FilesAvailable {
     }
 }
 
+/// A skip list that does not match the offered files
+#[derive(Debug, Error, PartialEq)]
+pub enum InvalidSkipList {
+    #[error("expected {expected} entries, got {got}")]
+    Length { expected: usize, got: usize },
+    #[error("expected {expected:?}, got {got:?}")]
+    Mismatch { expected: String, got: String },
+    #[error("{0:?} was not offered")]
+    UnknownFile(String),
+    #[error("{name:?} skips {skip} bytes, but has only {size}")]
+    SkipExceedsSize { name: String, skip: u64, size: u64 },
+}
+
 /// Tree structure that represents files that have been requested for skipping
 #[derive(Debug, PartialEq, Clone, Encode, Decode, Hash)]
 pub enum FilesToSkip {
     File {
-        name: String,
+        name: FileName,
         skip: u64,
+        /// BLAKE3 hash of the first `skip` bytes of the local file
+        prefix_hash: Option<[u8; blake3::OUT_LEN]>,
     },
     Dir {
-        name: String,
+        name: FileName,
         files: Vec<FilesToSkip>,
     },
 }
 
 impl FilesToSkip {
     /// Name of the file or directory
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &FileName {
         match self {
             FilesToSkip::File { name, .. } => name,
             FilesToSkip::Dir { name, .. } => name,
@@ -286,25 +1147,249 @@ impl FilesToSkip {
             FilesToSkip::Dir { files, .. } => files.iter().map(|f| f.skip()).sum(),
         }
     }
+
+    /// Combine two skip lists of the same file or directory,
+    /// where both skip the same file the entry of `self` is kept
+    pub fn merge(self, other: Option<FilesToSkip>) -> FilesToSkip {
+        match (self, other) {
+            (
+                FilesToSkip::Dir { name, mut files },
+                Some(FilesToSkip::Dir {
+                    name: other_name,
+                    files: other_files,
+                }),
+            ) if name == other_name => {
+                for other in other_files {
+                    match files.iter().position(|f| f.name() == other.name()) {
+                        Some(i) => {
+                            let file = files.remove(i);
+                            files.insert(i, file.merge(Some(other)));
+                        }
+                        None => files.push(other),
+                    }
+                }
+
+                FilesToSkip::Dir { name, files }
+            }
+            (this, _) => this,
+        }
+    }
+
+    /// Hash the already present prefix of every file,
+    /// `path` is the local path of this file or directory (see [local_data_path])
+    pub fn hash_prefixes(&mut self, path: &Path) -> std::io::Result<()> {
+        match self {
+            FilesToSkip::File {
+                skip, prefix_hash, ..
+            } => {
+                *prefix_hash = Some(hash_file_prefix(&local_data_path(path), *skip)?);
+            }
+            FilesToSkip::Dir { files, .. } => {
+                for file in files {
+                    let path = path.join(file.name());
+                    file.hash_prefixes(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare the prefix hashes with the local files,
+    /// files that do not match will be restarted from zero.
+    /// Files without a hash are only skipped if they are skipped as a whole
+    /// (the receiver keeps its own file or did not select it), otherwise they are restarted too.
+    /// `path` is the local path of this file or directory
+    pub fn verify_prefixes(&mut self, path: &Path) -> std::io::Result<()> {
+        match self {
+            FilesToSkip::File {
+                skip, prefix_hash, ..
+            } => {
+                if *skip == 0 {
+                    return Ok(());
+                }
+
+                let matches = match prefix_hash {
+                    Some(hash) => hash_file_prefix(path, *skip)? == *hash,
+                    // No data of the file is sent, so there is nothing to append to
+                    None => *skip >= std::fs::metadata(path)?.len(),
+                };
+
+                if !matches {
+                    tracing::debug!("prefix mismatch, restarting {}", path.display());
+                    *skip = 0;
+                    *prefix_hash = None;
+                }
+            }
+            FilesToSkip::Dir { files, .. } => {
+                for file in files {
+                    let path = path.join(file.name());
+                    file.verify_prefixes(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
-pub async fn send_packet<P: Encode + std::fmt::Debug>(
-    packet: P,
-    conn: &iroh::endpoint::Connection,
-) -> std::io::Result<()> {
-    tracing::debug!("Sending packet: {:?}", packet);
-    let mut send = conn.open_uni().await?;
+/// BLAKE3 hash of the first `len` bytes of the file at `path`.
+/// If the file is shorter than `len`, the hash of the whole file is returned.
+fn hash_file_prefix(path: &Path, len: u64) -> std::io::Result<[u8; blake3::OUT_LEN]> {
+    use std::io::Read;
 
-    let data = bincode::encode_to_vec(&packet, bincode::config::standard()).unwrap();
-    let compressed = compress_gzip(&data).await?;
-    send.write_all(&compressed).await?;
+    let file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(file.take(len))?;
 
-    send.flush().await?;
-    send.finish()?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Feed the first `len` bytes of `file` into `hasher`.
+/// The cursor of `file` will be positioned at `len` afterwards.
+pub async fn hash_prefix<R>(
+    file: &mut R,
+    len: u64,
+    hasher: &mut blake3::Hasher,
+) -> std::io::Result<()>
+where
+    R: tokio::io::AsyncReadExt + tokio::io::AsyncSeekExt + Unpin,
+{
+    file.seek(tokio::io::SeekFrom::Start(0)).await?;
+
+    let mut buf = vec![0; BUF_SIZE];
+    let mut read = 0;
+
+    while read < len {
+        let to_read = std::cmp::min(BUF_SIZE as u64, len - read);
+        let n = file.read_exact(&mut buf[..to_read as usize]).await?;
+
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
 
     Ok(())
 }
 
+/// Largest control frame that is accepted, compressed or not.
+/// Enough for the offer of a directory with many thousands of files
+pub(crate) const MAX_FRAME_SIZE: u32 = 8 * 1024 * 1024;
+
+/// Encode a packet as a length-prefixed, compressed bincode frame
+async fn encode_frame<P>(packet: &P, compression: Compression) -> std::io::Result<Vec<u8>>
+where
+    P: Encode + std::fmt::Debug,
+{
+    tracing::debug!("Sending packet: {:?}", packet);
+
+    let data = bincode::encode_to_vec(packet, bincode::config::standard()).unwrap();
+    let compressed = compress_packet(&data, compression).await?;
+
+    let len = u32::try_from(compressed.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_SIZE)
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "packet is too large")
+        })?;
+
+    let mut frame = Vec::with_capacity(4 + compressed.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&compressed);
+
+    Ok(frame)
+}
+
+/// Writes packets as frames
+///
+/// Writing is cancel safe, the rest of a frame whose write was cancelled
+/// is written in front of the next one.
+pub(crate) struct FrameWriter<W> {
+    writer: W,
+    /// Bytes of the frames that are not written yet
+    unsent: Vec<u8>,
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            unsent: Vec::new(),
+        }
+    }
+
+    pub async fn write<P>(&mut self, packet: &P, compression: Compression) -> std::io::Result<()>
+    where
+        P: Encode + std::fmt::Debug,
+    {
+        let frame = encode_frame(packet, compression).await?;
+        self.unsent.extend_from_slice(&frame);
+
+        while !self.unsent.is_empty() {
+            let n = self.writer.write(&self.unsent).await?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            self.unsent.drain(..n);
+        }
+
+        self.writer.flush().await
+    }
+}
+
+/// Reads frames written with [FrameWriter]
+///
+/// Reading is cancel safe, the bytes of a frame whose read was cancelled
+/// are kept for the next read.
+pub(crate) struct FrameReader<R> {
+    reader: R,
+    /// Bytes read of the next frames
+    unread: Vec<u8>,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            unread: Vec::new(),
+        }
+    }
+
+    pub async fn read<P>(&mut self) -> Result<P, PacketRecvError>
+    where
+        P: Decode<()> + std::fmt::Debug,
+    {
+        let mut buf = vec![0; BUF_SIZE];
+
+        loop {
+            if let Some(header) = self.unread.first_chunk::<4>() {
+                let len = u32::from_be_bytes(*header);
+                if len > MAX_FRAME_SIZE {
+                    return Err(PacketRecvError::FrameTooLarge(len));
+                }
+
+                let end = 4 + len as usize;
+                if self.unread.len() >= end {
+                    let frame: Vec<u8> = self.unread.drain(..end).collect();
+                    let decompressed = decompress_packet(&frame[4..]).await?;
+                    let packet =
+                        bincode::decode_from_slice(&decompressed, bincode::config::standard())?.0;
+
+                    tracing::debug!("Received packet: {:?}", packet);
+
+                    return Ok(packet);
+                }
+            }
+
+            // The frame grows in steps, so a peer can't make us allocate its announced length
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.unread.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PacketRecvError {
     #[error("io error: {0}")]
@@ -313,86 +1398,357 @@ pub enum PacketRecvError {
     EncodeError(#[from] bincode::error::DecodeError),
     #[error("connection error: {0}")]
     Connection(#[from] iroh::endpoint::ConnectionError),
-    #[error("read error {0}")]
-    Read(#[from] iroh::endpoint::ReadError),
+    #[error("control frame too large: {0} bytes")]
+    FrameTooLarge(u32),
 }
 
-pub async fn receive_packet<P: Decode<()> + std::fmt::Debug>(
-    conn: &iroh::endpoint::Connection,
-) -> Result<P, PacketRecvError> {
-    let mut recv = conn.accept_uni().await?;
-    let mut buf = Vec::new();
+/// The long-lived bidirectional stream the control packets are sent on,
+/// separate from the streams of the file data
+pub struct ControlChannel {
+    send: FrameWriter<iroh::endpoint::SendStream>,
+    recv: FrameReader<iroh::endpoint::RecvStream>,
+    /// Compression of the sent packets, none until it is negotiated
+    pub compression: Compression,
+}
+
+impl ControlChannel {
+    /// Open the channel, the other peer only sees it once the first packet is sent
+    pub async fn open(
+        conn: &iroh::endpoint::Connection,
+    ) -> Result<Self, iroh::endpoint::ConnectionError> {
+        let (send, recv) = conn.open_bi().await?;
+
+        Ok(Self {
+            send: FrameWriter::new(send),
+            recv: FrameReader::new(recv),
+            compression: Compression::NONE,
+        })
+    }
+
+    /// Accept the channel opened by the other peer
+    pub async fn accept(
+        conn: &iroh::endpoint::Connection,
+    ) -> Result<Self, iroh::endpoint::ConnectionError> {
+        let (send, recv) = conn.accept_bi().await?;
+
+        Ok(Self {
+            send: FrameWriter::new(send),
+            recv: FrameReader::new(recv),
+            compression: Compression::NONE,
+        })
+    }
+
+    /// Send a packet, this is cancel safe (see [FrameWriter])
+    pub async fn send<P: Encode + std::fmt::Debug>(&mut self, packet: P) -> std::io::Result<()> {
+        self.send.write(&packet, self.compression).await
+    }
 
-    loop {
-        let mut data = vec![0; 1024];
-        if let Some(n) = recv.read(&mut data).await? {
-            buf.extend_from_slice(&data[..n]);
-            continue;
+    /// Receive a packet, this is cancel safe (see [FrameReader])
+    pub async fn receive<P: Decode<()> + std::fmt::Debug>(&mut self) -> Result<P, PacketRecvError> {
+        self.recv.read().await
+    }
+
+    /// Send the [Hello] that starts the channel, before any packet
+    pub async fn send_hello(&mut self, hello: &Hello) -> std::io::Result<()> {
+        tracing::debug!("Sending hello: {:?}", hello);
+        hello.write(&mut self.send.writer).await
+    }
+
+    /// Receive the [Hello] of the other peer, before any packet
+    pub async fn receive_hello(&mut self) -> Result<Hello, PacketRecvError> {
+        let hello = Hello::read(&mut self.recv.reader).await?;
+        tracing::debug!("Received hello: {:?}", hello);
+        Ok(hello)
+    }
+}
+
+/// How long the peers try to reconnect after the connection was lost
+pub const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// State of the connection, reported while a transfer is running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection to the other peer was lost
+    Lost,
+    /// Trying to reach the other peer again (receiver only)
+    Reconnecting { attempt: u32 },
+    /// The transfer continues on a new connection,
+    /// with the progress of each file/dir (name, current, total)
+    Resumed { progress: Vec<(String, u64, u64)> },
+    /// One of the peers paused or resumed the transfer, the connection stays open
+    Paused(PauseState),
+}
+
+/// The control channel of a transfer, once both peers agreed on the protocol
+pub(crate) struct Session {
+    pub control: ControlChannel,
+    /// The features both peers support
+    pub capabilities: Capabilities,
+    /// Identifies the transfer when the receiver reconnects
+    pub id: u64,
+    /// The other peer, only the receiver of the session can resume it
+    pub peer: iroh::NodeId,
+}
+
+/// Reason that is sent when `should_continue` stops the transfer
+pub const CANCEL_REASON: &str = "stopped by the user";
+
+/// How long a peer that stops the transfer waits for the other peer to read why,
+/// and how long a failed transfer waits for the reason the other peer stopped it
+const PEER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// QUIC application codes the connection is closed with, so the other peer knows why
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// The transfer is over or the offer was answered
+    Normal = 0,
+    /// The connection does not resume the session that was lost
+    UnknownSession = 1,
+    /// One of the peers cancelled the transfer
+    Cancelled = 2,
+    /// One of the peers stopped the transfer because of an error
+    Error = 3,
+    /// The server already serves as many receivers as it allows
+    Busy = 4,
+}
+
+impl CloseCode {
+    /// Close `conn` with this code and a human readable reason
+    pub fn close(self, conn: &iroh::endpoint::Connection, reason: &str) {
+        conn.close((self as u32).into(), reason.as_bytes());
+    }
+}
+
+/// Why the other peer stopped the transfer early
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerStop {
+    Cancelled { reason: String },
+    Failed { kind: ErrorKind, message: String },
+}
+
+impl PeerStop {
+    /// The reason the other peer gave when it closed `conn`,
+    /// if it closed it with [CloseCode::Cancelled] or [CloseCode::Error]
+    pub fn from_close(conn: &iroh::endpoint::Connection) -> Option<Self> {
+        let iroh::endpoint::ConnectionError::ApplicationClosed(close) = conn.close_reason()? else {
+            return None;
+        };
+        let reason = String::from_utf8_lossy(&close.reason).to_string();
+
+        match close.error_code.into_inner() {
+            code if code == CloseCode::Cancelled as u64 => Some(PeerStop::Cancelled { reason }),
+            // The kind is only sent in the error packet
+            code if code == CloseCode::Error as u64 => Some(PeerStop::Failed {
+                kind: ErrorKind::Other,
+                message: reason,
+            }),
+            _ => None,
         }
+    }
+}
+
+/// A packet the other peer sends on the control channel while the file data is transferred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlPacket {
+    /// The peer paused or resumed the transfer
+    Paused(bool),
+    /// The peer stopped the transfer
+    Stop(PeerStop),
+}
 
-        break;
+/// Handle the control channel while the file data is transferred
+///
+/// The transfer usually ends while this runs, the channel can still be used afterwards
+/// because its reads and writes are cancel safe.
+///
+/// Tells the other peer when the transfer is paused or resumed on this side
+/// and applies its pause state, if both peers support pausing.
+/// Every change is reported to `connection_callback`.
+/// `paused_packet` builds the packet to send and `read_packet` reads a received one.
+///
+/// # Returns
+/// Why the other peer stopped the transfer, this only returns once it did
+pub async fn exchange_control<S, R>(
+    control: &mut ControlChannel,
+    pause: &Pause,
+    capabilities: Capabilities,
+    paused_packet: impl Fn(bool) -> S,
+    read_packet: impl Fn(R) -> Option<ControlPacket>,
+    connection_callback: &mut impl FnMut(ConnectionState),
+) -> PeerStop
+where
+    S: Encode + std::fmt::Debug,
+    R: Decode<()> + std::fmt::Debug,
+{
+    let ControlChannel {
+        send,
+        recv,
+        compression,
+    } = control;
+
+    let pausable = capabilities.contains(Capabilities::PAUSE);
+
+    // A new connection starts out with the other peer not paused
+    let mut reported = pause.state();
+    pause.set_remote(false);
+    let mut state = pause.subscribe();
+
+    let receive = async {
+        // The transfer itself notices a lost connection
+        while let Ok(packet) = recv.read::<R>().await {
+            match read_packet(packet) {
+                Some(ControlPacket::Paused(paused)) if pausable => pause.set_remote(paused),
+                Some(ControlPacket::Stop(stop)) => return stop,
+                _ => tracing::warn!("ignoring an unexpected packet during the transfer"),
+            }
+        }
+
+        futures::future::pending().await
+    };
+
+    let report = async {
+        let mut sent = false;
+
+        loop {
+            let current = *state.borrow_and_update();
+
+            if pausable && current.local != sent {
+                if send
+                    .write(&paused_packet(current.local), *compression)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                sent = current.local;
+            }
+
+            if current != reported {
+                connection_callback(ConnectionState::Paused(current));
+                reported = current;
+            }
+
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+
+        futures::future::pending::<()>().await
+    };
+
+    tokio::select! {
+        stop = receive => stop,
+        _ = report => unreachable!(),
+    }
+}
+
+/// Tell the other peer why this side stops the transfer and close the connection
+///
+/// The peer gets a moment to read `packet` and close the connection itself,
+/// `reason` is sent along with the close code in case it does not.
+/// The packet is only sent if both peers support it (`enabled`).
+pub async fn send_stop<S: Encode + std::fmt::Debug>(
+    conn: &iroh::endpoint::Connection,
+    control: &mut ControlChannel,
+    enabled: bool,
+    packet: S,
+    code: CloseCode,
+    reason: &str,
+) {
+    if enabled && control.send(packet).await.is_ok() {
+        let _ = tokio::time::timeout(PEER_STOP_TIMEOUT, conn.closed()).await;
+    }
+
+    code.close(conn, reason);
+}
+
+/// Why the other peer stopped the transfer, after it failed on this side
+///
+/// It is taken from the code the peer closed the connection with,
+/// or from the stop packet it sent shortly before (if both peers support it, `enabled`).
+pub async fn peer_stop<R: Decode<()> + std::fmt::Debug>(
+    conn: &iroh::endpoint::Connection,
+    control: &mut ControlChannel,
+    enabled: bool,
+    read_packet: impl Fn(R) -> Option<ControlPacket>,
+) -> Option<PeerStop> {
+    if let Some(stop) = PeerStop::from_close(conn) {
+        return Some(stop);
     }
 
-    let decompressed = decompress_gzip(&buf).await?;
+    if !enabled {
+        return None;
+    }
 
-    let packet = bincode::decode_from_slice(&decompressed, bincode::config::standard())?.0;
+    let read = async {
+        while let Ok(packet) = control.receive::<R>().await {
+            if let Some(ControlPacket::Stop(stop)) = read_packet(packet) {
+                return Some(stop);
+            }
+        }
 
-    tracing::debug!("Received packet: {:?}", packet);
+        None
+    };
 
-    Ok(packet)
+    match tokio::time::timeout(PEER_STOP_TIMEOUT, read).await {
+        Ok(Some(stop)) => Some(stop),
+        _ => PeerStop::from_close(conn),
+    }
 }
 
-async fn compress_gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
-    let mut out = Vec::new();
-    let mut encoder = GzipEncoder::new(&mut out);
-    encoder.write_all(data).await?;
-    encoder.shutdown().await?;
+/// If `conn` was closed because the other peer could not be reached anymore,
+/// rather than closed on purpose by one of the peers
+pub fn connection_lost(conn: &iroh::endpoint::Connection) -> bool {
+    use iroh::endpoint::ConnectionError;
 
-    Ok(out)
+    conn.close_reason().is_some_and(|reason| {
+        !matches!(
+            reason,
+            ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed
+        )
+    })
 }
 
-async fn decompress_gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
-    let mut out = Vec::new();
-    let mut decoder = GzipDecoder::new(&mut out);
-    decoder.write_all(data).await?;
-    decoder.shutdown().await?;
+/// The bytes still missing of each of the `entries` top-level files/dirs,
+/// if only the `pending` files are left to transfer
+pub fn remaining_bytes(files: &[TransferFile], pending: &[usize], entries: usize) -> Vec<u64> {
+    let mut remaining = vec![0; entries];
+    for file in pending.iter().map(|id| &files[*id]) {
+        remaining[file.entry] += file.size.saturating_sub(file.skip);
+    }
 
-    Ok(out)
+    remaining
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::packets::SenderToReceiver;
     use pretty_assertions::assert_eq;
 
-    #[tokio::test]
-    async fn test_compression() {
-        let data = b"hellllllllllllllllllllllllo world";
-        let compressed = compress_gzip(data).await.unwrap();
-        let decompressed = decompress_gzip(&compressed).await.unwrap();
-
-        assert!(compressed.len() < data.len());
-        assert_eq!(data, &decompressed[..]);
-    }
-
     #[test]
     fn test_file_trees() {
         let files_offered = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FilesAvailable::File {
-                    name: "file1".to_string(),
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
                     size: 10,
                 },
                 FilesAvailable::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![
                         FilesAvailable::File {
-                            name: "file2".to_string(),
+                            name: "file2".into(),
+                            meta: FileMeta::default(),
                             size: 20,
                         },
                         FilesAvailable::File {
-                            name: "file3".to_string(),
+                            name: "file3".into(),
+                            meta: FileMeta::default(),
                             size: 30,
                         },
                     ],
@@ -401,16 +1757,20 @@ mod tests {
         };
 
         let already_installed = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FilesAvailable::File {
-                    name: "file1".to_string(),
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
                     size: 10,
                 },
                 FilesAvailable::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![FilesAvailable::File {
-                        name: "file2".to_string(),
+                        name: "file2".into(),
+                        meta: FileMeta::default(),
                         size: 15,
                     }],
                 },
@@ -421,17 +1781,19 @@ mod tests {
         assert_eq!(
             to_skip,
             FilesToSkip::Dir {
-                name: "root".to_string(),
+                name: "root".into(),
                 files: vec![
                     FilesToSkip::File {
-                        name: "file1".to_string(),
-                        skip: 10
+                        name: "file1".into(),
+                        skip: 10,
+                        prefix_hash: None
                     },
                     FilesToSkip::Dir {
-                        name: "dir1".to_string(),
+                        name: "dir1".into(),
                         files: vec![FilesToSkip::File {
-                            name: "file2".to_string(),
-                            skip: 15
+                            name: "file2".into(),
+                            skip: 15,
+                            prefix_hash: None
                         }],
                     },
                 ],
@@ -439,17 +1801,21 @@ mod tests {
         );
 
         let new_tree_expected = FileSendRecvTree::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![FileSendRecvTree::Dir {
-                name: "dir1".to_string(),
+                name: "dir1".into(),
+                meta: FileMeta::default(),
                 files: vec![
                     FileSendRecvTree::File {
-                        name: "file2".to_string(),
+                        name: "file2".into(),
+                        meta: FileMeta::default(),
                         skip: 15,
                         size: 20,
                     },
                     FileSendRecvTree::File {
-                        name: "file3".to_string(),
+                        name: "file3".into(),
+                        meta: FileMeta::default(),
                         skip: 0,
                         size: 30,
                     },
@@ -457,28 +1823,33 @@ mod tests {
             }],
         };
 
-        let new_tree = files_offered.remove_skipped(&to_skip).unwrap();
+        let new_tree = files_offered.remove_skipped(&to_skip).unwrap().unwrap();
         assert_eq!(new_tree, new_tree_expected);
     }
 
     #[test]
     fn test_no_files_to_skip() {
         let offered = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FilesAvailable::File {
-                    name: "file1".to_string(),
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
                     size: 10,
                 },
                 FilesAvailable::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![
                         FilesAvailable::File {
-                            name: "file2".to_string(),
+                            name: "file2".into(),
+                            meta: FileMeta::default(),
                             size: 20,
                         },
                         FilesAvailable::File {
-                            name: "file3".to_string(),
+                            name: "file3".into(),
+                            meta: FileMeta::default(),
                             size: 30,
                         },
                     ],
@@ -487,7 +1858,8 @@ mod tests {
         };
 
         let installed = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![],
         };
 
@@ -498,36 +1870,45 @@ mod tests {
     #[test]
     fn larger_directory() {
         let offered = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FilesAvailable::File {
-                    name: "file1".to_string(),
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
                     size: 10,
                 },
                 FilesAvailable::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![
                         FilesAvailable::File {
-                            name: "file2".to_string(),
+                            name: "file2".into(),
+                            meta: FileMeta::default(),
                             size: 20,
                         },
                         FilesAvailable::File {
-                            name: "file3".to_string(),
+                            name: "file3".into(),
+                            meta: FileMeta::default(),
                             size: 30,
                         },
                         FilesAvailable::Dir {
-                            name: "dir2".to_string(),
+                            name: "dir2".into(),
+                            meta: FileMeta::default(),
                             files: vec![FilesAvailable::File {
-                                name: "file4".to_string(),
+                                name: "file4".into(),
+                                meta: FileMeta::default(),
                                 size: 40,
                             }],
                         },
                     ],
                 },
                 FilesAvailable::Dir {
-                    name: "dir3".to_string(),
+                    name: "dir3".into(),
+                    meta: FileMeta::default(),
                     files: vec![FilesAvailable::File {
-                        name: "file5".to_string(),
+                        name: "file5".into(),
+                        meta: FileMeta::default(),
                         size: 50,
                     }],
                 },
@@ -535,21 +1916,26 @@ mod tests {
         };
 
         let installed = FilesAvailable::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FilesAvailable::File {
-                    name: "file1".to_string(),
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
                     size: 10,
                 },
                 FilesAvailable::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![
                         FilesAvailable::File {
-                            name: "file2".to_string(),
+                            name: "file2".into(),
+                            meta: FileMeta::default(),
                             size: 5,
                         },
                         FilesAvailable::Dir {
-                            name: "dir2".to_string(),
+                            name: "dir2".into(),
+                            meta: FileMeta::default(),
                             files: vec![],
                         },
                     ],
@@ -561,21 +1947,23 @@ mod tests {
         assert_eq!(
             to_skip,
             FilesToSkip::Dir {
-                name: "root".to_string(),
+                name: "root".into(),
                 files: vec![
                     FilesToSkip::File {
-                        name: "file1".to_string(),
-                        skip: 10
+                        name: "file1".into(),
+                        skip: 10,
+                        prefix_hash: None
                     },
                     FilesToSkip::Dir {
-                        name: "dir1".to_string(),
+                        name: "dir1".into(),
                         files: vec![
                             FilesToSkip::File {
-                                name: "file2".to_string(),
-                                skip: 5
+                                name: "file2".into(),
+                                skip: 5,
+                                prefix_hash: None
                             },
                             // FilesToSkip::Dir {
-                            //     name: "dir2".to_string(),
+                            //     name: "dir2".into(),
                             //     files: vec![],
                             // },
                         ],
@@ -584,27 +1972,33 @@ mod tests {
             }
         );
 
-        let new_tree = offered.remove_skipped(&to_skip).unwrap();
+        let new_tree = offered.remove_skipped(&to_skip).unwrap().unwrap();
         let new_tree_expected = FileSendRecvTree::Dir {
-            name: "root".to_string(),
+            name: "root".into(),
+            meta: FileMeta::default(),
             files: vec![
                 FileSendRecvTree::Dir {
-                    name: "dir1".to_string(),
+                    name: "dir1".into(),
+                    meta: FileMeta::default(),
                     files: vec![
                         FileSendRecvTree::File {
-                            name: "file2".to_string(),
+                            name: "file2".into(),
+                            meta: FileMeta::default(),
                             skip: 5,
                             size: 20,
                         },
                         FileSendRecvTree::File {
-                            name: "file3".to_string(),
+                            name: "file3".into(),
+                            meta: FileMeta::default(),
                             skip: 0,
                             size: 30,
                         },
                         FileSendRecvTree::Dir {
-                            name: "dir2".to_string(),
+                            name: "dir2".into(),
+                            meta: FileMeta::default(),
                             files: vec![FileSendRecvTree::File {
-                                name: "file4".to_string(),
+                                name: "file4".into(),
+                                meta: FileMeta::default(),
                                 skip: 0,
                                 size: 40,
                             }],
@@ -612,9 +2006,11 @@ mod tests {
                     ],
                 },
                 FileSendRecvTree::Dir {
-                    name: "dir3".to_string(),
+                    name: "dir3".into(),
+                    meta: FileMeta::default(),
                     files: vec![FileSendRecvTree::File {
-                        name: "file5".to_string(),
+                        name: "file5".into(),
+                        meta: FileMeta::default(),
                         skip: 0,
                         size: 50,
                     }],
@@ -624,4 +2020,677 @@ mod tests {
 
         assert_eq!(new_tree, new_tree_expected);
     }
+
+    #[test]
+    fn test_larger_local_file_not_skipped() {
+        let offered = FilesAvailable::File {
+            name: "file1".into(),
+            meta: FileMeta::default(),
+            size: 10,
+        };
+
+        let local = FilesAvailable::File {
+            name: "file1".into(),
+            meta: FileMeta::default(),
+            size: 20,
+        };
+
+        assert_eq!(offered.get_skippable(&local), None);
+    }
+
+    #[test]
+    fn test_verify_prefixes() {
+        let dir = std::env::temp_dir().join(format!("qs-test-prefix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let local = dir.join("local");
+        let remote = dir.join("remote");
+        std::fs::write(&local, b"hello").unwrap();
+        std::fs::write(&remote, b"hello world").unwrap();
+
+        let mut to_skip = FilesToSkip::File {
+            name: "file1".into(),
+            skip: 5,
+            prefix_hash: None,
+        };
+        to_skip.hash_prefixes(&local).unwrap();
+
+        let mut matching = to_skip.clone();
+        matching.verify_prefixes(&remote).unwrap();
+        assert_eq!(matching, to_skip);
+
+        std::fs::write(&remote, b"jello world").unwrap();
+        let mut mismatching = to_skip.clone();
+        mismatching.verify_prefixes(&remote).unwrap();
+        assert_eq!(mismatching.skip(), 0);
+
+        // Without a hash only a whole file is skipped
+        let mut unverified = FilesToSkip::File {
+            name: "file1".into(),
+            skip: 5,
+            prefix_hash: None,
+        };
+        unverified.verify_prefixes(&remote).unwrap();
+        assert_eq!(unverified.skip(), 0);
+
+        let mut whole = FilesToSkip::File {
+            name: "file1".into(),
+            skip: 11,
+            prefix_hash: None,
+        };
+        whole.verify_prefixes(&remote).unwrap();
+        assert_eq!(whole.skip(), 11);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_files() {
+        let dir = std::env::temp_dir().join(format!("qs-test-partial-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+
+        std::fs::write(dir.join("root").join("file1"), b"hello").unwrap();
+        std::fs::write(dir.join("root").join("file1.qs-part"), b"hel").unwrap();
+        std::fs::write(dir.join("root").join("file2.qs-part"), b"world").unwrap();
+
+        assert_eq!(
+            partial_path(&dir.join("root").join("file2")),
+            dir.join("root").join("file2.qs-part")
+        );
+
+        let received = match get_files_received(&dir.join("root")).unwrap() {
+            FilesAvailable::Dir { files, .. } => files,
+            _ => panic!("expected a directory"),
+        };
+        let mut received: Vec<(&str, u64)> = received
+            .iter()
+            .map(|f| (f.name().as_str(), f.size()))
+            .collect();
+        received.sort();
+
+        assert_eq!(received, vec![("file1", 5), ("file2", 5)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../a",
+            "a/../b",
+            "/etc/passwd",
+            "C:\\a",
+            "a\\b",
+        ] {
+            assert!(!is_valid_file_name(name), "{:?} should be invalid", name);
+        }
+
+        for name in ["a", ".bashrc", "a..b", "file.txt"] {
+            assert!(is_valid_file_name(name), "{:?} should be valid", name);
+        }
+
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![FilesAvailable::Dir {
+                name: "dir1".into(),
+                meta: FileMeta::default(),
+                files: vec![FilesAvailable::File {
+                    name: "../../.bashrc".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                }],
+            }],
+        };
+        assert_eq!(
+            offered.find_invalid_name().map(FileName::as_str),
+            Some("../../.bashrc")
+        );
+
+        let offered = FilesAvailable::File {
+            name: "/etc/passwd".into(),
+            meta: FileMeta::default(),
+            size: 10,
+        };
+        assert_eq!(
+            offered.find_invalid_name().map(FileName::as_str),
+            Some("/etc/passwd")
+        );
+
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![FilesAvailable::File {
+                name: "file1".into(),
+                meta: FileMeta::default(),
+                size: 10,
+            }],
+        };
+        assert_eq!(offered.find_invalid_name(), None);
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let file = |name: &str| FilesAvailable::File {
+            name: name.into(),
+            meta: FileMeta::default(),
+            size: 10,
+        };
+        let dir = |name: &str| FilesAvailable::Dir {
+            name: name.into(),
+            meta: FileMeta::default(),
+            files: vec![file("x")],
+        };
+
+        assert_eq!(find_duplicate_name(&[file("x"), dir("y")]), None);
+        assert_eq!(
+            find_duplicate_name(&[file("a"), dir("x"), file("x")]).map(FileName::as_str),
+            Some("x")
+        );
+    }
+
+    #[test]
+    fn test_invalid_skip_list() {
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![FilesAvailable::File {
+                name: "file1".into(),
+                meta: FileMeta::default(),
+                size: 10,
+            }],
+        };
+
+        let skip_file = |name: &str, skip| FilesToSkip::File {
+            name: name.into(),
+            skip,
+            prefix_hash: None,
+        };
+        let skip_dir = |name: &str, files| FilesToSkip::Dir {
+            name: name.into(),
+            files,
+        };
+
+        assert_eq!(
+            offered.remove_skipped(&skip_dir("other", vec![])),
+            Err(InvalidSkipList::Mismatch {
+                expected: "root".to_string(),
+                got: "other".to_string()
+            })
+        );
+        assert_eq!(
+            offered.remove_skipped(&skip_file("root", 0)),
+            Err(InvalidSkipList::Mismatch {
+                expected: "root".to_string(),
+                got: "root".to_string()
+            })
+        );
+        assert_eq!(
+            offered.remove_skipped(&skip_dir("root", vec![skip_file("file2", 1)])),
+            Err(InvalidSkipList::UnknownFile("file2".to_string()))
+        );
+        assert_eq!(
+            offered.remove_skipped(&skip_dir("root", vec![skip_file("file1", 11)])),
+            Err(InvalidSkipList::SkipExceedsSize {
+                name: "file1".into(),
+                skip: 11,
+                size: 10
+            })
+        );
+        assert_eq!(
+            offered.remove_skipped(&skip_dir("root", vec![skip_file("file1", 10)])),
+            Ok(None)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_meta() {
+        let dir = std::env::temp_dir().join(format!("qs-test-meta-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::write(dir.join("root").join("script.sh"), b"#!/bin/sh").unwrap();
+
+        let meta = FileMeta {
+            mtime: Some(Duration::from_secs(1_000_000_000)),
+            mode: Some(0o755),
+        };
+        meta.apply(&dir.join("root").join("script.sh")).unwrap();
+        meta.apply(&dir.join("root")).unwrap();
+
+        let file_meta = std::fs::metadata(dir.join("root").join("script.sh")).unwrap();
+        assert_eq!(FileMeta::from_metadata(&file_meta), meta);
+
+        let dir_meta = std::fs::metadata(dir.join("root")).unwrap();
+        assert_eq!(FileMeta::from_metadata(&dir_meta), meta);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_symlink_targets() {
+        assert!(is_contained_symlink_target("file1", 0));
+        assert!(is_contained_symlink_target("./dir1/../file1", 0));
+        assert!(is_contained_symlink_target("../file1", 1));
+        assert!(is_contained_symlink_target("../../dir1/file2", 2));
+
+        assert!(!is_contained_symlink_target("", 0));
+        assert!(!is_contained_symlink_target("../file1", 0));
+        assert!(!is_contained_symlink_target("dir1/../../file1", 0));
+        assert!(!is_contained_symlink_target("../../../.bashrc", 2));
+        assert!(!is_contained_symlink_target("/etc/passwd", 3));
+        assert!(!is_contained_symlink_target("..\\..\\file1", 3));
+
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::Symlink {
+                    name: "ok".into(),
+                    target: "../root/file1".to_string(),
+                },
+                FilesAvailable::Symlink {
+                    name: "escape".into(),
+                    target: "../../.ssh".to_string(),
+                },
+            ],
+        };
+        assert_eq!(
+            find_escaping_symlink(std::slice::from_ref(&offered))
+                .map(|(name, target)| (name.as_str(), target)),
+            Some(("escape", "../../.ssh"))
+        );
+
+        // Each link stays inside on its own, but `y` resolves through `x`
+        let chained = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::Symlink {
+                    name: "x".into(),
+                    target: "..".to_string(),
+                },
+                FilesAvailable::Symlink {
+                    name: "y".into(),
+                    target: "x/../..".to_string(),
+                },
+                FilesAvailable::Symlink {
+                    name: "z".into(),
+                    target: "x".to_string(),
+                },
+            ],
+        };
+        assert_eq!(
+            find_escaping_symlink(std::slice::from_ref(&chained))
+                .map(|(name, target)| (name.as_str(), target)),
+            Some(("y", "x/../.."))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        let dir = std::env::temp_dir().join(format!("qs-test-symlinks-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root").join("dir1")).unwrap();
+        std::fs::write(dir.join("root").join("file1"), b"hello").unwrap();
+        std::os::unix::fs::symlink("../file1", dir.join("root").join("dir1").join("link")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("root").join("dir1").join("loop")).unwrap();
+
+        let link_names = |policy| {
+            let root = get_files_available(&dir.join("root"), policy).unwrap();
+            let FilesAvailable::Dir { files, .. } = root else {
+                panic!("expected a directory");
+            };
+            let dir1 = files
+                .into_iter()
+                .find(|f| f.name().as_str() == "dir1")
+                .unwrap();
+            let FilesAvailable::Dir { files, .. } = dir1 else {
+                panic!("expected a directory");
+            };
+            let mut files: Vec<FilesAvailable> = files;
+            files.sort_by(|a, b| a.name().as_str().cmp(b.name().as_str()));
+            files
+        };
+
+        // The loop is left out, the link to the file is followed
+        let followed = link_names(SymlinkPolicy::Follow);
+        assert_eq!(followed.len(), 1);
+        assert!(
+            matches!(&followed[0], FilesAvailable::File { name, size: 5, .. } if name.as_str() == "link")
+        );
+
+        assert_eq!(
+            link_names(SymlinkPolicy::Preserve),
+            vec![
+                FilesAvailable::Symlink {
+                    name: "link".into(),
+                    target: "../file1".to_string(),
+                },
+                FilesAvailable::Symlink {
+                    name: "loop".into(),
+                    target: "..".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(link_names(SymlinkPolicy::Skip), vec![]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let raw = b"caf\xe9";
+        let name = FileName::from_os_str(std::ffi::OsStr::from_bytes(raw));
+
+        assert_eq!(name.as_str(), "caf%E9");
+        assert_eq!(name.as_path().as_os_str().as_bytes(), raw);
+        assert!(name.is_valid());
+
+        let malicious = FileName {
+            name: "harmless".to_string(),
+            raw: Some(b"../../.bashrc".to_vec()),
+        };
+        assert!(!malicious.is_valid());
+
+        let dir = std::env::temp_dir().join(format!("qs-test-non-utf8-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(name.as_path()), b"hello").unwrap();
+
+        let available = get_files_available(&dir, SymlinkPolicy::Follow).unwrap();
+        let FilesAvailable::Dir { files, .. } = available else {
+            panic!("expected a directory");
+        };
+        assert_eq!(files[0].name(), &name);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::File {
+                    name: "file1".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+                FilesAvailable::File {
+                    name: "file2".into(),
+                    meta: FileMeta::default(),
+                    size: 20,
+                },
+            ],
+        };
+
+        let local = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![FilesAvailable::File {
+                name: "file1".into(),
+                meta: FileMeta::default(),
+                size: 30,
+            }],
+        };
+
+        // Existing files are skipped regardless of their size
+        let existing = offered.get_existing(&local).unwrap();
+        assert_eq!(existing.skip(), 10);
+
+        let remaining = offered.remove_skipped(&existing).unwrap().unwrap();
+        assert_eq!(remaining.size(), 20);
+        assert_eq!(remaining.skip(), 0);
+    }
+
+    #[test]
+    fn test_selected_files() {
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::File {
+                    name: "a.jpg".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+                FilesAvailable::Dir {
+                    name: "sub".into(),
+                    meta: FileMeta::default(),
+                    files: vec![
+                        FilesAvailable::File {
+                            name: "b.jpg".into(),
+                            meta: FileMeta::default(),
+                            size: 20,
+                        },
+                        FilesAvailable::File {
+                            name: "c.txt".into(),
+                            meta: FileMeta::default(),
+                            size: 30,
+                        },
+                    ],
+                },
+            ],
+        };
+
+        let jpg = |path: &Path| path.extension().is_some_and(|e| e == "jpg");
+        let selected = offered.select(Path::new("root"), &jpg).unwrap();
+        assert_eq!(selected.size(), 30);
+        assert!(offered.select(Path::new("root"), &|_| false).is_none());
+
+        // Everything else is skipped as a whole
+        let excluded = offered.excluded(Some(&selected)).unwrap();
+        assert_eq!(excluded.skip(), 30);
+        assert!(offered.excluded(Some(&offered)).is_none());
+        assert_eq!(offered.excluded(None).unwrap().skip(), 60);
+
+        let resumed = FilesToSkip::Dir {
+            name: "root".into(),
+            files: vec![FilesToSkip::Dir {
+                name: "sub".into(),
+                files: vec![
+                    FilesToSkip::File {
+                        name: "b.jpg".into(),
+                        skip: 5,
+                        prefix_hash: Some([0; 32]),
+                    },
+                    FilesToSkip::File {
+                        name: "c.txt".into(),
+                        skip: 10,
+                        prefix_hash: Some([0; 32]),
+                    },
+                ],
+            }],
+        };
+
+        // The excluded file is not resumed
+        let skip = excluded.merge(Some(resumed));
+        assert_eq!(skip.skip(), 35);
+        offered.validate_skip(&skip).unwrap();
+        // Only the excluded file is skipped as a whole
+        assert_eq!(offered.fully_skipped(&skip), 30);
+
+        let remaining = offered.remove_skipped(&skip).unwrap().unwrap();
+        assert_eq!(remaining.size(), 30);
+        assert_eq!(remaining.skip(), 5);
+    }
+
+    #[test]
+    fn test_retain_selected() {
+        let offered = FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::File {
+                    name: "a.jpg".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+                FilesAvailable::Symlink {
+                    name: "link.txt".into(),
+                    target: "a.jpg".into(),
+                },
+                FilesAvailable::Dir {
+                    name: "empty".into(),
+                    meta: FileMeta::default(),
+                    files: vec![],
+                },
+            ],
+        };
+
+        let jpg = |path: &Path| path.extension().is_some_and(|e| e == "jpg");
+        let selected = offered.select(Path::new("root"), &jpg).unwrap();
+
+        // The symlink and the empty directory carry no data, so nothing skips them
+        let excluded = offered.excluded(Some(&selected));
+        assert!(excluded.is_none());
+
+        let remaining = offered.to_send_recv_tree().retain_selected(&selected);
+        assert_eq!(
+            remaining,
+            Some(FileSendRecvTree::Dir {
+                name: "root".into(),
+                meta: FileMeta::default(),
+                files: vec![FileSendRecvTree::File {
+                    name: "a.jpg".into(),
+                    meta: FileMeta::default(),
+                    skip: 0,
+                    size: 10,
+                }],
+            })
+        );
+
+        let all = offered.to_send_recv_tree();
+        assert_eq!(all.clone().retain_selected(&offered), Some(all));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let tree = FileSendRecvTree::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FileSendRecvTree::Dir {
+                    name: "dir".into(),
+                    meta: FileMeta::default(),
+                    files: vec![FileSendRecvTree::File {
+                        name: "file1".into(),
+                        meta: FileMeta::default(),
+                        skip: 5,
+                        size: 10,
+                    }],
+                },
+                FileSendRecvTree::Symlink {
+                    name: "link".into(),
+                    target: "dir".into(),
+                },
+                FileSendRecvTree::File {
+                    name: "file2".into(),
+                    meta: FileMeta::default(),
+                    skip: 0,
+                    size: 20,
+                },
+            ],
+        };
+
+        let mut files = Vec::new();
+        tree.flatten(Path::new("out/root"), 3, &mut files);
+
+        assert_eq!(
+            files,
+            vec![
+                TransferFile {
+                    path: Path::new("out/root").join("dir").join("file1"),
+                    entry: 3,
+                    meta: FileMeta::default(),
+                    skip: 5,
+                    size: 10,
+                },
+                TransferFile {
+                    path: Path::new("out/root").join("file2"),
+                    entry: 3,
+                    meta: FileMeta::default(),
+                    skip: 0,
+                    size: 20,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let packets = [
+            SenderToReceiver::FileInfo {
+                files: vec![FilesAvailable::File {
+                    name: "file".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                }],
+            },
+            SenderToReceiver::SkipVerified {
+                files: vec![None],
+                streams: 1,
+            },
+        ];
+
+        let mut writer = FrameWriter::new(Vec::new());
+        for packet in &packets {
+            writer.write(packet, Compression::default()).await.unwrap();
+        }
+
+        let mut reader = FrameReader::new(writer.writer.as_slice());
+        for packet in &packets {
+            let received: SenderToReceiver = reader.read().await.unwrap();
+            assert_eq!(format!("{:?}", received), format!("{:?}", packet));
+        }
+
+        let too_large = (MAX_FRAME_SIZE + 1).to_be_bytes();
+        assert!(matches!(
+            FrameReader::new(too_large.as_slice())
+                .read::<SenderToReceiver>()
+                .await,
+            Err(PacketRecvError::FrameTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_frames() {
+        let (send, recv) = tokio::io::duplex(16);
+        let mut writer = FrameWriter::new(send);
+        let mut reader = FrameReader::new(recv);
+
+        let first = SenderToReceiver::Text {
+            content: "first".repeat(20),
+        };
+        let second = SenderToReceiver::Paused { paused: true };
+
+        // Both are cancelled in the middle of the frame, once the pipe is full
+        let timeout = Duration::from_millis(50);
+        assert!(
+            tokio::time::timeout(timeout, writer.write(&first, Compression::NONE))
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio::time::timeout(timeout, reader.read::<SenderToReceiver>())
+                .await
+                .is_err()
+        );
+
+        let (written, received) = tokio::join!(writer.write(&second, Compression::NONE), async {
+            let first: SenderToReceiver = reader.read().await.unwrap();
+            let second: SenderToReceiver = reader.read().await.unwrap();
+            (first, second)
+        });
+        written.unwrap();
+        assert_eq!(format!("{:?}", received.0), format!("{:?}", first));
+        assert_eq!(format!("{:?}", received.1), format!("{:?}", second));
+    }
 }
@@ -0,0 +1,458 @@
+use crate::common::MAX_FRAME_SIZE;
+use async_compression::{
+    tokio::{bufread, write},
+    Level,
+};
+use bincode::{Decode, Encode};
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Number of bytes at the start of a file that are compressed
+/// to check if the file is compressible
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Extensions of files that are already compressed
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "cab", "deb", "docx", "epub", "flac", "gif",
+    "gz", "heic", "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4",
+    "odt", "ogg", "opus", "png", "pptx", "rar", "rpm", "tgz", "txz", "webm", "webp", "whl", "xlsx",
+    "xz", "zip", "zst",
+];
+
+/// Compression algorithm of the file data and the packets
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Encode, Decode)]
+pub enum CompressionAlgorithm {
+    None,
+    Gzip,
+    #[default]
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Tag that is sent in front of data compressed with this algorithm
+    fn tag(self) -> u8 {
+        match self {
+            CompressionAlgorithm::None => 0,
+            CompressionAlgorithm::Gzip => 1,
+            CompressionAlgorithm::Zstd => 2,
+        }
+    }
+
+    /// The level that is used if none is set
+    pub fn default_level(self) -> i32 {
+        match self {
+            CompressionAlgorithm::None => 0,
+            CompressionAlgorithm::Gzip => 6,
+            CompressionAlgorithm::Zstd => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(CompressionAlgorithm::None),
+            1 => Ok(CompressionAlgorithm::Gzip),
+            2 => Ok(CompressionAlgorithm::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression tag: {}", tag),
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for CompressionAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CompressionAlgorithm::None),
+            "gzip" => Ok(CompressionAlgorithm::Gzip),
+            "zstd" => Ok(CompressionAlgorithm::Zstd),
+            _ => Err(format!(
+                "invalid compression algorithm {:?}, expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressionAlgorithm::None => write!(f, "none"),
+            CompressionAlgorithm::Gzip => write!(f, "gzip"),
+            CompressionAlgorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// The compression the sender proposes in the connection request
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Encode, Decode)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    /// Level of the algorithm, its default level if `None`
+    pub level: Option<i32>,
+    /// Send files that don't compress well without compression
+    pub adaptive: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::default(),
+            level: None,
+            adaptive: true,
+        }
+    }
+}
+
+/// The compression the receiver accepts, uncompressed data is always accepted
+#[derive(Debug, PartialEq, Eq, Clone, Encode, Decode)]
+pub struct AcceptedCompression {
+    /// The accepted algorithms, the preferred one first
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Highest accepted level, e.g. to limit the memory needed for decompression.
+    /// Any level is accepted if `None`
+    pub max_level: Option<i32>,
+}
+
+impl Default for AcceptedCompression {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Gzip],
+            max_level: None,
+        }
+    }
+}
+
+impl Compression {
+    /// No compression at all, used until the compression is negotiated
+    pub const NONE: Self = Self {
+        algorithm: CompressionAlgorithm::None,
+        level: None,
+        adaptive: false,
+    };
+
+    fn quality(&self) -> Level {
+        match self.level {
+            Some(level) => Level::Precise(level),
+            None => Level::Default,
+        }
+    }
+
+    /// The algorithm the file at `path` is sent with
+    ///
+    /// In adaptive mode files with a known compressed format, or where a sample
+    /// from the start of the file does not get smaller, are not compressed.
+    pub async fn for_file(&self, path: &Path) -> io::Result<CompressionAlgorithm> {
+        if self.algorithm == CompressionAlgorithm::None || !self.adaptive {
+            return Ok(self.algorithm);
+        }
+
+        let compressed_extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+
+        if compressed_extension {
+            return Ok(CompressionAlgorithm::None);
+        }
+
+        let mut sample = Vec::new();
+        tokio::fs::File::open(path)
+            .await?
+            .take(SAMPLE_SIZE)
+            .read_to_end(&mut sample)
+            .await?;
+
+        let mut compressed = Vec::new();
+        let mut encoder = encoder(&mut compressed, self.algorithm, Level::Precise(1));
+        encoder.write_all(&sample).await?;
+        encoder.shutdown().await?;
+        drop(encoder);
+
+        // Compression has to save at least 10%
+        if compressed.len() * 10 < sample.len() * 9 {
+            Ok(self.algorithm)
+        } else {
+            Ok(CompressionAlgorithm::None)
+        }
+    }
+
+    /// Wrap `writer` in the encoder for a file sent with `algorithm`
+    pub(crate) fn encoder<'a, W>(
+        &self,
+        writer: W,
+        algorithm: CompressionAlgorithm,
+    ) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        encoder(writer, algorithm, self.quality())
+    }
+}
+
+/// Writer that only flushes when it is shut down,
+/// so an encoder can finish its data without closing the underlying stream
+struct Unclosed<W>(W);
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Unclosed<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+}
+
+/// Shutting down the encoder ends the compressed data, but not `writer` itself
+fn encoder<'a, W>(
+    writer: W,
+    algorithm: CompressionAlgorithm,
+    level: Level,
+) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+where
+    W: AsyncWrite + Unpin + Send + 'a,
+{
+    let writer = Unclosed(writer);
+
+    match algorithm {
+        CompressionAlgorithm::None => Box::new(writer),
+        CompressionAlgorithm::Gzip => Box::new(write::GzipEncoder::with_quality(writer, level)),
+        CompressionAlgorithm::Zstd => Box::new(write::ZstdEncoder::with_quality(writer, level)),
+    }
+}
+
+/// Wrap `reader` in the decoder for data compressed with `algorithm`,
+/// the decoder stops at the end of the compressed data
+pub(crate) fn decoder<'a, R>(
+    reader: R,
+    algorithm: CompressionAlgorithm,
+) -> Box<dyn AsyncRead + Unpin + Send + 'a>
+where
+    R: AsyncBufRead + Unpin + Send + 'a,
+{
+    match algorithm {
+        CompressionAlgorithm::None => Box::new(reader),
+        CompressionAlgorithm::Gzip => Box::new(bufread::GzipDecoder::new(reader)),
+        CompressionAlgorithm::Zstd => Box::new(bufread::ZstdDecoder::new(reader)),
+    }
+}
+
+/// Write the tag of `algorithm` in front of compressed data
+pub(crate) async fn write_tag<W>(writer: &mut W, algorithm: CompressionAlgorithm) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_u8(algorithm.tag()).await
+}
+
+/// Read the tag written with [write_tag]
+pub(crate) async fn read_tag<R>(reader: &mut R) -> io::Result<CompressionAlgorithm>
+where
+    R: AsyncRead + Unpin,
+{
+    CompressionAlgorithm::from_tag(reader.read_u8().await?)
+}
+
+/// Read the rest of the compressed data after all of its content was read,
+/// so the reader is positioned right behind it
+pub(crate) async fn finish_decoder(
+    decoder: &mut (dyn AsyncRead + Unpin + Send + '_),
+    algorithm: CompressionAlgorithm,
+) -> io::Result<()> {
+    // Uncompressed data has no end marker
+    if algorithm == CompressionAlgorithm::None {
+        return Ok(());
+    }
+
+    if decoder.read(&mut [0]).await? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "more compressed data than expected",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Compress a packet, it is sent uncompressed if that is smaller
+pub(crate) async fn compress_packet(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    write_tag(&mut out, compression.algorithm).await?;
+
+    let mut encoder = compression.encoder(&mut out, compression.algorithm);
+    encoder.write_all(data).await?;
+    encoder.shutdown().await?;
+    drop(encoder);
+
+    if out.len() > data.len() + 1 {
+        out.clear();
+        write_tag(&mut out, CompressionAlgorithm::None).await?;
+        out.extend_from_slice(data);
+    }
+
+    Ok(out)
+}
+
+/// Decompress a packet compressed with [compress_packet],
+/// packets larger than [MAX_FRAME_SIZE] are rejected
+pub(crate) async fn decompress_packet(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let algorithm = read_tag(&mut data).await?;
+
+    let mut out = Vec::new();
+    decoder(data, algorithm)
+        .take(MAX_FRAME_SIZE as u64 + 1)
+        .read_to_end(&mut out)
+        .await?;
+
+    if out.len() > MAX_FRAME_SIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed packet is too large",
+        ));
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_compression() {
+        let data = b"hellllllllllllllllllllllllo world".repeat(10);
+
+        for algorithm in [
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::Gzip,
+            CompressionAlgorithm::Zstd,
+        ] {
+            let compression = Compression {
+                algorithm,
+                level: Some(3),
+                adaptive: false,
+            };
+
+            let compressed = compress_packet(&data, compression).await.unwrap();
+            let decompressed = decompress_packet(&compressed).await.unwrap();
+
+            if algorithm != CompressionAlgorithm::None {
+                assert!(compressed.len() < data.len());
+            }
+            assert_eq!(data, decompressed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decompression_bomb() {
+        let data = vec![0; MAX_FRAME_SIZE as usize + 1];
+        let compressed = compress_packet(&data, Compression::default())
+            .await
+            .unwrap();
+        assert!(compressed.len() < 64 * 1024);
+
+        let err = decompress_packet(&compressed).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let data = vec![0; MAX_FRAME_SIZE as usize];
+        let compressed = compress_packet(&data, Compression::default())
+            .await
+            .unwrap();
+        assert_eq!(decompress_packet(&compressed).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_consecutive_frames() {
+        let frames = [
+            (CompressionAlgorithm::Zstd, b"first".repeat(100)),
+            (CompressionAlgorithm::None, b"second".to_vec()),
+            (CompressionAlgorithm::Gzip, b"third".repeat(100)),
+        ];
+
+        let mut stream = Vec::new();
+        for (algorithm, data) in &frames {
+            write_tag(&mut stream, *algorithm).await.unwrap();
+            let mut encoder = Compression::default().encoder(&mut stream, *algorithm);
+            encoder.write_all(data).await.unwrap();
+            encoder.shutdown().await.unwrap();
+        }
+
+        let mut reader = BufReader::new(Cursor::new(stream));
+        for (algorithm, data) in &frames {
+            assert_eq!(read_tag(&mut reader).await.unwrap(), *algorithm);
+
+            let mut decoder = decoder(&mut reader, *algorithm);
+            let mut read = vec![0; data.len()];
+            decoder.read_exact(&mut read).await.unwrap();
+            finish_decoder(&mut decoder, *algorithm).await.unwrap();
+
+            assert_eq!(&read, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_adaptive() {
+        let dir = std::env::temp_dir().join(format!("qs-test-adaptive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let text = dir.join("text.txt");
+        std::fs::write(&text, b"some text ".repeat(1000)).unwrap();
+        let random = dir.join("random.bin");
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let data: Vec<u8> = (0..SAMPLE_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        std::fs::write(&random, data).unwrap();
+        let archive = dir.join("text.ZIP");
+        std::fs::write(&archive, b"some text ".repeat(1000)).unwrap();
+
+        let compression = Compression::default();
+        assert_eq!(
+            compression.for_file(&text).await.unwrap(),
+            CompressionAlgorithm::Zstd
+        );
+        assert_eq!(
+            compression.for_file(&random).await.unwrap(),
+            CompressionAlgorithm::None
+        );
+        assert_eq!(
+            compression.for_file(&archive).await.unwrap(),
+            CompressionAlgorithm::None
+        );
+
+        let fixed = Compression {
+            adaptive: false,
+            ..compression
+        };
+        assert_eq!(
+            fixed.for_file(&random).await.unwrap(),
+            CompressionAlgorithm::Zstd
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
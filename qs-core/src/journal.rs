@@ -0,0 +1,301 @@
+use crate::common::{local_data_path, FilesAvailable, FilesToSkip};
+use bincode::{Decode, Encode};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Name of the journal file in the output directory,
+/// offered files or directories with this name are rejected
+pub const JOURNAL_FILE_NAME: &str = ".qs-journal";
+/// Name the journal is written to before it replaces [JOURNAL_FILE_NAME],
+/// offered files or directories with this name are rejected as well
+pub const JOURNAL_TEMP_NAME: &str = ".qs-journal.tmp";
+
+/// The journal is written at most this often while data is received,
+/// a lost connection or a stopped transfer writes it right away
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the journal knows about a single file of the transfer
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub enum FileProgress {
+    /// Nothing is recorded, the file is resumed by looking at the local data
+    Unknown,
+    /// The first `len` bytes are written to disk
+    Partial {
+        len: u64,
+        /// BLAKE3 hash of the first `len` bytes
+        hash: [u8; blake3::OUT_LEN],
+    },
+    /// The file is received and verified
+    Complete {
+        /// BLAKE3 hash of the whole file
+        hash: [u8; blake3::OUT_LEN],
+    },
+}
+
+/// Content of the journal file
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+struct JournalState {
+    /// Session of the transfer that wrote the journal
+    session_id: u64,
+    /// The files that were offered, the journal is only used for the same offer
+    files: Vec<FilesAvailable>,
+    /// Progress of every file of each offered file/dir, in the order of [FilesAvailable::file_paths]
+    progress: Vec<Vec<FileProgress>>,
+}
+
+/// Resume journal of a transfer, kept in the output directory
+///
+/// It records the progress of every file, so an interrupted transfer can be resumed
+/// from the verified bytes without hashing the local files again.
+/// The journal is removed once the transfer is complete.
+pub struct Journal {
+    path: PathBuf,
+    state: Mutex<(JournalState, Instant)>,
+}
+
+impl Journal {
+    /// Load the journal in `output_path`, if it was written for the offer `files`
+    pub fn load(output_path: &Path, files: &[FilesAvailable]) -> Option<Self> {
+        let path = output_path.join(JOURNAL_FILE_NAME);
+        let data = std::fs::read(&path).ok()?;
+
+        let state: JournalState =
+            match bincode::decode_from_slice(&data, bincode::config::standard()) {
+                Ok((state, _)) => state,
+                Err(e) => {
+                    tracing::warn!("ignoring invalid journal {}: {}", path.display(), e);
+                    return None;
+                }
+            };
+
+        let matches = state.files == files
+            && state.progress.len() == files.len()
+            && state
+                .progress
+                .iter()
+                .zip(files)
+                .all(|(progress, file)| progress.len() == file.file_paths(Path::new("")).len());
+
+        if !matches {
+            tracing::debug!("the journal in {} is for another offer", path.display());
+            return None;
+        }
+
+        tracing::info!("found the journal of session {:x}", state.session_id);
+
+        Some(Self {
+            path,
+            state: Mutex::new((state, Instant::now())),
+        })
+    }
+
+    /// Start a new journal in `output_path` for the offer `files`,
+    /// keeping the progress of `previous` if there is one
+    pub fn create(
+        output_path: &Path,
+        session_id: u64,
+        files: &[FilesAvailable],
+        previous: Option<Journal>,
+    ) -> Self {
+        let progress = match previous {
+            Some(previous) => previous.state.into_inner().unwrap().0.progress,
+            None => files
+                .iter()
+                .map(|f| vec![FileProgress::Unknown; f.file_paths(Path::new("")).len()])
+                .collect(),
+        };
+
+        let journal = Self {
+            path: output_path.join(JOURNAL_FILE_NAME),
+            state: Mutex::new((
+                JournalState {
+                    session_id,
+                    files: files.to_vec(),
+                    progress,
+                },
+                Instant::now(),
+            )),
+        };
+        journal.flush();
+
+        journal
+    }
+
+    /// The files of the offered `entry` that can be skipped according to the journal,
+    /// `path` is the local path of the entry.
+    ///
+    /// A file is only skipped if its local data still has the recorded length,
+    /// the sender verifies the recorded hashes before skipping anything.
+    pub fn skippable(
+        &self,
+        entry: usize,
+        offered: &FilesAvailable,
+        path: &Path,
+    ) -> Option<FilesToSkip> {
+        let state = self.state.lock().unwrap();
+        let progress = state.0.progress.get(entry)?;
+
+        offered.skippable_from(path, &mut progress.iter(), &|path, size, progress| {
+            let local_len = |path: &Path| path.metadata().ok().map(|m| m.len());
+
+            match progress {
+                FileProgress::Unknown => None,
+                FileProgress::Partial { len, hash } => (*len > 0
+                    && local_len(&local_data_path(path))? >= *len)
+                    .then_some((*len, *hash)),
+                FileProgress::Complete { hash } => {
+                    (local_len(path)? == size).then_some((size, *hash))
+                }
+            }
+        })
+    }
+
+    /// Record the progress of a file, the file is identified by its entry
+    /// and its index in [FilesAvailable::file_paths]
+    pub fn record(&self, (entry, index): (usize, usize), progress: FileProgress) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = state
+            .0
+            .progress
+            .get_mut(entry)
+            .and_then(|p| p.get_mut(index))
+        {
+            *file = progress;
+        }
+
+        if state.1.elapsed() >= SAVE_INTERVAL {
+            state.1 = Instant::now();
+            self.save(&state.0);
+        }
+    }
+
+    /// Write the journal to disk now
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.1 = Instant::now();
+        self.save(&state.0);
+    }
+
+    /// Remove the journal once the transfer is complete
+    pub fn remove(self) -> io::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The journal only speeds up resuming, so failing to write it does not stop the transfer
+    fn save(&self, state: &JournalState) {
+        let data = bincode::encode_to_vec(state, bincode::config::standard()).unwrap();
+
+        // Replace the journal at once, so it is never left half written
+        let temp = self.path.with_file_name(JOURNAL_TEMP_NAME);
+        let result = std::fs::write(&temp, data).and_then(|_| std::fs::rename(&temp, &self.path));
+
+        if let Err(e) = result {
+            tracing::warn!("failed to write the journal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Map the local paths of the files of a transfer to their position in the journal
+///
+/// # Returns
+/// The `(entry, index)` of every file, by its local path
+pub fn journal_ids(
+    files: &[FilesAvailable],
+    targets: &[PathBuf],
+) -> HashMap<PathBuf, (usize, usize)> {
+    let mut ids = HashMap::new();
+    for (entry, (file, target)) in files.iter().zip(targets).enumerate() {
+        for (index, path) in file.file_paths(target).into_iter().enumerate() {
+            ids.insert(path, (entry, index));
+        }
+    }
+
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::FileMeta;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_journal() {
+        let dir = std::env::temp_dir().join(format!("qs-test-journal-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+
+        let offered = vec![FilesAvailable::Dir {
+            name: "root".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::File {
+                    name: "done".into(),
+                    meta: FileMeta::default(),
+                    size: 5,
+                },
+                FilesAvailable::File {
+                    name: "partial".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+                FilesAvailable::File {
+                    name: "missing".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+            ],
+        }];
+
+        std::fs::write(dir.join("root").join("done"), b"hello").unwrap();
+        std::fs::write(dir.join("root").join("partial.qs-part"), b"hello").unwrap();
+
+        let ids = journal_ids(&offered, &[dir.join("root")]);
+        assert_eq!(ids[&dir.join("root").join("partial")], (0, 1));
+
+        let journal = Journal::create(&dir, 1, &offered, None);
+        let hash = *blake3::hash(b"hello").as_bytes();
+        journal.record((0, 0), FileProgress::Complete { hash });
+        journal.record((0, 1), FileProgress::Partial { len: 4, hash });
+        journal.record((0, 2), FileProgress::Partial { len: 4, hash });
+        journal.flush();
+
+        assert!(Journal::load(&dir, &offered[..0]).is_none());
+        let journal = Journal::load(&dir, &offered).unwrap();
+
+        // The missing file has no local data, so it is sent again
+        let skip = journal
+            .skippable(0, &offered[0], &dir.join("root"))
+            .unwrap();
+        assert_eq!(
+            skip,
+            FilesToSkip::Dir {
+                name: "root".into(),
+                files: vec![
+                    FilesToSkip::File {
+                        name: "done".into(),
+                        skip: 5,
+                        prefix_hash: Some(hash),
+                    },
+                    FilesToSkip::File {
+                        name: "partial".into(),
+                        skip: 4,
+                        prefix_hash: Some(hash),
+                    },
+                ],
+            }
+        );
+
+        journal.remove().unwrap();
+        assert!(!dir.join(JOURNAL_FILE_NAME).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
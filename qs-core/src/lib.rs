@@ -7,16 +7,39 @@ use std::sync::Arc;
 use thiserror::Error;
 
 pub mod common;
+pub mod compression;
+pub mod journal;
 pub mod packets;
+pub mod pause;
+pub mod rate_limit;
 pub mod receive;
 pub mod send;
+pub mod server;
+pub mod share;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
+pub mod version;
 
 pub const BUF_SIZE: usize = 8192;
+/// Most streams the files of a transfer are sent on concurrently
+pub const MAX_STREAMS: usize = 64;
 pub const SEND_SERVER_NAME: &str = "quic-send";
 pub const KEEP_ALIVE_INTERVAL_SECS: u64 = 5;
-pub const QS_PROTO_VERSION: &str = "0.4.0"; 
-pub const QS_ALPN: &[u8] = b"quic-send/0.4.0";
+pub const QS_PROTO_VERSION: &str = "0.7.0";
+/// Version of the packets that follow the [version::Hello],
+/// it only changes when they break in a way capabilities can't cover
+pub const QS_WIRE_VERSION: u32 = 1;
+/// Not versioned, compatibility is decided with [version::is_compatible]
+pub const QS_ALPN: &[u8] = b"quic-send";
+/// ALPN of version 0.4, which put the version into it.
+/// It is still accepted to tell those peers that the versions do not match
+pub const QS_LEGACY_ALPN: &[u8] = b"quic-send/0.4.0";
+
+/// The ALPNs the endpoint of a sender accepts
+pub fn alpns() -> Vec<Vec<u8>> {
+    vec![QS_ALPN.to_vec(), QS_LEGACY_ALPN.to_vec()]
+}
 
 #[derive(Error, Debug)]
 pub enum QuicSendError {
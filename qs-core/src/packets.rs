@@ -1,24 +1,107 @@
-use crate::common::{FilesAvailable, FilesToSkip};
+use crate::{
+    common::{FileName, FilesAvailable, FilesToSkip},
+    compression::{AcceptedCompression, Compression},
+    share::DirEntry,
+};
 use bincode::{Decode, Encode};
 
 /// All packets send from the sender to the receiver
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum SenderToReceiver {
-    /// Initial connection request
-    ConnRequest { version_num: String },
+    /// Initial connection request, it follows the [crate::version::Hello] of the sender
+    /// and is only read if the versions are compatible.
+    /// The sender wants to use `compression` for the following packets and the file data,
+    /// the receiver uses `session_id` to resume the transfer on a new connection
+    ConnRequest {
+        compression: Compression,
+        session_id: u64,
+    },
     /// Send the files the sender wants to send
     FileInfo { files: Vec<FilesAvailable> },
+    /// The sender shares a directory instead of offering files,
+    /// the receiver lists it and requests the paths it wants
+    Share,
+    /// The sender streams data of unknown length instead of offering files,
+    /// it follows on a single unidirectional stream once the receiver accepts it
+    Stream { name: FileName },
+    /// The sender offers a piece of text instead of files, e.g. from its clipboard
+    Text { content: String },
+    /// The entries of the directory the receiver listed
+    DirListing { entries: Vec<DirEntry> },
+    /// The files to skip after checking the prefix hashes,
+    /// files with a mismatching prefix will be sent from the start.
+    /// The file data follows on `streams` unidirectional streams
+    SkipVerified {
+        files: Vec<Option<FilesToSkip>>,
+        streams: u32,
+    },
+    /// Accept the [ReceiverToSender::Resume] request,
+    /// the remaining file data follows on `streams` unidirectional streams
+    Resumed { streams: u32 },
+    /// The sender paused or resumed the transfer
+    Paused { paused: bool },
+    /// The sender cancelled the transfer
+    Cancel { reason: String },
+    /// The sender stopped the transfer because of an error
+    Error { kind: ErrorKind, message: String },
 }
 
 /// All packets send from the receiver to the sender
 #[derive(Debug, Clone, Encode, Decode)]
 pub enum ReceiverToSender {
-    /// Reject the connection request, because the version number is wrong
-    WrongVersion { expected: String },
-    /// Accept the connection request
-    Ok,
+    /// The compression the receiver accepts, it follows the [crate::version::Hello] of the receiver
+    /// if the versions are compatible
+    Compression { accepted: AcceptedCompression },
     /// Reject the files the sender wants to send
     RejectFiles,
+    /// List a directory of the shared directory,
+    /// the path is relative to the shared directory with `/` as separator
+    ListDir { path: String },
+    /// Request paths of the shared directory, the sender offers them with [SenderToReceiver::FileInfo]
+    RequestFiles { paths: Vec<String> },
     /// Accept the files, and send the files that are supposed to be fully or partially skipped
+    /// (including the hashes of the already present prefixes)
     AcceptFilesSkip { files: Vec<Option<FilesToSkip>> },
+    /// Accept the [SenderToReceiver::Stream], it can not be resumed
+    AcceptStream,
+    /// Accept the [SenderToReceiver::Text], the sender closes the connection right after
+    AcceptText,
+    /// Resume the session on a new connection after the old one was lost,
+    /// with the bytes of each transfer file written to disk (`None` if it is complete)
+    Resume {
+        session_id: u64,
+        offsets: Vec<Option<u64>>,
+    },
+    /// The receiver paused or resumed the transfer
+    Paused { paused: bool },
+    /// The receiver cancelled the transfer
+    Cancel { reason: String },
+    /// The receiver stopped the transfer because of an error
+    Error { kind: ErrorKind, message: String },
+}
+
+/// What kind of error stopped the transfer, sent along with the error message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum ErrorKind {
+    /// Reading or writing the files failed
+    Io,
+    /// The other peer sent something unexpected or invalid
+    Protocol,
+    /// Received data did not match its checksum
+    Checksum,
+    /// A requested path does not exist or is outside of the shared directory
+    InvalidPath,
+    Other,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Io => write!(f, "io"),
+            ErrorKind::Protocol => write!(f, "protocol"),
+            ErrorKind::Checksum => write!(f, "checksum"),
+            ErrorKind::InvalidPath => write!(f, "invalid path"),
+            ErrorKind::Other => write!(f, "other"),
+        }
+    }
 }
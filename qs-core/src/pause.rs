@@ -0,0 +1,138 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+/// How often `should_continue` is checked while the transfer is paused
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Who paused the transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PauseState {
+    /// Paused on this side
+    pub local: bool,
+    /// Paused by the other peer
+    pub remote: bool,
+}
+
+impl PauseState {
+    /// No data is sent while either peer paused the transfer
+    pub fn is_paused(self) -> bool {
+        self.local || self.remote
+    }
+}
+
+/// Pause state of a transfer
+///
+/// Clones share the same state, so a clone can be kept
+/// to pause and resume the transfer while it is running.
+/// The connection stays open while the transfer is paused.
+#[derive(Debug, Clone)]
+pub struct Pause {
+    state: Arc<watch::Sender<PauseState>>,
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(PauseState::default())),
+        }
+    }
+}
+
+impl Pause {
+    /// Stop the data flow, the other peer is told about it
+    pub fn pause(&self) {
+        self.set_local(true);
+    }
+
+    /// Continue the transfer from where it was paused
+    pub fn resume(&self) {
+        self.set_local(false);
+    }
+
+    /// Pause if the transfer is running, resume if it is paused on this side
+    pub fn toggle(&self) {
+        self.state.send_modify(|s| s.local = !s.local);
+    }
+
+    /// Who paused the transfer
+    pub fn state(&self) -> PauseState {
+        *self.state.borrow()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state().is_paused()
+    }
+
+    fn set_local(&self, paused: bool) {
+        self.state
+            .send_if_modified(|s| std::mem::replace(&mut s.local, paused) != paused);
+    }
+
+    /// Apply the pause state the other peer sent
+    pub(crate) fn set_remote(&self, paused: bool) {
+        self.state
+            .send_if_modified(|s| std::mem::replace(&mut s.remote, paused) != paused);
+    }
+
+    /// Get notified when the state changes
+    pub(crate) fn subscribe(&self) -> watch::Receiver<PauseState> {
+        self.state.subscribe()
+    }
+
+    /// Wait until neither peer has the transfer paused
+    ///
+    /// # Returns
+    /// * `true` if the transfer should continue
+    /// * `false` if `should_continue` stopped it while it was paused
+    pub async fn wait(&self, should_continue: &mut impl FnMut() -> bool) -> bool {
+        if !self.is_paused() {
+            return true;
+        }
+
+        let mut state = self.subscribe();
+        while state.borrow_and_update().is_paused() {
+            if !should_continue() {
+                return false;
+            }
+
+            let _ = tokio::time::timeout(PAUSE_POLL_INTERVAL, state.changed()).await;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_pause() {
+        let pause = Pause::default();
+        assert!(pause.wait(&mut || true).await);
+
+        let shared = pause.clone();
+        shared.pause();
+        pause.set_remote(true);
+        assert_eq!(
+            pause.state(),
+            PauseState {
+                local: true,
+                remote: true
+            }
+        );
+
+        // Paused by the other peer, also after resuming here
+        pause.resume();
+        assert!(pause.is_paused());
+        assert!(!pause.wait(&mut || false).await);
+
+        let waiting = tokio::spawn({
+            let pause = pause.clone();
+            async move { pause.wait(&mut || true).await }
+        });
+        shared.set_remote(false);
+        assert!(waiting.await.unwrap());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Token bucket that limits the bytes per second of a transfer
+///
+/// Clones share the same bucket, so a clone can be kept
+/// to change the limit while the transfer is running.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, `None` for no limit
+    limit: Option<u64>,
+    /// Bytes that can be transferred right now, negative if the transfer is ahead
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RateLimit {
+    /// Create a limit of `limit` bytes per second, `None` for no limit
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                limit,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// The current limit in bytes per second
+    pub fn get(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().limit
+    }
+
+    /// Change the limit, also while a transfer is running
+    pub fn set(&self, limit: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.limit = limit;
+        bucket.tokens = 0.0;
+        bucket.last_refill = Instant::now();
+    }
+
+    /// Take `bytes` from the bucket and wait until they can be transferred
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let Some(limit) = bucket.limit.filter(|l| *l > 0) else {
+                return;
+            };
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.last_refill = now;
+
+            // Allow bursts of up to one second
+            bucket.tokens = (bucket.tokens + elapsed * limit as f64).min(limit as f64);
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+
+            Duration::from_secs_f64(-bucket.tokens / limit as f64)
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Parse a size like `20MiB`, `500k` or `1.5 GB` into bytes
+///
+/// Units are case insensitive, `K`, `M` and `G` are decimal
+/// and `KiB`, `MiB` and `GiB` are binary.
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}, expected e.g. 20MiB", s))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1_000_000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1_000_000_000,
+        "gib" => 1 << 30,
+        unit => {
+            return Err(format!(
+                "invalid unit {:?}, expected B, K, KiB, M, MiB, G or GiB",
+                unit
+            ))
+        }
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let limit = RateLimit::new(Some(100_000));
+
+        let start = Instant::now();
+        for _ in 0..10 {
+            limit.acquire(5_000).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+
+        let shared = limit.clone();
+        shared.set(None);
+        assert_eq!(limit.get(), None);
+
+        let start = Instant::now();
+        limit.acquire(u64::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("20MiB"), Ok(20 << 20));
+        assert_eq!(parse_byte_size("500k"), Ok(500_000));
+        assert_eq!(parse_byte_size("1.5 GB"), Ok(1_500_000_000));
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert!(parse_byte_size("fast").is_err());
+        assert!(parse_byte_size("10 TiB").is_err());
+    }
+}
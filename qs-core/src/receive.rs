@@ -2,41 +2,67 @@
 
 use crate::{
     common::{
-        get_files_available, receive_packet, send_packet, FileSendRecvTree, FilesAvailable,
-        PacketRecvError,
+        connection_lost, exchange_control, find_duplicate_name, find_escaping_symlink,
+        get_files_received, hash_prefix, partial_path, peer_stop, remaining_bytes, send_stop,
+        CloseCode, ConnectionState, ControlChannel, ControlPacket, FileMeta, FileName,
+        FileSendRecvTree, FilesAvailable, FilesToSkip, InvalidSkipList, PacketRecvError, PeerStop,
+        Session, TransferContext, TransferFile, CANCEL_REASON,
     },
-    packets::{ReceiverToSender, SenderToReceiver},
-    BUF_SIZE, QS_ALPN, QS_PROTO_VERSION,
+    compression::{self, AcceptedCompression, Compression},
+    journal::{journal_ids, FileProgress, Journal, JOURNAL_FILE_NAME, JOURNAL_TEMP_NAME},
+    packets::{ErrorKind, ReceiverToSender, SenderToReceiver},
+    pause::Pause,
+    rate_limit::RateLimit,
+    share::DirEntry,
+    version::{is_compatible, legacy_wrong_version, Capabilities, Hello},
+    BUF_SIZE, MAX_STREAMS, QS_ALPN, QS_LEGACY_ALPN, QS_PROTO_VERSION,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
 };
-use async_compression::tokio::bufread::GzipDecoder;
-use std::{io, path::PathBuf};
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Bytes of a file that are received between two checkpoints
+const CHECKPOINT_INTERVAL: u64 = 16 * 1024 * 1024;
 
 /// Generic receive function
 ///
+/// All bytes of the file, including the already present (skipped) part,
+/// are fed into `hasher`. The data is received no faster than the rate limit of `ctx` allows.
+/// Every [CHECKPOINT_INTERVAL] bytes the written data is flushed
+/// and `checkpoint` is called with the bytes written so far.
+///
 /// # Returns
 /// * `Ok(true)` if the transfer should continue
 /// * `Ok(false)` if the transfer should stop
 pub async fn receive_file<R, W>(
     recv: &mut R,
     file: &mut W,
+    hasher: &mut blake3::Hasher,
     skip: u64,
     size: u64,
-    read_callback: &mut impl FnMut(u64),
-    should_continue: &mut impl FnMut() -> bool,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
+    checkpoint: &mut impl FnMut(u64, &blake3::Hasher),
 ) -> std::io::Result<bool>
 where
     R: tokio::io::AsyncReadExt + Unpin,
-    W: tokio::io::AsyncWriteExt + tokio::io::AsyncSeekExt + Unpin,
+    W: tokio::io::AsyncReadExt + tokio::io::AsyncWriteExt + tokio::io::AsyncSeekExt + Unpin,
 {
-    file.seek(tokio::io::SeekFrom::Start(skip)).await?;
+    hash_prefix(file, skip, hasher).await?;
 
     let mut buf = vec![0; BUF_SIZE];
     let mut written = skip;
+    let mut unchecked = 0;
 
     while written < size {
-        if !should_continue() {
+        if !(ctx.should_continue)() || !ctx.pause.wait(&mut ctx.should_continue).await {
             return Ok(false);
         }
 
@@ -50,76 +76,482 @@ where
             ));
         }
 
+        ctx.rate_limit.acquire(n as u64).await;
+
+        hasher.update(&buf[..n]);
         file.write_all(&buf[..n]).await?;
         written += n as u64;
 
-        read_callback(n as u64);
+        (ctx.progress)(n as u64);
+
+        unchecked += n as u64;
+        if unchecked >= CHECKPOINT_INTERVAL {
+            file.flush().await?;
+            checkpoint(written, hasher);
+            unchecked = 0;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Generic receive function for data of unknown length, sent with [crate::send::send_chunked]
+///
+/// All bytes are fed into `hasher`, the checksum that follows the data is left to the caller.
+/// The data is received no faster than the rate limit of `ctx` allows.
+///
+/// # Returns
+/// * `Ok(true)` if the end of the data was reached
+/// * `Ok(false)` if the transfer should stop
+pub async fn receive_chunked<R, W>(
+    recv: &mut R,
+    output: &mut W,
+    hasher: &mut blake3::Hasher,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
+) -> std::io::Result<bool>
+where
+    R: tokio::io::AsyncReadExt + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+
+    loop {
+        if !(ctx.should_continue)() || !ctx.pause.wait(&mut ctx.should_continue).await {
+            return Ok(false);
+        }
+
+        let len = recv.read_u32().await? as usize;
+        if len == 0 {
+            return Ok(true);
+        }
+
+        if len > BUF_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk of {} bytes is too large", len),
+            ));
+        }
+
+        recv.read_exact(&mut buf[..len]).await?;
+
+        ctx.rate_limit.acquire(len as u64).await;
+
+        hasher.update(&buf[..len]);
+        output.write_all(&buf[..len]).await?;
+
+        (ctx.progress)(len as u64);
+    }
+}
+
+/// Receive a single file to `path` and verify its checksum
+///
+/// The data is written to the [partial_path] of `path`,
+/// which is renamed to `path` once the file is complete.
+/// `meta` is applied to the complete file, if given.
+/// The progress of the file is reported to `journal`, see [Journal].
+///
+/// # Returns
+/// * `Ok(true)` if the transfer should continue
+/// * `Ok(false)` if the transfer should stop
+async fn receive_to_path<R>(
+    recv: &mut R,
+    path: &Path,
+    meta: Option<&FileMeta>,
+    skip: u64,
+    size: u64,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
+    journal: &mut impl FnMut(FileProgress),
+) -> Result<bool, ReceiveError>
+where
+    R: tokio::io::AsyncReadExt + Unpin,
+{
+    let partial = partial_path(path);
+    reject_symlink(path)?;
+    reject_symlink(&partial)?;
+
+    // Resume a file that was left incomplete under its final name,
+    // its prefix was hashed (see [local_data_path]) so a stale partial file is replaced
+    if skip > 0 && path.exists() {
+        tokio::fs::rename(path, &partial).await?;
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&partial)
+        .await?;
+
+    // Drop any data past the resumed prefix
+    file.set_len(skip).await?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut written = skip;
+    let mut file_ctx = TransferContext {
+        rate_limit: ctx.rate_limit.clone(),
+        pause: ctx.pause.clone(),
+        progress: |n| {
+            written += n;
+            (ctx.progress)(n)
+        },
+        should_continue: &mut ctx.should_continue,
+    };
+    let result = receive_file(
+        recv,
+        &mut file,
+        &mut hasher,
+        skip,
+        size,
+        &mut file_ctx,
+        &mut |len, hasher| {
+            journal(FileProgress::Partial {
+                len,
+                hash: *hasher.finalize().as_bytes(),
+            })
+        },
+    )
+    .await;
+    drop(file_ctx);
+
+    // Finish the pending writes also if the connection was lost,
+    // so the file can be resumed from the reported bytes
+    file.flush().await?;
+    if !matches!(result, Ok(true)) && written > 0 {
+        journal(FileProgress::Partial {
+            len: written,
+            hash: *hasher.finalize().as_bytes(),
+        });
+    }
+    let continues = result?;
+
+    file.sync_all().await?;
+    file.shutdown().await?;
+
+    if !continues {
+        return Ok(false);
+    }
+
+    let mut checksum = [0; blake3::OUT_LEN];
+    recv.read_exact(&mut checksum).await?;
+
+    if hasher.finalize() != checksum {
+        journal(FileProgress::Unknown);
+        return Err(ReceiveError::ChecksumMismatch {
+            path: path.to_path_buf(),
+        });
     }
 
+    tokio::fs::rename(&partial, path).await?;
+
+    if let Some(meta) = meta {
+        meta.apply(path)?;
+    }
+
+    journal(FileProgress::Complete { hash: checksum });
+
     Ok(true)
 }
 
+/// Receive the files of a directory sent with [crate::send::send_directory]
+///
+/// The metadata of the files and subdirectories is applied if `preserve_metadata` is set,
+/// the caller is responsible for the metadata of `root_path` itself.
+///
 /// # Returns
 /// * `Ok(true)` if the transfer should continue
 /// * `Ok(false)` if the transfer should stop
-pub fn receive_directory<S>(
-    send: &mut S,
-    root_path: &std::path::Path,
+pub async fn receive_directory<R>(
+    recv: &mut R,
+    root_path: &Path,
     files: &[FileSendRecvTree],
-    read_callback: &mut impl FnMut(u64),
-    should_continue: &mut impl FnMut() -> bool,
-) -> std::io::Result<bool>
+    preserve_metadata: bool,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
+) -> Result<bool, ReceiveError>
 where
-    S: tokio::io::AsyncReadExt + Unpin + Send,
+    R: tokio::io::AsyncReadExt + Unpin,
 {
+    let mut to_receive = Vec::new();
     for file in files {
-        match file {
-            FileSendRecvTree::File { name, skip, size } => {
-                let path = root_path.join(name);
-
-                let continues = tokio::task::block_in_place(|| {
-                    let rt = tokio::runtime::Runtime::new().unwrap();
-                    rt.block_on(async {
-                        let mut file = tokio::fs::OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .open(&path)
-                            .await?;
-                        let continues = receive_file(
-                            send,
-                            &mut file,
-                            *skip,
-                            *size,
-                            read_callback,
-                            should_continue,
-                        )
-                        .await?;
-
-                        file.sync_all().await?;
-                        file.shutdown().await?;
-                        Ok::<bool, std::io::Error>(continues)
-                    })
-                })?;
+        let path = root_path.join(file.name());
+        create_tree(file, &path)?;
+        file.flatten(&path, 0, &mut to_receive);
+    }
 
-                if !continues {
-                    return Ok(false);
-                }
+    for file in to_receive {
+        if !receive_to_path(
+            recv,
+            &file.path,
+            preserve_metadata.then_some(&file.meta),
+            file.skip,
+            file.size,
+            ctx,
+            &mut |_| {},
+        )
+        .await?
+        {
+            return Ok(false);
+        }
+    }
+
+    if preserve_metadata {
+        for file in files {
+            apply_tree_meta(file, &root_path.join(file.name()))?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Create a symlink at `path` pointing to `target`,
+/// an existing symlink at `path` is replaced
+fn create_symlink(path: &Path, target: &str) -> io::Result<()> {
+    if path.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        std::fs::remove_file(path)?;
+    }
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)?;
+
+    #[cfg(windows)]
+    {
+        let resolved = path.parent().unwrap_or(Path::new(".")).join(target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, path)?;
+        } else {
+            std::os::windows::fs::symlink_file(target, path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fail if `path` is a symlink, so no file or directory is written through it,
+/// the link may point outside of the output directory
+fn reject_symlink(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("refusing to write through the symlink {}", path.display()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Create the directories and symlinks of `tree` at `path`,
+/// so its files can be received in any order
+fn create_tree(tree: &FileSendRecvTree, path: &Path) -> io::Result<()> {
+    match tree {
+        FileSendRecvTree::File { .. } => {}
+        FileSendRecvTree::Dir { files, .. } => {
+            reject_symlink(path)?;
+            if !path.exists() {
+                std::fs::create_dir(path)?;
             }
-            FileSendRecvTree::Dir { name, files } => {
-                let root_path = root_path.join(name);
 
-                if !root_path.exists() {
-                    std::fs::create_dir(&root_path)?;
-                }
+            for file in files {
+                create_tree(file, &path.join(file.name()))?;
+            }
+        }
+        FileSendRecvTree::Symlink { target, .. } => create_symlink(path, target)?,
+    }
 
-                if !receive_directory(send, &root_path, files, read_callback, should_continue)? {
-                    return Ok(false);
-                }
+    Ok(())
+}
+
+/// Apply the metadata of the files and directories of `tree` at `path`,
+/// once all files inside of them are written
+///
+/// The received files already have their metadata,
+/// this covers the directories and the files that were skipped on resume.
+fn apply_tree_meta(tree: &FileSendRecvTree, path: &Path) -> io::Result<()> {
+    match tree {
+        FileSendRecvTree::File { meta, .. } => meta.apply(path)?,
+        FileSendRecvTree::Dir { meta, files, .. } => {
+            for file in files {
+                apply_tree_meta(file, &path.join(file.name()))?;
             }
+
+            meta.apply(path)?;
         }
+        FileSendRecvTree::Symlink { .. } => {}
     }
 
-    Ok(true)
+    Ok(())
+}
+
+/// The parts of the offered files that are left to receive after the sender verified
+/// the skip list, `None` for the entries that are fully skipped or not selected
+fn remaining_files(
+    files_offered: &[FilesAvailable],
+    files_to_skip: &[Option<FilesToSkip>],
+    selected: &[Option<&FilesAvailable>],
+) -> Result<Vec<Option<FileSendRecvTree>>, ReceiveError> {
+    if files_to_skip.len() != files_offered.len() {
+        return Err(InvalidSkipList::Length {
+            expected: files_offered.len(),
+            got: files_to_skip.len(),
+        }
+        .into());
+    }
+
+    let to_receive = files_offered
+        .iter()
+        .zip(files_to_skip)
+        .zip(selected)
+        .map(|((offered, skip), selected)| {
+            let Some(selected) = selected else {
+                return Ok(None);
+            };
+
+            let remaining = match skip {
+                Some(skip) => offered.remove_skipped(skip)?,
+                None => Some(offered.to_send_recv_tree()),
+            };
+            Ok(remaining.and_then(|tree| tree.retain_selected(selected)))
+        })
+        .collect::<Result<_, InvalidSkipList>>()?;
+
+    Ok(to_receive)
+}
+
+/// Check the number of streams the sender announced for `pending` files,
+/// at most one stream per file and [MAX_STREAMS] are accepted
+fn check_streams(streams: u32, pending: usize) -> Result<usize, ReceiveError> {
+    let max = pending.min(MAX_STREAMS);
+    if streams as usize > max {
+        return Err(ReceiveError::TooManyStreams { streams, max });
+    }
+
+    Ok(streams as usize)
+}
+
+/// Read the index of the next file on a stream, `None` if the stream ended
+async fn read_file_id<R>(recv: &mut R) -> io::Result<Option<u32>>
+where
+    R: tokio::io::AsyncReadExt + Unpin,
+{
+    let mut id = [0; 4];
+    if recv.read(&mut id[..1]).await? == 0 {
+        return Ok(None);
+    }
+    recv.read_exact(&mut id[1..]).await?;
+
+    Ok(Some(u32::from_be_bytes(id)))
+}
+
+/// Progress of a transfer, shared by its streams
+struct ReceiveProgress {
+    /// If each file was started, so every file is sent only once
+    started: Vec<AtomicBool>,
+    /// Bytes of each file written to disk, including the skipped part
+    written: Vec<AtomicU64>,
+    /// If each file is received and verified
+    complete: Vec<AtomicBool>,
+    interrupted: AtomicBool,
+}
+
+impl ReceiveProgress {
+    fn new(files: &[TransferFile]) -> Self {
+        Self {
+            started: files.iter().map(|_| AtomicBool::new(false)).collect(),
+            written: files.iter().map(|f| AtomicU64::new(f.skip)).collect(),
+            complete: files.iter().map(|_| AtomicBool::new(false)).collect(),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    /// The bytes of each file to resume from, `None` if the file is complete
+    fn offsets(&self) -> Vec<Option<u64>> {
+        self.written
+            .iter()
+            .zip(&self.complete)
+            .map(|(written, complete)| {
+                (!complete.load(Ordering::Relaxed)).then(|| written.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+
+    /// Receive the incomplete files again from the bytes written so far
+    ///
+    /// # Returns
+    /// The indices of the files that are left
+    fn resume(&self, files: &mut [TransferFile]) -> Vec<usize> {
+        let mut pending = Vec::new();
+        for (id, file) in files.iter_mut().enumerate() {
+            if !self.complete[id].load(Ordering::Relaxed) {
+                file.skip = self.written[id].load(Ordering::Relaxed);
+                self.started[id].store(false, Ordering::Relaxed);
+                pending.push(id);
+            }
+        }
+
+        pending
+    }
+}
+
+/// Receive the files sent on the next incoming stream, until the sender finishes it
+///
+/// The progress is recorded in `journal` with the position of each file in it.
+/// The callbacks of `ctx` are shared by all streams
+async fn receive_stream(
+    conn: &iroh::endpoint::Connection,
+    files: &[TransferFile],
+    progress: &ReceiveProgress,
+    journal: Option<(&Journal, &[(usize, usize)])>,
+    preserve_metadata: bool,
+    ctx: &TransferContext<Mutex<impl FnMut(usize, u64)>, Mutex<impl FnMut() -> bool>>,
+) -> Result<(), ReceiveError> {
+    let recv = conn.accept_uni().await?;
+    let mut recv = tokio::io::BufReader::with_capacity(BUF_SIZE, recv);
+    let interrupted = &progress.interrupted;
+
+    while let Some(id) = read_file_id(&mut recv).await? {
+        // Every file must be sent exactly once
+        let file = files
+            .get(id as usize)
+            .filter(|_| !progress.started[id as usize].swap(true, Ordering::Relaxed))
+            .ok_or(ReceiveError::InvalidFileId(id))?;
+
+        let algorithm = compression::read_tag(&mut recv).await?;
+        let mut decoder = compression::decoder(&mut recv, algorithm);
+
+        let mut file_ctx = TransferContext {
+            rate_limit: ctx.rate_limit.clone(),
+            pause: ctx.pause.clone(),
+            progress: |n| {
+                progress.written[id as usize].fetch_add(n, Ordering::Relaxed);
+                (ctx.progress.lock().unwrap())(file.entry, n)
+            },
+            should_continue: || {
+                !interrupted.load(Ordering::Relaxed) && (ctx.should_continue.lock().unwrap())()
+            },
+        };
+        let continues = receive_to_path(
+            &mut decoder,
+            &file.path,
+            preserve_metadata.then_some(&file.meta),
+            file.skip,
+            file.size,
+            &mut file_ctx,
+            &mut |file_progress| {
+                if let Some((journal, ids)) = journal {
+                    journal.record(ids[id as usize], file_progress);
+                }
+            },
+        )
+        .await?;
+
+        if !continues {
+            interrupted.store(true, Ordering::Relaxed);
+            break;
+        }
+
+        progress.complete[id as usize].store(true, Ordering::Relaxed);
+        compression::finish_decoder(&mut decoder, algorithm).await?;
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -134,8 +566,16 @@ pub enum ReceiveError {
     Write(#[from] quinn::WriteError),
     #[error("read error {0}")]
     Read(#[from] quinn::ReadError),
-    #[error("version mismatch, expected: {0}, got: {1}")]
-    WrongVersion(String, String),
+    #[error(
+        "incompatible versions, the sender has {remote} ({remote_capabilities}), \
+         but this is {local} ({local_capabilities})"
+    )]
+    WrongVersion {
+        local: String,
+        local_capabilities: Capabilities,
+        remote: String,
+        remote_capabilities: Capabilities,
+    },
     #[error(
         "wrong roundezvous protocol version, the roundezvous server expected {0}, but got: {1}"
     )]
@@ -148,8 +588,93 @@ pub enum ReceiveError {
     InvalidCode,
     #[error("receive packet error: {0}")]
     ReceivePacket(#[from] PacketRecvError),
+    #[error("checksum mismatch: {path}")]
+    ChecksumMismatch { path: PathBuf },
+    #[error("file already exists: {0}")]
+    AlreadyExists(PathBuf),
+    #[error("invalid file name offered: {0:?}")]
+    InvalidFileName(String),
+    #[error("the file name {0:?} is offered more than once")]
+    DuplicateFileName(String),
+    #[error("symlink {name:?} points outside of the output directory: {target:?}")]
+    InvalidSymlinkTarget { name: String, target: String },
+    #[error("invalid skip list: {0}")]
+    InvalidSkipList(#[from] InvalidSkipList),
+    #[error("invalid file id: {0}")]
+    InvalidFileId(u32),
+    #[error("file is not part of the offer: {0}")]
+    UnknownFile(PathBuf),
+    #[error("the sender wants to use {streams} streams, at most {max} are accepted")]
+    TooManyStreams { streams: u32, max: usize },
+    #[error("{0} files were not sent")]
+    MissingFiles(usize),
+    #[error("not enough disk space, {needed} bytes needed but only {available} bytes available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("the sender cancelled the transfer ({reason})")]
+    PeerCancelled { reason: String },
+    #[error("the sender stopped because of an error ({kind}): {message}")]
+    PeerError { kind: ErrorKind, message: String },
+    #[error("the sender shares a directory, list it and request paths from it")]
+    Shared,
+    #[error("the sender does not share a directory")]
+    NotShared,
+    #[error("the sender streams data of unknown length, receive it into an output stream")]
+    Streamed,
+    #[error("the sender does not stream data")]
+    NotStreamed,
+}
+
+impl ReceiveError {
+    /// The kind of the error, as it is sent to the sender
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ReceiveError::Io(_)
+            | ReceiveError::AlreadyExists(_)
+            | ReceiveError::InsufficientSpace { .. } => ErrorKind::Io,
+            ReceiveError::ChecksumMismatch { .. } => ErrorKind::Checksum,
+            ReceiveError::WrongVersion { .. }
+            | ReceiveError::UnexpectedDataPacket(_)
+            | ReceiveError::ReceivePacket(_)
+            | ReceiveError::InvalidFileName(_)
+            | ReceiveError::DuplicateFileName(_)
+            | ReceiveError::InvalidSymlinkTarget { .. }
+            | ReceiveError::InvalidSkipList(_)
+            | ReceiveError::InvalidFileId(_)
+            | ReceiveError::UnknownFile(_)
+            | ReceiveError::TooManyStreams { .. }
+            | ReceiveError::MissingFiles(_) => ErrorKind::Protocol,
+            ReceiveError::PeerError { kind, .. } => *kind,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<PeerStop> for ReceiveError {
+    fn from(stop: PeerStop) -> Self {
+        match stop {
+            PeerStop::Cancelled { reason } => ReceiveError::PeerCancelled { reason },
+            PeerStop::Failed { kind, message } => ReceiveError::PeerError { kind, message },
+        }
+    }
+}
+
+/// Read a packet the sender sends while the file data is transferred
+fn control_packet(packet: SenderToReceiver) -> Option<ControlPacket> {
+    match packet {
+        SenderToReceiver::Paused { paused } => Some(ControlPacket::Paused(paused)),
+        SenderToReceiver::Cancel { reason } => {
+            Some(ControlPacket::Stop(PeerStop::Cancelled { reason }))
+        }
+        SenderToReceiver::Error { kind, message } => {
+            Some(ControlPacket::Stop(PeerStop::Failed { kind, message }))
+        }
+        _ => None,
+    }
 }
 
+/// Accepts (true) or rejects text offered by the sender
+type AcceptTextCallback = Box<dyn FnMut(&str) -> bool + Send>;
+
 /// A receiver that can receive files
 pub struct Receiver {
     /// Receiver arguments
@@ -158,12 +683,272 @@ pub struct Receiver {
     conn: iroh::endpoint::Connection,
     /// The local endpoint
     endpoint: iroh::Endpoint,
+    /// The address of the sender, to reconnect to it
+    node_addr: iroh::NodeAddr,
+    /// The session with a sender that shares a directory, once it was listed
+    share: Option<Session>,
+    /// Decides on text the sender offers instead of files, see [Receiver::on_text]
+    accept_text_callback: Option<AcceptTextCallback>,
+}
+
+/// What to do when an offered file or directory already exists,
+/// see [resolve_collision] for how it applies to directories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Resume the existing file if it is a prefix of the offered one
+    #[default]
+    Resume,
+    /// Receive the file again and replace the existing one
+    Overwrite,
+    /// Keep both, the offered file is received under a new name, e.g. `report (1).pdf`
+    Rename,
+    /// Keep the existing files and don't receive them again
+    Skip,
+    /// Reject the transfer
+    Fail,
+}
+
+impl std::str::FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resume" => Ok(CollisionPolicy::Resume),
+            "overwrite" => Ok(CollisionPolicy::Overwrite),
+            "rename" => Ok(CollisionPolicy::Rename),
+            "skip" => Ok(CollisionPolicy::Skip),
+            "fail" => Ok(CollisionPolicy::Fail),
+            _ => Err(format!(
+                "invalid collision policy {:?}, expected resume, overwrite, rename, skip or fail",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollisionPolicy::Resume => write!(f, "resume"),
+            CollisionPolicy::Overwrite => write!(f, "overwrite"),
+            CollisionPolicy::Rename => write!(f, "rename"),
+            CollisionPolicy::Skip => write!(f, "skip"),
+            CollisionPolicy::Fail => write!(f, "fail"),
+        }
+    }
 }
 
+/// What the receiver does with an offered file or directory,
+/// the result of the [CollisionPolicy]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileAction {
+    /// Nothing exists yet
+    Create,
+    /// The existing data is resumed (if the sender confirms it)
+    Resume,
+    /// The existing file or directory is overwritten
+    Overwrite,
+    /// Received to a new path to keep the existing one
+    Rename(PathBuf),
+    /// The existing files are kept
+    Skip,
+    /// Not selected by the receiver
+    Excluded,
+}
+
+/// The receiver's answer to an offer
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptFiles {
+    /// Directory the files are received into
+    pub output_path: PathBuf,
+    /// The selected part of each offered file/dir (see [FilesAvailable::select]),
+    /// `None` to receive everything
+    pub selection: Option<Vec<Option<FilesAvailable>>>,
+}
+
+impl AcceptFiles {
+    /// Receive all offered files into `output_path`
+    pub fn all(output_path: PathBuf) -> Self {
+        Self {
+            output_path,
+            selection: None,
+        }
+    }
+}
+
+/// Apply the [CollisionPolicy] to the offered top-level file or directory that already exists
+/// at `path` as `local`, `recorded` is what the [Journal] recorded for it.
+///
+/// The policy decides for the entry as a whole: [CollisionPolicy::Resume] and
+/// [CollisionPolicy::Skip] compare the files of a directory one by one, but
+/// [CollisionPolicy::Rename] receives the whole directory under a new name and
+/// [CollisionPolicy::Fail] rejects it, even if none of its files exist yet.
+/// [CollisionPolicy::Overwrite] replaces the offered files and keeps any other local files.
+/// # Returns
+/// - The target path, the action and the files to skip
+/// - [std::option::Option::None] if the transfer has to be rejected
+fn resolve_collision(
+    offered: &FilesAvailable,
+    local: &FilesAvailable,
+    path: &Path,
+    collision: CollisionPolicy,
+    recorded: Option<FilesToSkip>,
+) -> io::Result<Option<(PathBuf, FileAction, Option<FilesToSkip>)>> {
+    let path = path.to_path_buf();
+
+    let resolved = match collision {
+        CollisionPolicy::Resume => match recorded {
+            Some(recorded) => (path, FileAction::Resume, Some(recorded)),
+            None => match offered.get_skippable(local) {
+                Some(mut skippable) => {
+                    skippable.hash_prefixes(&path)?;
+                    (path, FileAction::Resume, Some(skippable))
+                }
+                None => (path, FileAction::Overwrite, None),
+            },
+        },
+        CollisionPolicy::Overwrite => (path, FileAction::Overwrite, None),
+        CollisionPolicy::Rename => {
+            let renamed = unique_path(&path);
+            (renamed.clone(), FileAction::Rename(renamed), None)
+        }
+        CollisionPolicy::Skip => (path, FileAction::Skip, offered.get_existing(local)),
+        CollisionPolicy::Fail => return Ok(None),
+    };
+
+    Ok(Some(resolved))
+}
+
+/// Find a free path next to `path` by appending a counter, e.g. `report (1).pdf`
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    let extension = path.extension();
+
+    (1..)
+        .map(|i| {
+            let mut name = stem.to_os_string();
+            name.push(format!(" ({})", i));
+            if let Some(extension) = extension {
+                name.push(".");
+                name.push(extension);
+            }
+            path.with_file_name(name)
+        })
+        .find(|p| p.symlink_metadata().is_err() && !partial_path(p).exists())
+        .unwrap()
+}
+
+/// Disk space needed by a transfer on one filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskSpace {
+    /// An existing directory on the filesystem
+    pub path: PathBuf,
+    /// Bytes that still have to be received
+    pub needed: u64,
+    /// Bytes available to the current user
+    pub available: u64,
+}
+
+impl DiskSpace {
+    pub fn is_sufficient(&self) -> bool {
+        self.needed <= self.available
+    }
+}
+
+/// The space the offered (or selected) files need when received into `output_path`,
+/// without the data that `collision` would keep
+pub fn required_space<'a>(
+    files_offered: impl IntoIterator<Item = &'a FilesAvailable>,
+    output_path: &Path,
+    collision: CollisionPolicy,
+) -> io::Result<Vec<DiskSpace>> {
+    let needed = files_offered.into_iter().map(|offered| {
+        let path = output_path.join(offered.name());
+        let local = get_files_received(&path).ok();
+
+        let skip = match (local, collision) {
+            (Some(local), CollisionPolicy::Resume) => offered.get_skippable(&local),
+            (Some(local), CollisionPolicy::Skip) => offered.get_existing(&local),
+            _ => None,
+        };
+
+        (
+            path,
+            offered
+                .size()
+                .saturating_sub(skip.map(|s| s.skip()).unwrap_or(0)),
+        )
+    });
+
+    disk_space(needed)
+}
+
+/// Sum up the bytes needed at each path per filesystem
+///
+/// Overwritten files are only replaced once the new data is complete,
+/// so they don't free any space up front.
+fn disk_space(needed: impl IntoIterator<Item = (PathBuf, u64)>) -> io::Result<Vec<DiskSpace>> {
+    let mut spaces: Vec<(u64, DiskSpace)> = Vec::new();
+
+    for (path, bytes) in needed {
+        // The path itself usually does not exist yet
+        let existing = path
+            .ancestors()
+            .find(|p| p.is_dir())
+            .unwrap_or(Path::new("."));
+        let id = filesystem_id(existing)?;
+
+        match spaces.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, space)) => space.needed += bytes,
+            None => spaces.push((
+                id,
+                DiskSpace {
+                    path: existing.to_path_buf(),
+                    needed: bytes,
+                    available: fs4::available_space(existing)?,
+                },
+            )),
+        }
+    }
+
+    Ok(spaces.into_iter().map(|(_, space)| space).collect())
+}
+
+#[cfg(unix)]
+fn filesystem_id(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.dev())
+}
+
+/// Everything is counted as one filesystem where the device is not known
+#[cfg(not(unix))]
+fn filesystem_id(_path: &Path) -> io::Result<u64> {
+    Ok(0)
+}
+
+/// The delay before the first reconnect attempt, doubled after every failed attempt
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(8);
+
 /// Arguments for the receiver
 pub struct ReceiverArgs {
-    /// Resume interrupted transfer
-    pub resume: bool,
+    /// What to do with files that already exist
+    pub collision: CollisionPolicy,
+    /// Apply the modification time and permissions of the sender's files
+    pub preserve_metadata: bool,
+    /// Receive the files even if there does not seem to be enough disk space
+    pub ignore_free_space: bool,
+    /// Limit of the download speed, keep a clone to change it during the transfer
+    pub rate_limit: RateLimit,
+    /// Keep a clone to pause and resume the transfer
+    pub pause: Pause,
+    /// How long to try to reconnect to the sender after the connection was lost,
+    /// zero to fail right away
+    pub reconnect_timeout: Duration,
+    /// Keep a [Journal] in the output directory to resume interrupted transfers
+    pub journal: bool,
+    /// The compression algorithms and levels the sender may use
+    pub compression: AcceptedCompression,
 }
 
 impl Receiver {
@@ -172,10 +957,14 @@ impl Receiver {
         node_addr: iroh::NodeAddr,
         args: ReceiverArgs,
     ) -> Result<Self, ReceiveError> {
+        // A sender of version 0.4 only knows its own ALPN, see [Receiver::handshake]
+        let options = iroh::endpoint::ConnectOptions::new()
+            .with_additional_alpns(vec![QS_LEGACY_ALPN.to_vec()]);
         let conn = this_endpoint
-            .connect(node_addr, QS_ALPN)
+            .connect_with_opts(node_addr.clone(), QS_ALPN, options)
             .await
-            .map_err(|e| ReceiveError::Connect(e.to_string()))?;
+            .map_err(|e| ReceiveError::Connect(e.to_string()))?
+            .await?;
 
         tracing::info!("receiver connected to sender");
 
@@ -183,12 +972,21 @@ impl Receiver {
             args,
             conn,
             endpoint: this_endpoint,
+            node_addr,
+            share: None,
+            accept_text_callback: None,
         })
     }
 
+    /// Set the callback that accepts (true) or rejects text the sender offers instead of files
+    /// in [Receiver::receive_files], the text is rejected if no callback is set
+    pub fn on_text(&mut self, accept_text_callback: impl FnMut(&str) -> bool + Send + 'static) {
+        self.accept_text_callback = Some(Box::new(accept_text_callback));
+    }
+
     /// Close the connection
     pub async fn close(&mut self) {
-        self.conn.close(0u32.into(), &[0]);
+        CloseCode::Normal.close(&self.conn, "");
         self.endpoint.close().await;
     }
 
@@ -203,168 +1001,1745 @@ impl Receiver {
         self.endpoint.conn_type(node_id).ok()?.get().ok()
     }
 
-    /// Receive files
-    /// # Arguments
-    /// * `initial_progress_callback` - Callback with the initial progress of each file to send (name, current, total)
-    /// * `accept_files_callback` - Callback to accept or reject the files (Some(path) to accept, None to reject)
-    /// * `read_callback` - Callback every time data is written to disk
-    /// * `should_continue` - Callback to check if the transfer should continue
+    /// Connect to the sender again after the connection was lost
+    /// and resume the session `session_id` from `offsets`,
+    /// retrying with an increasing delay until the reconnect timeout
+    ///
+    /// The versions are checked again, the new connection has to agree
+    /// on the same `capabilities` as the session.
     ///
     /// # Returns
-    /// * `Ok(true)` if the transfer was finished successfully
-    /// * `Ok(false)` if the transfer was stopped
-    pub async fn receive_files(
+    /// * `Ok(Some((control, streams)))` with the number of streams the remaining files are sent on
+    /// * `Ok(None)` if the sender could not be reached
+    /// * `Err` if the sender refused to resume or is not compatible anymore
+    async fn reconnect(
         &mut self,
-        mut initial_progress_callback: impl FnMut(&[(String, u64, u64)]),
-        mut accept_files_callback: impl FnMut(&[FilesAvailable]) -> Option<PathBuf>,
-        read_callback: &mut impl FnMut(u64),
-        should_continue: &mut impl FnMut() -> bool,
-    ) -> Result<bool, ReceiveError> {
-        match receive_packet::<SenderToReceiver>(&self.conn).await? {
-            SenderToReceiver::ConnRequest { version_num } => {
-                if version_num != QS_PROTO_VERSION {
-                    send_packet(
-                        ReceiverToSender::WrongVersion {
-                            expected: QS_PROTO_VERSION.to_string(),
-                        },
-                        &self.conn,
-                    )
-                    .await?;
-                    return Err(ReceiveError::WrongVersion(
-                        QS_PROTO_VERSION.to_string(),
-                        version_num,
-                    ));
-                }
-                send_packet(ReceiverToSender::Ok, &self.conn).await?;
+        session_id: u64,
+        offsets: &[Option<u64>],
+        capabilities: Capabilities,
+        compression: Compression,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<Option<(ControlChannel, u32)>, ReceiveError> {
+        let deadline = tokio::time::Instant::now() + self.args.reconnect_timeout;
+        let mut delay = RECONNECT_MIN_DELAY;
+
+        for attempt in 1.. {
+            connection_callback(ConnectionState::Reconnecting { attempt });
+
+            let handshake = async {
+                let conn = self
+                    .endpoint
+                    .connect(self.node_addr.clone(), QS_ALPN)
+                    .await
+                    .map_err(|e| ReceiveError::Connect(e.to_string()))?;
+
+                let mut control = ControlChannel::open(&conn).await?;
+                control.compression = compression;
+                let ours = Hello::new(Capabilities::supported());
+                control.send_hello(&ours).await?;
+                control
+                    .send(ReceiverToSender::Resume {
+                        session_id,
+                        offsets: offsets.to_vec(),
+                    })
+                    .await?;
+
+                let hello = control.receive_hello().await?;
+                if !is_compatible(&ours, &hello)
+                    || hello.capabilities.intersection(ours.capabilities) != capabilities
+                {
+                    CloseCode::Normal.close(&conn, "");
+                    return Err(ReceiveError::WrongVersion {
+                        local: QS_PROTO_VERSION.to_string(),
+                        local_capabilities: Capabilities::supported(),
+                        remote: hello.version.to_string(),
+                        remote_capabilities: hello.capabilities,
+                    });
+                }
+
+                match control.receive::<SenderToReceiver>().await? {
+                    SenderToReceiver::Resumed { streams } => Ok((conn, control, streams)),
+                    SenderToReceiver::Error { kind, message } => {
+                        CloseCode::Normal.close(&conn, "");
+                        Err(ReceiveError::PeerError { kind, message })
+                    }
+                    p => Err(ReceiveError::UnexpectedDataPacket(p)),
+                }
+            };
+
+            match tokio::time::timeout_at(deadline, handshake).await {
+                Ok(Ok((conn, control, streams))) => {
+                    self.conn = conn;
+                    return Ok(Some((control, streams)));
+                }
+                Ok(Err(
+                    e @ (ReceiveError::PeerError { .. } | ReceiveError::WrongVersion { .. }),
+                )) => return Err(e),
+                Ok(Err(e)) => tracing::warn!("reconnect attempt {} failed: {}", attempt, e),
+                Err(_) => return Ok(None),
+            }
+
+            if tokio::time::Instant::now() + delay >= deadline {
+                return Ok(None);
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+
+        unreachable!();
+    }
+
+    /// Receive files
+    /// # Arguments
+    /// * `initial_progress_callback` - Callback with the initial progress of each file to receive (name, current, total, action)
+    /// * `accept_files_callback` - Callback to accept or reject the files (Some([AcceptFiles]) to accept
+    ///   all or a selection of them, None to reject), gets a function that computes the [DiskSpace]
+    ///   needed for an output path and a selection (all offered files if `None`)
+    /// * `read_callback` - Callback every time data is written to disk (index of the file/dir, bytes)
+    /// * `should_continue` - Callback to check if the transfer should continue
+    /// * `connection_callback` - Callback when the connection is lost, reconnecting and resumed,
+    ///   and when one of the peers pauses or resumes the transfer
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the transfer was finished successfully
+    /// * `Ok(false)` if the transfer was stopped
+    pub async fn receive_files(
+        &mut self,
+        initial_progress_callback: impl FnMut(&[(String, u64, u64, FileAction)]),
+        accept_files_callback: impl FnMut(
+            &[FilesAvailable],
+            &dyn Fn(&Path, Option<&[Option<FilesAvailable>]>) -> io::Result<Vec<DiskSpace>>,
+        ) -> Option<AcceptFiles>,
+        read_callback: &mut impl FnMut(usize, u64),
+        should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, ReceiveError> {
+        let mut session = self.handshake().await?;
+
+        let files_offered = match session.control.receive::<SenderToReceiver>().await? {
+            SenderToReceiver::FileInfo { files } => files,
+            SenderToReceiver::Share => return Err(ReceiveError::Shared),
+            SenderToReceiver::Stream { .. } => {
+                session.control.send(ReceiverToSender::RejectFiles).await?;
+                self.wait_for_close().await;
+                return Err(ReceiveError::Streamed);
+            }
+            SenderToReceiver::Text { content } => {
+                let accepted = self
+                    .accept_text_callback
+                    .as_mut()
+                    .is_some_and(|accept| accept(&content));
+                let reply = if accepted {
+                    ReceiverToSender::AcceptText
+                } else {
+                    ReceiverToSender::RejectFiles
+                };
+                session.control.send(reply).await?;
+                // The sender closes the connection once it has the answer
+                self.wait_for_close().await;
+
+                if !accepted {
+                    return Err(ReceiveError::FilesRejected);
+                }
+                return Ok(true);
             }
             p => return Err(ReceiveError::UnexpectedDataPacket(p)),
+        };
+
+        self.receive_offer(
+            session,
+            files_offered,
+            initial_progress_callback,
+            accept_files_callback,
+            self.transfer_context(read_callback, should_continue),
+            connection_callback,
+        )
+        .await
+    }
+
+    /// List the directory `path` of a sender that shares a directory
+    ///
+    /// `path` is relative to the shared directory, with `/` as separator.
+    /// The directory can be listed several times, before [Receiver::receive_shared].
+    pub async fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, ReceiveError> {
+        let control = &mut self.share_session().await?.control;
+
+        control
+            .send(ReceiverToSender::ListDir {
+                path: path.to_string(),
+            })
+            .await?;
+
+        match control.receive::<SenderToReceiver>().await? {
+            SenderToReceiver::DirListing { entries } => Ok(entries),
+            SenderToReceiver::Error { kind, message } => {
+                Err(ReceiveError::PeerError { kind, message })
+            }
+            p => Err(ReceiveError::UnexpectedDataPacket(p)),
         }
+    }
+
+    /// Request `paths` from a sender that shares a directory and receive them,
+    /// the callbacks are the ones of [Receiver::receive_files]
+    ///
+    /// The paths are relative to the shared directory, with `/` as separator.
+    pub async fn receive_shared(
+        &mut self,
+        paths: Vec<String>,
+        initial_progress_callback: impl FnMut(&[(String, u64, u64, FileAction)]),
+        accept_files_callback: impl FnMut(
+            &[FilesAvailable],
+            &dyn Fn(&Path, Option<&[Option<FilesAvailable>]>) -> io::Result<Vec<DiskSpace>>,
+        ) -> Option<AcceptFiles>,
+        read_callback: &mut impl FnMut(usize, u64),
+        should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, ReceiveError> {
+        self.share_session().await?;
+        let mut session = self.share.take().unwrap();
+
+        session
+            .control
+            .send(ReceiverToSender::RequestFiles { paths })
+            .await?;
 
-        let files_offered = match receive_packet::<SenderToReceiver>(&self.conn).await? {
+        let files_offered = match session.control.receive::<SenderToReceiver>().await? {
             SenderToReceiver::FileInfo { files } => files,
+            SenderToReceiver::Error { kind, message } => {
+                return Err(ReceiveError::PeerError { kind, message })
+            }
             p => return Err(ReceiveError::UnexpectedDataPacket(p)),
         };
 
-        let output_path = match accept_files_callback(&files_offered) {
-            Some(path) => path,
-            None => {
-                send_packet(ReceiverToSender::RejectFiles, &self.conn).await?;
-                // Wait for the sender to acknowledge the rejection
+        self.receive_offer(
+            session,
+            files_offered,
+            initial_progress_callback,
+            accept_files_callback,
+            self.transfer_context(read_callback, should_continue),
+            connection_callback,
+        )
+        .await
+    }
+
+    /// Receive the data a sender streams with [crate::send::Sender::send_reader] into `output`,
+    /// e.g. stdout
+    /// # Arguments
+    /// * `accept_stream_callback` - Callback with the name of the stream, to accept (true) or reject it
+    /// * `read_callback` - Callback every time data is written to `output` (bytes)
+    /// * `should_continue` - Callback to check if the transfer should continue
+    /// * `connection_callback` - Callback when one of the peers pauses or resumes the transfer
+    ///
+    /// The sender can not read the data again, so a stream is not resumed when the connection is lost.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the stream was received completely
+    /// * `Ok(false)` if the transfer was stopped
+    pub async fn receive_stream<W>(
+        &mut self,
+        output: &mut W,
+        mut accept_stream_callback: impl FnMut(&FileName) -> bool,
+        read_callback: &mut impl FnMut(u64),
+        should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, ReceiveError>
+    where
+        W: tokio::io::AsyncWriteExt + Unpin,
+    {
+        let Session {
+            mut control,
+            capabilities,
+            ..
+        } = self.handshake().await?;
+
+        let name = match control.receive::<SenderToReceiver>().await? {
+            SenderToReceiver::Stream { name } => name,
+            SenderToReceiver::FileInfo { .. } | SenderToReceiver::Text { .. } => {
+                control.send(ReceiverToSender::RejectFiles).await?;
                 self.wait_for_close().await;
-                return Err(ReceiveError::FilesRejected);
+                return Err(ReceiveError::NotStreamed);
+            }
+            SenderToReceiver::Share => {
+                self.close().await;
+                return Err(ReceiveError::NotStreamed);
             }
+            p => return Err(ReceiveError::UnexpectedDataPacket(p)),
         };
 
-        let files_available = {
-            let mut files = Vec::new();
-            for file in &files_offered {
-                let path = output_path.join(file.name());
-                files.push(get_files_available(&path).ok());
+        if !accept_stream_callback(&name) {
+            control.send(ReceiverToSender::RejectFiles).await?;
+            self.wait_for_close().await;
+            return Err(ReceiveError::FilesRejected);
+        }
+
+        control.send(ReceiverToSender::AcceptStream).await?;
+
+        let cancellable = capabilities.contains(Capabilities::CANCEL);
+
+        let transfer = async {
+            let recv = self.conn.accept_uni().await?;
+            let mut recv = tokio::io::BufReader::with_capacity(BUF_SIZE, recv);
+
+            let algorithm = compression::read_tag(&mut recv).await?;
+            let mut decoder = compression::decoder(&mut recv, algorithm);
+
+            let mut hasher = blake3::Hasher::new();
+            let mut ctx = self.transfer_context(read_callback, should_continue);
+            let continues = receive_chunked(&mut decoder, output, &mut hasher, &mut ctx).await?;
+            output.flush().await?;
+
+            if !continues {
+                return Ok(false);
+            }
+
+            let mut checksum = [0; blake3::OUT_LEN];
+            decoder.read_exact(&mut checksum).await?;
+
+            if hasher.finalize() != checksum {
+                return Err(ReceiveError::ChecksumMismatch {
+                    path: PathBuf::from(name.as_str()),
+                });
             }
 
-            files
+            compression::finish_decoder(&mut decoder, algorithm).await?;
+            Ok::<_, ReceiveError>(true)
+        };
+        let result = tokio::select! {
+            result = transfer => result,
+            stop = exchange_control(
+                &mut control,
+                &self.args.pause,
+                capabilities,
+                |paused| ReceiverToSender::Paused { paused },
+                control_packet,
+                connection_callback,
+            ) => Err(stop.into()),
+        };
+
+        let continues = match result {
+            Ok(continues) => continues,
+            Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
         };
 
-        let files_to_skip = if self.args.resume {
-            let mut to_skip = Vec::new();
-            for (available, offered) in files_available.iter().zip(&files_offered) {
-                match available {
-                    Some(available) => to_skip.push(offered.get_skippable(available)),
-                    None => to_skip.push(None),
-                }
+        if !continues {
+            tracing::info!("transfer interrupted");
+            send_stop(
+                &self.conn,
+                &mut control,
+                cancellable,
+                ReceiverToSender::Cancel {
+                    reason: CANCEL_REASON.to_string(),
+                },
+                CloseCode::Cancelled,
+                CANCEL_REASON,
+            )
+            .await;
+        }
+
+        self.close().await;
+
+        Ok(continues)
+    }
+
+    /// The session with a sender that shares a directory, started on first use
+    async fn share_session(&mut self) -> Result<&mut Session, ReceiveError> {
+        if self.share.is_none() {
+            let mut session = self.handshake().await?;
+
+            match session.control.receive::<SenderToReceiver>().await? {
+                SenderToReceiver::Share => {}
+                SenderToReceiver::FileInfo { .. }
+                | SenderToReceiver::Stream { .. }
+                | SenderToReceiver::Text { .. } => return Err(ReceiveError::NotShared),
+                p => return Err(ReceiveError::UnexpectedDataPacket(p)),
             }
 
-            to_skip
-        } else {
-            // Don't skip any files
-            vec![None; files_offered.len()]
+            self.share = Some(session);
+        }
+
+        Ok(self.share.as_mut().unwrap())
+    }
+
+    /// Agree on the protocol with the sender
+    async fn handshake(&mut self) -> Result<Session, ReceiveError> {
+        if self.conn.alpn().as_deref() == Some(QS_LEGACY_ALPN) {
+            let remote = legacy_wrong_version(&self.conn).await?;
+            return Err(ReceiveError::WrongVersion {
+                local: QS_PROTO_VERSION.to_string(),
+                local_capabilities: Capabilities::supported(),
+                remote,
+                remote_capabilities: Capabilities::NONE,
+            });
+        }
+
+        let mut control = ControlChannel::accept(&self.conn).await?;
+
+        // Both peers decide on their own if they are compatible
+        let hello = control.receive_hello().await?;
+        let ours = Hello::new(Capabilities::supported());
+        control.send_hello(&ours).await?;
+
+        let capabilities = hello.capabilities.intersection(Capabilities::supported());
+        if !is_compatible(&ours, &hello) {
+            return Err(ReceiveError::WrongVersion {
+                local: QS_PROTO_VERSION.to_string(),
+                local_capabilities: Capabilities::supported(),
+                remote: hello.version.to_string(),
+                remote_capabilities: hello.capabilities,
+            });
+        }
+
+        let accepted = self.args.compression.clone();
+        control
+            .send(ReceiverToSender::Compression {
+                accepted: accepted.clone(),
+            })
+            .await?;
+
+        let session_id = match control.receive::<SenderToReceiver>().await? {
+            SenderToReceiver::ConnRequest {
+                compression,
+                session_id,
+            } => {
+                control.compression = capabilities.negotiate_compression(compression, &accepted);
+                session_id
+            }
+            p => return Err(ReceiveError::UnexpectedDataPacket(p)),
         };
 
-        let to_receive: Vec<Option<FileSendRecvTree>> = files_offered
+        Ok(Session {
+            control,
+            capabilities,
+            id: session_id,
+            peer: self.node_addr.node_id,
+        })
+    }
+
+    /// The [TransferContext] with the limits of the [ReceiverArgs]
+    fn transfer_context<P, C>(&self, progress: P, should_continue: C) -> TransferContext<P, C> {
+        TransferContext {
+            rate_limit: self.args.rate_limit.clone(),
+            pause: self.args.pause.clone(),
+            progress,
+            should_continue,
+        }
+    }
+
+    /// Accept or reject the offered files and receive them
+    async fn receive_offer(
+        &mut self,
+        session: Session,
+        files_offered: Vec<FilesAvailable>,
+        mut initial_progress_callback: impl FnMut(&[(String, u64, u64, FileAction)]),
+        mut accept_files_callback: impl FnMut(
+            &[FilesAvailable],
+            &dyn Fn(&Path, Option<&[Option<FilesAvailable>]>) -> io::Result<Vec<DiskSpace>>,
+        ) -> Option<AcceptFiles>,
+        ctx: TransferContext<&mut impl FnMut(usize, u64), &mut impl FnMut() -> bool>,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, ReceiveError> {
+        let Session {
+            mut control,
+            capabilities,
+            id: session_id,
+            ..
+        } = session;
+
+        let preserve_metadata =
+            self.args.preserve_metadata && capabilities.contains(Capabilities::METADATA);
+
+        // Names are joined onto the output path, so they must not escape it
+        // or replace the journal that is kept there
+        let invalid_name = files_offered.iter().find_map(|f| {
+            f.find_invalid_name().or_else(|| {
+                [JOURNAL_FILE_NAME, JOURNAL_TEMP_NAME]
+                    .contains(&f.name().as_str())
+                    .then(|| f.name())
+            })
+        });
+        if let Some(name) = invalid_name {
+            let name = name.to_string();
+            control.send(ReceiverToSender::RejectFiles).await?;
+            self.wait_for_close().await;
+            return Err(ReceiveError::InvalidFileName(name));
+        }
+
+        // Entries with the same name would be written to the same path at the same time
+        if let Some(name) = find_duplicate_name(&files_offered) {
+            let name = name.to_string();
+            control.send(ReceiverToSender::RejectFiles).await?;
+            self.wait_for_close().await;
+            return Err(ReceiveError::DuplicateFileName(name));
+        }
+
+        if let Some((name, target)) = find_escaping_symlink(&files_offered) {
+            let (name, target) = (name.to_string(), target.to_string());
+            control.send(ReceiverToSender::RejectFiles).await?;
+            self.wait_for_close().await;
+            return Err(ReceiveError::InvalidSymlinkTarget { name, target });
+        }
+
+        let collision = self.args.collision;
+        let space_for =
+            |output_path: &Path, selection: Option<&[Option<FilesAvailable>]>| match selection {
+                Some(selection) => {
+                    required_space(selection.iter().flatten(), output_path, collision)
+                }
+                None => required_space(&files_offered, output_path, collision),
+            };
+
+        let AcceptFiles {
+            output_path,
+            selection,
+        } = match accept_files_callback(&files_offered, &space_for) {
+            Some(accept) => accept,
+            None => {
+                control.send(ReceiverToSender::RejectFiles).await?;
+                // Wait for the sender to acknowledge the rejection
+                self.wait_for_close().await;
+                return Err(ReceiveError::FilesRejected);
+            }
+        };
+
+        // The progress of an earlier attempt, so the local files don't have to be hashed again
+        let previous = (self.args.journal && collision == CollisionPolicy::Resume)
+            .then(|| Journal::load(&output_path, &files_offered))
+            .flatten();
+
+        let mut targets = Vec::with_capacity(files_offered.len());
+        let mut actions = Vec::with_capacity(files_offered.len());
+        let mut files_to_skip = Vec::with_capacity(files_offered.len());
+
+        // The files the receiver did not select are skipped as a whole
+        let selected: Vec<Option<&FilesAvailable>> = match &selection {
+            Some(selection) => (0..files_offered.len())
+                .map(|i| selection.get(i).and_then(|s| s.as_ref()))
+                .collect(),
+            None => files_offered.iter().map(Some).collect(),
+        };
+        let excluded: Vec<Option<FilesToSkip>> = files_offered
             .iter()
-            .zip(&files_to_skip)
-            .map(|(offered, skip)| {
-                if let Some(skip) = skip {
-                    offered.remove_skipped(skip)
-                } else {
-                    Some(offered.to_send_recv_tree())
+            .zip(&selected)
+            .map(|(offered, selected)| offered.excluded(*selected))
+            .collect();
+
+        for (entry, offered) in files_offered.iter().enumerate() {
+            let path = output_path.join(offered.name());
+            let local = get_files_received(&path).ok();
+
+            let (target, action, skip) = match local {
+                _ if selected[entry].is_none() => (path, FileAction::Excluded, None),
+                None => (path, FileAction::Create, None),
+                Some(local) => {
+                    let recorded = (collision == CollisionPolicy::Resume)
+                        .then(|| {
+                            previous
+                                .as_ref()
+                                .and_then(|j| j.skippable(entry, offered, &path))
+                        })
+                        .flatten();
+
+                    match resolve_collision(offered, &local, &path, collision, recorded)? {
+                        Some(resolved) => resolved,
+                        None => {
+                            control.send(ReceiverToSender::RejectFiles).await?;
+                            self.wait_for_close().await;
+                            return Err(ReceiveError::AlreadyExists(path));
+                        }
+                    }
                 }
+            };
+
+            let skip = match excluded[entry].clone() {
+                Some(excluded) => Some(excluded.merge(skip)),
+                None => skip,
+            };
+
+            targets.push(target);
+            actions.push(action);
+            files_to_skip.push(skip);
+        }
+
+        if !self.args.ignore_free_space {
+            let needed = files_offered.iter().zip(&targets).zip(&files_to_skip).map(
+                |((offered, target), skip)| {
+                    let skip = skip.as_ref().map(|s| s.skip()).unwrap_or(0);
+                    (target.clone(), offered.size().saturating_sub(skip))
+                },
+            );
+
+            if let Some(space) = disk_space(needed)?.into_iter().find(|s| !s.is_sufficient()) {
+                control.send(ReceiverToSender::RejectFiles).await?;
+                self.wait_for_close().await;
+                return Err(ReceiveError::InsufficientSpace {
+                    needed: space.needed,
+                    available: space.available,
+                });
+            }
+        }
+
+        control
+            .send(ReceiverToSender::AcceptFilesSkip {
+                files: files_to_skip,
             })
-            .collect();
+            .await?;
+
+        let cancellable = capabilities.contains(Capabilities::CANCEL);
+
+        // The sender restarts files where the prefix hash did not match,
+        // or tells why it can't send the files
+        let verified = match control.receive::<SenderToReceiver>().await {
+            Ok(SenderToReceiver::SkipVerified { files, streams }) => {
+                remaining_files(&files_offered, &files, &selected)
+                    .map(|to_receive| (files, to_receive, streams))
+            }
+            Ok(SenderToReceiver::Error { kind, message }) => {
+                Err(ReceiveError::PeerError { kind, message })
+            }
+            Ok(p) => Err(ReceiveError::UnexpectedDataPacket(p)),
+            Err(e) => Err(e.into()),
+        };
+        let (files_to_skip, to_receive, streams) = match verified {
+            Ok(verified) => verified,
+            Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+        };
 
         // progress callback
-        let mut progress: Vec<(String, u64, u64)> = Vec::with_capacity(to_receive.len());
-        for (offered, skip) in files_offered.iter().zip(&files_to_skip) {
+        let mut progress: Vec<(String, u64, u64, FileAction)> =
+            Vec::with_capacity(to_receive.len());
+        for (((offered, skip), excluded), action) in files_offered
+            .iter()
+            .zip(&files_to_skip)
+            .zip(&excluded)
+            .zip(&actions)
+        {
+            // The totals only count the selected files
+            let excluded = excluded.as_ref().map(|s| s.skip()).unwrap_or(0);
             progress.push((
                 offered.name().to_string(),
-                skip.as_ref().map(|s| s.skip()).unwrap_or(0),
-                offered.size(),
+                skip.as_ref()
+                    .map(|s| s.skip())
+                    .unwrap_or(0)
+                    .saturating_sub(excluded),
+                offered.size() - excluded,
+                action.clone(),
             ));
         }
 
         initial_progress_callback(&progress);
 
-        send_packet(
-            ReceiverToSender::AcceptFilesSkip {
-                files: files_to_skip,
-            },
-            &self.conn,
-        )
-        .await?;
+        let mut files = Vec::new();
+        for (entry, (file, path)) in to_receive.iter().zip(&targets).enumerate() {
+            if let Some(file) = file {
+                file.flatten(path, entry, &mut files);
+            }
+        }
 
-        let recv = self.conn.accept_uni().await?;
-        let mut recv = GzipDecoder::new(tokio::io::BufReader::with_capacity(BUF_SIZE, recv));
-
-        let mut interrupted = false;
-
-        for file in to_receive.into_iter().flatten() {
-            match file {
-                FileSendRecvTree::File { name, skip, size } => {
-                    let path = output_path.join(name);
-                    let mut file = tokio::fs::OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(&path)
-                        .await?;
-
-                    interrupted = !receive_file(
-                        &mut recv,
-                        &mut file,
-                        skip,
-                        size,
-                        read_callback,
-                        should_continue,
-                    )
-                    .await?;
-                    file.sync_all().await?;
-                    file.shutdown().await?;
+        // Every stream waits for the sender to open it
+        let mut streams = match check_streams(streams, files.len()) {
+            Ok(streams) => streams,
+            Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+        };
 
-                    if interrupted {
-                        break;
-                    }
-                }
-                FileSendRecvTree::Dir { name, files } => {
-                    let path = output_path.join(name);
+        for (file, path) in to_receive.iter().zip(&targets) {
+            if let Some(file) = file {
+                create_tree(file, path)?;
+            }
+        }
 
-                    if !path.exists() {
-                        std::fs::create_dir(&path)?;
-                    }
+        let journal = self
+            .args
+            .journal
+            .then(|| Journal::create(&output_path, session_id, &files_offered, previous));
+        let ids = journal_ids(&files_offered, &targets);
+        let ids = match files
+            .iter()
+            .map(|f| ids.get(&f.path).copied().ok_or_else(|| f.path.clone()))
+            .collect::<Result<Vec<(usize, usize)>, _>>()
+        {
+            Ok(ids) => ids,
+            Err(path) => {
+                let e = ReceiveError::UnknownFile(path);
+                return Err(self.stopped(&mut control, e, cancellable).await);
+            }
+        };
+
+        let reconnect = capabilities.contains(Capabilities::RECONNECT)
+            && !self.args.reconnect_timeout.is_zero();
+
+        let state = ReceiveProgress::new(&files);
+        let ctx = TransferContext {
+            rate_limit: ctx.rate_limit,
+            pause: ctx.pause,
+            progress: Mutex::new(ctx.progress),
+            should_continue: Mutex::new(ctx.should_continue),
+        };
+
+        loop {
+            // All streams run to the end, so no write is left pending when resuming
+            let workers = (0..streams).map(|_| {
+                receive_stream(
+                    &self.conn,
+                    &files,
+                    &state,
+                    journal.as_ref().map(|j| (j, ids.as_slice())),
+                    preserve_metadata,
+                    &ctx,
+                )
+            });
+            let results = tokio::select! {
+                results = futures::future::join_all(workers) => results,
+                stop = exchange_control(
+                    &mut control,
+                    &self.args.pause,
+                    capabilities,
+                    |paused| ReceiverToSender::Paused { paused },
+                    control_packet,
+                    connection_callback,
+                ) => vec![Err(stop.into())],
+            };
+            let result: Result<Vec<()>, _> = results.into_iter().collect();
+
+            if let (Err(_), Some(journal)) = (&result, &journal) {
+                journal.flush();
+            }
+
+            let error = match result {
+                Ok(_) => break,
+                Err(e) if reconnect && connection_lost(&self.conn) => e,
+                Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+            };
+
+            tracing::warn!("connection lost: {}", error);
+            connection_callback(ConnectionState::Lost);
+
+            let offsets = state.offsets();
+            let compression = control.compression;
+            let Some((new_control, new_streams)) = self
+                .reconnect(
+                    session_id,
+                    &offsets,
+                    capabilities,
+                    compression,
+                    connection_callback,
+                )
+                .await?
+            else {
+                return Err(error);
+            };
+
+            control = new_control;
+
+            let pending = state.resume(&mut files);
+            streams = match check_streams(new_streams, pending.len()) {
+                Ok(streams) => streams,
+                Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+            };
+            let remaining = remaining_bytes(&files, &pending, progress.len());
+            let progress = progress
+                .iter()
+                .zip(remaining)
+                .map(|((name, _, total, _), remaining)| {
+                    (name.clone(), total.saturating_sub(remaining), *total)
+                })
+                .collect();
 
-                    if !receive_directory(&mut recv, &path, &files, read_callback, should_continue)?
-                    {
-                        interrupted = true;
-                        break;
+            tracing::info!("resumed the transfer");
+            connection_callback(ConnectionState::Resumed { progress });
+        }
+
+        let interrupted = state.interrupted.into_inner();
+
+        if !interrupted {
+            let missing = state
+                .complete
+                .iter()
+                .filter(|c| !c.load(Ordering::Relaxed))
+                .count();
+            if missing > 0 {
+                if let Some(journal) = &journal {
+                    journal.flush();
+                }
+                let error = ReceiveError::MissingFiles(missing);
+                return Err(self.stopped(&mut control, error, cancellable).await);
+            }
+
+            if preserve_metadata {
+                for (entry, path) in targets.iter().enumerate() {
+                    // The files skipped on resume are not in `to_receive`,
+                    // but they are the offered ones and get the same metadata
+                    let tree = match (&actions[entry], selected[entry]) {
+                        (FileAction::Resume, Some(selected)) => files_offered[entry]
+                            .to_send_recv_tree()
+                            .retain_selected(selected),
+                        _ => to_receive[entry].clone(),
+                    };
+                    if let Some(tree) = tree {
+                        apply_tree_meta(&tree, path)?;
                     }
                 }
             }
         }
 
-        self.close().await;
+        match journal {
+            Some(journal) if !interrupted => journal.remove()?,
+            Some(journal) => journal.flush(),
+            None => {}
+        }
 
         if interrupted {
             tracing::info!("transfer interrupted");
+            send_stop(
+                &self.conn,
+                &mut control,
+                cancellable,
+                ReceiverToSender::Cancel {
+                    reason: CANCEL_REASON.to_string(),
+                },
+                CloseCode::Cancelled,
+                CANCEL_REASON,
+            )
+            .await;
         }
 
+        self.close().await;
+
         Ok(!interrupted)
     }
+
+    /// Find out why the transfer failed with `error`,
+    /// and tell the sender about it unless the sender stopped the transfer
+    async fn stopped(
+        &self,
+        control: &mut ControlChannel,
+        error: ReceiveError,
+        cancellable: bool,
+    ) -> ReceiveError {
+        let error = match error {
+            ReceiveError::PeerCancelled { .. } | ReceiveError::PeerError { .. } => error,
+            error => match peer_stop(&self.conn, control, cancellable, control_packet).await {
+                Some(stop) => stop.into(),
+                None => {
+                    let message = error.to_string();
+                    let packet = ReceiverToSender::Error {
+                        kind: error.kind(),
+                        message: message.clone(),
+                    };
+                    send_stop(
+                        &self.conn,
+                        control,
+                        cancellable,
+                        packet,
+                        CloseCode::Error,
+                        &message,
+                    )
+                    .await;
+                    return error;
+                }
+            },
+        };
+
+        CloseCode::Normal.close(&self.conn, "");
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{get_files_available, SymlinkPolicy},
+        send::{send_chunked, send_directory, send_file, SendError, Sender, SenderArgs},
+        test_utils,
+    };
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_checksum_roundtrip() {
+        let data: Vec<u8> = (0..3 * BUF_SIZE as u32).map(|i| (i % 251) as u8).collect();
+        let skip = BUF_SIZE as u64 + 17;
+
+        let mut stream = Vec::new();
+        send_file(
+            &mut stream,
+            &mut Cursor::new(data.clone()),
+            skip,
+            data.len() as u64,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut partial = Cursor::new(data[..skip as usize].to_vec());
+        let mut hasher = blake3::Hasher::new();
+        let mut recv = Cursor::new(stream);
+        receive_file(
+            &mut recv,
+            &mut partial,
+            &mut hasher,
+            skip,
+            data.len() as u64,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+            &mut |_, _| {},
+        )
+        .await
+        .unwrap();
+
+        let mut checksum = [0; blake3::OUT_LEN];
+        recv.read_exact(&mut checksum).await.unwrap();
+
+        assert_eq!(partial.into_inner(), data);
+        assert_eq!(hasher.finalize(), checksum);
+        assert_eq!(checksum, *blake3::hash(&data).as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_roundtrip() {
+        let data: Vec<u8> = (0..2 * BUF_SIZE as u32 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        // A pipe does not tell its length, only when it ends
+        let mut stream = Vec::new();
+        let (mut pipe, mut pipe_end) = tokio::io::duplex(1000);
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move { pipe_end.write_all(&data).await })
+        };
+        send_chunked(
+            &mut stream,
+            &mut pipe,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+        writer.await.unwrap().unwrap();
+
+        // Data behind the end marker is not part of the stream
+        stream.extend_from_slice(b"trailing");
+
+        let mut output = Vec::new();
+        let mut hasher = blake3::Hasher::new();
+        let mut recv = Cursor::new(stream);
+        let finished = receive_chunked(
+            &mut recv,
+            &mut output,
+            &mut hasher,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(finished);
+
+        let mut checksum = [0; blake3::OUT_LEN];
+        recv.read_exact(&mut checksum).await.unwrap();
+
+        assert_eq!(output, data);
+        assert_eq!(hasher.finalize(), checksum);
+
+        let mut trailing = Vec::new();
+        recv.read_to_end(&mut trailing).await.unwrap();
+        assert_eq!(trailing, b"trailing");
+    }
+
+    #[test]
+    fn test_unique_path() {
+        let dir = std::env::temp_dir().join(format!("qs-test-unique-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("report.pdf"), b"").unwrap();
+        std::fs::write(dir.join("report (1).pdf"), b"").unwrap();
+        std::fs::write(dir.join("report (2).pdf.qs-part"), b"").unwrap();
+
+        assert_eq!(
+            unique_path(&dir.join("report.pdf")),
+            dir.join("report (3).pdf")
+        );
+        assert_eq!(unique_path(&dir.join("dir")), dir.join("dir (1)"));
+        assert_eq!(unique_path(&dir.join(".bashrc")), dir.join(".bashrc (1)"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_collision() {
+        let dir = std::env::temp_dir().join(format!("qs-test-collision-{}", std::process::id()));
+        let path = dir.join("photos");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("a.jpg"), b"hello").unwrap();
+
+        let offered = FilesAvailable::Dir {
+            name: "photos".into(),
+            meta: FileMeta::default(),
+            files: vec![
+                FilesAvailable::File {
+                    name: "a.jpg".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+                FilesAvailable::File {
+                    name: "b.jpg".into(),
+                    meta: FileMeta::default(),
+                    size: 10,
+                },
+            ],
+        };
+        let local = get_files_received(&path).unwrap();
+        let resolve = |collision| resolve_collision(&offered, &local, &path, collision, None);
+
+        // Resume and skip only apply to the file that exists
+        let (target, action, skip) = resolve(CollisionPolicy::Resume).unwrap().unwrap();
+        assert_eq!((target, action), (path.clone(), FileAction::Resume));
+        assert_eq!(skip.unwrap().skip(), 5);
+
+        let (target, action, skip) = resolve(CollisionPolicy::Skip).unwrap().unwrap();
+        assert_eq!((target, action), (path.clone(), FileAction::Skip));
+        assert_eq!(skip.unwrap().skip(), 10);
+
+        // The others apply to the whole directory
+        assert_eq!(
+            resolve(CollisionPolicy::Overwrite).unwrap(),
+            Some((path.clone(), FileAction::Overwrite, None))
+        );
+        let renamed = dir.join("photos (1)");
+        assert_eq!(
+            resolve(CollisionPolicy::Rename).unwrap(),
+            Some((renamed.clone(), FileAction::Rename(renamed), None))
+        );
+        assert_eq!(resolve(CollisionPolicy::Fail).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_tree_through_symlink() {
+        let dir = std::env::temp_dir().join(format!("qs-test-through-link-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("out").join("y")).unwrap();
+
+        let tree = FileSendRecvTree::Dir {
+            name: "y".into(),
+            meta: FileMeta::default(),
+            files: vec![FileSendRecvTree::Dir {
+                name: "escaped".into(),
+                meta: FileMeta::default(),
+                files: Vec::new(),
+            }],
+        };
+
+        assert!(create_tree(&tree, &dir.join("out").join("y")).is_err());
+        assert!(!dir.join("escaped").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_streams() {
+        assert_eq!(check_streams(4, 10).unwrap(), 4);
+        assert_eq!(check_streams(0, 0).unwrap(), 0);
+        assert!(matches!(
+            check_streams(5, 3),
+            Err(ReceiveError::TooManyStreams { streams: 5, max: 3 })
+        ));
+        assert!(matches!(
+            check_streams(u32::MAX, usize::MAX),
+            Err(ReceiveError::TooManyStreams {
+                max: MAX_STREAMS,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_required_space() {
+        let dir = std::env::temp_dir().join(format!("qs-test-space-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bin.qs-part"), [0; 40]).unwrap();
+
+        let offered = [
+            FilesAvailable::File {
+                name: "a.bin".into(),
+                meta: FileMeta::default(),
+                size: 100,
+            },
+            FilesAvailable::File {
+                name: "b.bin".into(),
+                meta: FileMeta::default(),
+                size: 50,
+            },
+        ];
+
+        let resume = required_space(&offered, &dir, CollisionPolicy::Resume).unwrap();
+        assert_eq!(resume.len(), 1);
+        assert_eq!(resume[0].path, dir);
+        assert_eq!(resume[0].needed, 110);
+
+        let overwrite = required_space(&offered, &dir, CollisionPolicy::Overwrite).unwrap();
+        assert_eq!(overwrite[0].needed, 150);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_lost_connection() {
+        let dir = std::env::temp_dir().join(format!("qs-test-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..3 * BUF_SIZE as u32 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let size = data.len() as u64;
+        let mut files = vec![
+            TransferFile {
+                path: dir.join("a.bin"),
+                entry: 0,
+                meta: FileMeta::default(),
+                skip: 0,
+                size,
+            },
+            TransferFile {
+                path: dir.join("b.bin"),
+                entry: 0,
+                meta: FileMeta::default(),
+                skip: 0,
+                size: 10,
+            },
+        ];
+        let state = ReceiveProgress::new(&files);
+
+        let mut stream = Vec::new();
+        send_file(
+            &mut stream,
+            &mut Cursor::new(data.clone()),
+            0,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+
+        // The connection is lost in the middle of the third chunk
+        stream.truncate(2 * BUF_SIZE + 10);
+        let mut recorded = Vec::new();
+        assert!(receive_to_path(
+            &mut Cursor::new(stream),
+            &files[0].path,
+            None,
+            0,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |n| {
+                    state.written[0].fetch_add(n, Ordering::Relaxed);
+                },
+                should_continue: || true,
+            },
+            &mut |progress| recorded.push(progress),
+        )
+        .await
+        .is_err());
+
+        // The journal has the same bytes as the reported offset
+        assert_eq!(
+            recorded,
+            vec![FileProgress::Partial {
+                len: 2 * BUF_SIZE as u64,
+                hash: *blake3::hash(&data[..2 * BUF_SIZE]).as_bytes(),
+            }]
+        );
+
+        state.complete[1].store(true, Ordering::Relaxed);
+        assert_eq!(state.offsets(), vec![Some(2 * BUF_SIZE as u64), None]);
+
+        let pending = state.resume(&mut files);
+        assert_eq!(pending, vec![0]);
+        assert_eq!(files[0].skip, 2 * BUF_SIZE as u64);
+        assert_eq!(
+            remaining_bytes(&files, &pending, 1),
+            vec![size - 2 * BUF_SIZE as u64]
+        );
+
+        let mut stream = Vec::new();
+        send_file(
+            &mut stream,
+            &mut Cursor::new(data.clone()),
+            files[0].skip,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+
+        let mut recorded = Vec::new();
+        assert!(receive_to_path(
+            &mut Cursor::new(stream),
+            &files[0].path,
+            None,
+            files[0].skip,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+            &mut |progress| recorded.push(progress),
+        )
+        .await
+        .unwrap());
+        assert_eq!(std::fs::read(&files[0].path).unwrap(), data);
+        assert_eq!(
+            recorded,
+            vec![FileProgress::Complete {
+                hash: *blake3::hash(&data).as_bytes()
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_beside_stale_partial() {
+        let dir = std::env::temp_dir().join(format!("qs-test-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.bin");
+
+        let data: Vec<u8> = (0..2 * BUF_SIZE as u32).map(|i| (i % 251) as u8).collect();
+        let size = data.len() as u64;
+        std::fs::write(&path, &data[..BUF_SIZE + 10]).unwrap();
+        std::fs::write(partial_path(&path), [0xff; 100]).unwrap();
+
+        // The prefix is taken from the final file, so the data must be appended to it
+        let FilesAvailable::File { size: skip, .. } = get_files_received(&path).unwrap() else {
+            panic!("expected a file");
+        };
+        let mut to_skip = FilesToSkip::File {
+            name: "a.bin".into(),
+            skip,
+            prefix_hash: None,
+        };
+        to_skip.hash_prefixes(&path).unwrap();
+        assert_eq!(
+            to_skip,
+            FilesToSkip::File {
+                name: "a.bin".into(),
+                skip: BUF_SIZE as u64 + 10,
+                prefix_hash: Some(*blake3::hash(&data[..BUF_SIZE + 10]).as_bytes()),
+            }
+        );
+
+        let mut stream = Vec::new();
+        send_file(
+            &mut stream,
+            &mut Cursor::new(data.clone()),
+            skip,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(receive_to_path(
+            &mut Cursor::new(stream),
+            &path,
+            None,
+            skip,
+            size,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |_| {},
+                should_continue: || true,
+            },
+            &mut |_| {},
+        )
+        .await
+        .unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!partial_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Runs on the current-thread runtime of `#[tokio::test]`
+    #[tokio::test]
+    async fn test_directory_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("qs-test-dir-{}", std::process::id()));
+        let src = dir.join("src");
+        let dst = dir.join("dst");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::create_dir_all(&dst).unwrap();
+        for i in 0..20 {
+            std::fs::write(src.join(format!("file{}", i)), vec![i as u8; i * 100]).unwrap();
+        }
+        std::fs::write(src.join("sub").join("large"), vec![1; 3 * BUF_SIZE + 5]).unwrap();
+
+        let FileSendRecvTree::Dir { files, .. } = get_files_available(&src, SymlinkPolicy::Follow)
+            .unwrap()
+            .to_send_recv_tree()
+        else {
+            panic!("expected a directory");
+        };
+
+        let mut stream = Vec::new();
+        let mut sent = 0;
+        assert!(send_directory(
+            &mut stream,
+            &src,
+            &files,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |n| sent += n,
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap());
+
+        let mut received = 0;
+        assert!(receive_directory(
+            &mut Cursor::new(stream),
+            &dst,
+            &files,
+            true,
+            &mut TransferContext {
+                rate_limit: RateLimit::default(),
+                pause: Pause::default(),
+                progress: |n| received += n,
+                should_continue: || true,
+            },
+        )
+        .await
+        .unwrap());
+
+        assert_eq!(sent, received);
+        for i in 0..20 {
+            let name = format!("file{}", i);
+            assert_eq!(
+                std::fs::read(dst.join(&name)).unwrap(),
+                std::fs::read(src.join(&name)).unwrap()
+            );
+        }
+        assert_eq!(
+            std::fs::read(dst.join("sub").join("large")).unwrap(),
+            vec![1; 3 * BUF_SIZE + 5]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_mid_transfer() {
+        let dir = std::env::temp_dir().join(format!("qs-test-cancel-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(dir.join("file.bin"), vec![7; 1024 * 1024]).unwrap();
+
+        let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+
+        let sender_args = SenderArgs {
+            rate_limit: RateLimit::new(Some(256 * 1024)),
+            ..test_utils::sender_args(vec![dir.join("file.bin")])
+        };
+        let receiver_args = test_utils::receiver_args();
+
+        let send = async {
+            let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+            sender
+                .send_files(
+                    || {},
+                    |_| {},
+                    |_| {},
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let receive = async {
+            let mut receiver =
+                Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                    .await
+                    .unwrap();
+
+            // Stop once the transfer is running
+            let received = AtomicU64::new(0);
+            receiver
+                .receive_files(
+                    |_| {},
+                    |_, _| {
+                        Some(AcceptFiles {
+                            output_path: dir.join("out"),
+                            selection: None,
+                        })
+                    },
+                    &mut |_, n| {
+                        received.fetch_add(n, Ordering::Relaxed);
+                    },
+                    &mut || received.load(Ordering::Relaxed) < 64 * 1024,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let (sent, received) = tokio::join!(send, receive);
+
+        assert!(!received.unwrap());
+        match sent {
+            Err(SendError::PeerCancelled { reason }) => assert_eq!(reason, CANCEL_REASON),
+            result => panic!("expected the receiver to cancel, got {:?}", result),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sender_error_before_transfer() {
+        let dir = std::env::temp_dir().join(format!("qs-test-sender-error-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(dir.join("file.bin"), vec![3; 10_000]).unwrap();
+        std::fs::write(dir.join("out").join("file.bin"), vec![3; 5_000]).unwrap();
+
+        let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+        let sender_args = test_utils::sender_args(vec![dir.join("file.bin")]);
+        let receiver_args = ReceiverArgs {
+            collision: CollisionPolicy::Resume,
+            ..test_utils::receiver_args()
+        };
+
+        let send = async {
+            let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+            sender
+                .send_files(
+                    || {},
+                    |_| {},
+                    |_| {},
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let receive = async {
+            let mut receiver =
+                Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                    .await
+                    .unwrap();
+            receiver
+                .receive_files(
+                    |_| {},
+                    |_, _| {
+                        // The sender can't verify the resumed prefix anymore
+                        std::fs::remove_file(dir.join("file.bin")).unwrap();
+                        Some(AcceptFiles {
+                            output_path: dir.join("out"),
+                            selection: None,
+                        })
+                    },
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let (sent, received) = tokio::join!(send, receive);
+
+        assert!(matches!(sent, Err(SendError::Io(_))));
+        match received {
+            Err(ReceiveError::PeerError { kind, .. }) => assert_eq!(kind, ErrorKind::Io),
+            result => panic!("expected the error of the sender, got {:?}", result),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_applies_metadata() {
+        let dir = std::env::temp_dir().join(format!("qs-test-resume-meta-{}", std::process::id()));
+        let meta = FileMeta {
+            mtime: Some(Duration::from_secs(1_000_000_000)),
+            mode: Some(0o750),
+        };
+        for root in [dir.join("src"), dir.join("out")] {
+            std::fs::create_dir_all(root.join("root").join("sub")).unwrap();
+            std::fs::write(root.join("root").join("a"), vec![1; 1000]).unwrap();
+            std::fs::write(root.join("root").join("sub").join("b"), vec![2; 1000]).unwrap();
+        }
+        let src = dir.join("src").join("root");
+        for path in [
+            src.join("a"),
+            src.join("sub").join("b"),
+            src.join("sub"),
+            src.clone(),
+        ] {
+            meta.apply(&path).unwrap();
+        }
+
+        let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+
+        let sender_args = test_utils::sender_args(vec![src.clone()]);
+        let receiver_args = ReceiverArgs {
+            collision: CollisionPolicy::Resume,
+            preserve_metadata: true,
+            ..test_utils::receiver_args()
+        };
+
+        let send = async {
+            let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+            sender
+                .send_files(
+                    || {},
+                    |_| {},
+                    |_| {},
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let receive = async {
+            let mut receiver =
+                Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                    .await
+                    .unwrap();
+            receiver
+                .receive_files(
+                    |_| {},
+                    |_, _| {
+                        Some(AcceptFiles {
+                            output_path: dir.join("out"),
+                            selection: None,
+                        })
+                    },
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let (sent, received) = tokio::join!(send, receive);
+        assert!(sent.unwrap());
+        assert!(received.unwrap());
+
+        // Everything was skipped, but it gets the metadata of a fresh transfer
+        let out = dir.join("out").join("root");
+        for path in [
+            out.join("a"),
+            out.join("sub").join("b"),
+            out.join("sub"),
+            out,
+        ] {
+            let local = std::fs::metadata(&path).unwrap();
+            assert_eq!(FileMeta::from_metadata(&local), meta);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_journal_names() {
+        let dir = std::env::temp_dir().join(format!("qs-test-journal-name-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+
+        // Saving the journal would replace a received file with these names
+        for name in [JOURNAL_FILE_NAME, JOURNAL_TEMP_NAME] {
+            std::fs::write(dir.join(name), b"data").unwrap();
+
+            let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+            let sender_args = test_utils::sender_args(vec![dir.join(name)]);
+            let receiver_args = ReceiverArgs {
+                journal: true,
+                ..test_utils::receiver_args()
+            };
+
+            let send = async {
+                let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+                sender
+                    .send_files(
+                        || {},
+                        |_| {},
+                        |_| {},
+                        &mut |_, _| {},
+                        &mut || true,
+                        &mut |_| {},
+                    )
+                    .await
+            };
+            let receive = async {
+                let mut receiver =
+                    Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                        .await
+                        .unwrap();
+                receiver
+                    .receive_files(
+                        |_| {},
+                        |_, _| {
+                            Some(AcceptFiles {
+                                output_path: dir.join("out"),
+                                selection: None,
+                            })
+                        },
+                        &mut |_, _| {},
+                        &mut || true,
+                        &mut |_| {},
+                    )
+                    .await
+            };
+            let (sent, received) = tokio::join!(send, receive);
+
+            assert!(matches!(sent, Err(SendError::FilesRejected)));
+            match received {
+                Err(ReceiveError::InvalidFileName(rejected)) => assert_eq!(rejected, name),
+                result => panic!("expected {:?} to be rejected, got {:?}", name, result),
+            }
+            assert!(!dir.join("out").join(name).exists());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_share_duplicate_names() {
+        let dir = std::env::temp_dir().join(format!("qs-test-share-dup-{}", std::process::id()));
+        for sub in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+            std::fs::write(dir.join(sub).join("x"), sub).unwrap();
+        }
+
+        let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+
+        let sender_args = test_utils::sender_args(Vec::new());
+        let receiver_args = test_utils::receiver_args();
+
+        let send = async {
+            let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+            sender
+                .share(
+                    &dir,
+                    |_| {},
+                    |_| {},
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await
+        };
+        let receive = async {
+            let mut receiver =
+                Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                    .await
+                    .unwrap();
+
+            // Both would be received as `x`
+            let result = receiver
+                .receive_shared(
+                    vec!["a/x".to_string(), "b/x".to_string()],
+                    |_| {},
+                    |_, _| None,
+                    &mut |_, _| {},
+                    &mut || true,
+                    &mut |_| {},
+                )
+                .await;
+            receiver.close().await;
+            result
+        };
+        let (sent, received) = tokio::join!(send, receive);
+
+        assert!(!sent.unwrap());
+        match received {
+            Err(ReceiveError::PeerError { kind, .. }) => assert_eq!(kind, ErrorKind::InvalidPath),
+            result => panic!("expected the sender to reject the paths, got {:?}", result),
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_text() {
+        // Text is only accepted with a callback
+        for accept in [true, false] {
+            let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+
+            let sender_args = test_utils::sender_args(Vec::new());
+            let receiver_args = test_utils::receiver_args();
+
+            let send = async {
+                let mut sender = Sender::connect(sender_endpoint, sender_args).await.unwrap();
+                sender.send_text("hello".to_string(), || {}, |_| {}).await
+            };
+            let text = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+            let receive = async {
+                let mut receiver =
+                    Receiver::connect(test_utils::local_endpoint().await, node_addr, receiver_args)
+                        .await
+                        .unwrap();
+
+                if accept {
+                    let text = text.clone();
+                    receiver.on_text(move |content| {
+                        *text.lock().unwrap() = content.to_string();
+                        true
+                    });
+                }
+
+                receiver
+                    .receive_files(
+                        |_| {},
+                        |_, _| None,
+                        &mut |_, _| {},
+                        &mut || true,
+                        &mut |_| {},
+                    )
+                    .await
+            };
+            let (sent, received) = tokio::join!(send, receive);
+
+            if accept {
+                assert!(sent.unwrap());
+                assert!(received.unwrap());
+                assert_eq!(*text.lock().unwrap(), "hello");
+            } else {
+                assert!(matches!(sent, Err(SendError::FilesRejected)));
+                assert!(matches!(received, Err(ReceiveError::FilesRejected)));
+            }
+        }
+    }
 }
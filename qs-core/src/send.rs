@@ -1,17 +1,39 @@
 #![allow(clippy::suspicious_open_options)]
 
 use crate::{
-    common::{get_files_available, receive_packet, send_packet, FileSendRecvTree, PacketRecvError},
-    packets::{ReceiverToSender, SenderToReceiver},
-    BUF_SIZE, QS_PROTO_VERSION,
+    common::{
+        connection_lost, exchange_control, find_duplicate_name, get_files_available, hash_prefix,
+        peer_stop, remaining_bytes, send_stop, CloseCode, ConnectionState, ControlChannel,
+        ControlPacket, FileName, FileSendRecvTree, FilesAvailable, FilesToSkip, InvalidSkipList,
+        PacketRecvError, PeerStop, Session, SymlinkPolicy, TransferContext, TransferFile,
+        CANCEL_REASON,
+    },
+    compression::{self, Compression},
+    packets::{ErrorKind, ReceiverToSender, SenderToReceiver},
+    pause::Pause,
+    rate_limit::RateLimit,
+    share::{list_shared_dir, resolve_shared_path},
+    version::{is_compatible, legacy_conn_request, Capabilities, Hello},
+    BUF_SIZE, MAX_STREAMS, QS_LEGACY_ALPN, QS_PROTO_VERSION,
+};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
 };
-use async_compression::tokio::write::GzipEncoder;
-use std::path::PathBuf;
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 
 /// Generic send function
 ///
+/// The file data is followed by the BLAKE3 checksum of the whole file
+/// (including the skipped part), so the receiver can verify it.
+/// The data is sent no faster than the rate limit of `ctx` allows.
+///
 /// # Returns
 /// * `Ok(true)` if the transfer should continue
 /// * `Ok(false)` if the transfer should stop
@@ -20,20 +42,20 @@ pub async fn send_file<S, R>(
     file: &mut R,
     skip: u64,
     size: u64,
-    write_callback: &mut impl FnMut(u64),
-    should_continue: &mut impl FnMut() -> bool,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
 ) -> std::io::Result<bool>
 where
     S: tokio::io::AsyncWriteExt + Unpin,
     R: tokio::io::AsyncReadExt + tokio::io::AsyncSeekExt + Unpin,
 {
-    file.seek(tokio::io::SeekFrom::Start(skip)).await?;
+    let mut hasher = blake3::Hasher::new();
+    hash_prefix(file, skip, &mut hasher).await?;
 
     let mut buf = vec![0; BUF_SIZE];
     let mut read = skip;
 
     while read < size {
-        if !should_continue() {
+        if !(ctx.should_continue)() || !ctx.pause.wait(&mut ctx.should_continue).await {
             return Ok(false);
         }
 
@@ -47,70 +69,216 @@ where
             ));
         }
 
+        ctx.rate_limit.acquire(n as u64).await;
+
+        hasher.update(&buf[..n]);
         send.write_all(&buf[..n]).await?;
         read += n as u64;
 
-        write_callback(n as u64);
+        (ctx.progress)(n as u64);
     }
 
+    send.write_all(hasher.finalize().as_bytes()).await?;
+
     Ok(true)
 }
 
+/// Send the files of a directory one after another,
+/// in the order of [FileSendRecvTree::flatten]
+///
 /// # Returns
 /// * `Ok(true)` if the transfer should continue
 /// * `Ok(false)` if the transfer should stop
-pub fn send_directory<S>(
+pub async fn send_directory<S>(
     send: &mut S,
     root_path: &std::path::Path,
     files: &[FileSendRecvTree],
-    write_callback: &mut impl FnMut(u64),
-    should_continue: &mut impl FnMut() -> bool,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
 ) -> std::io::Result<bool>
 where
-    S: tokio::io::AsyncWriteExt + Unpin + Send,
+    S: tokio::io::AsyncWriteExt + Unpin,
 {
+    let mut to_send = Vec::new();
     for file in files {
-        match file {
-            FileSendRecvTree::File { name, skip, size } => {
-                let path = root_path.join(name);
-
-                let continues = tokio::task::block_in_place(|| {
-                    let rt = tokio::runtime::Runtime::new().unwrap();
-                    rt.block_on(async {
-                        let mut file = tokio::fs::OpenOptions::new().read(true).open(&path).await?;
-
-                        if !send_file(
-                            send,
-                            &mut file,
-                            *skip,
-                            *size,
-                            write_callback,
-                            should_continue,
-                        )
-                        .await?
-                        {
-                            return Ok::<bool, std::io::Error>(false);
-                        }
+        file.flatten(&root_path.join(file.name()), 0, &mut to_send);
+    }
 
-                        file.shutdown().await?;
-                        Ok::<bool, std::io::Error>(true)
-                    })
-                })?;
+    for file in to_send {
+        let mut reader = tokio::fs::File::open(&file.path).await?;
 
-                if !continues {
-                    return Ok(false);
-                }
+        if !send_file(send, &mut reader, file.skip, file.size, ctx).await? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Generic send function for data of unknown length, e.g. from a pipe
+///
+/// The data is sent in chunks that are prefixed with their length,
+/// an empty chunk marks the end of the data.
+/// Like with [send_file] the BLAKE3 checksum of the data follows.
+///
+/// # Returns
+/// * `Ok(true)` if the transfer should continue
+/// * `Ok(false)` if the transfer should stop
+pub async fn send_chunked<S, R>(
+    send: &mut S,
+    reader: &mut R,
+    ctx: &mut TransferContext<impl FnMut(u64), impl FnMut() -> bool>,
+) -> std::io::Result<bool>
+where
+    S: tokio::io::AsyncWriteExt + Unpin,
+    R: tokio::io::AsyncReadExt + Unpin,
+{
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; BUF_SIZE];
+
+    loop {
+        if !(ctx.should_continue)() || !ctx.pause.wait(&mut ctx.should_continue).await {
+            return Ok(false);
+        }
+
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        ctx.rate_limit.acquire(n as u64).await;
+
+        hasher.update(&buf[..n]);
+        send.write_u32(n as u32).await?;
+        send.write_all(&buf[..n]).await?;
+
+        (ctx.progress)(n as u64);
+    }
+
+    send.write_u32(0).await?;
+    send.write_all(hasher.finalize().as_bytes()).await?;
+
+    Ok(true)
+}
+
+/// Progress of a transfer, shared by its streams
+struct SendProgress {
+    /// The files that are left to send, as indices into the transfer files
+    pending: Vec<usize>,
+    /// Position in `pending` of the next file that is not taken yet
+    next: AtomicUsize,
+    /// Bytes of each file written to the connection, including the skipped part
+    sent: Vec<AtomicU64>,
+    interrupted: AtomicBool,
+}
+
+impl SendProgress {
+    fn new(files: &[TransferFile]) -> Self {
+        Self {
+            pending: (0..files.len()).collect(),
+            next: AtomicUsize::new(0),
+            sent: files.iter().map(|f| AtomicU64::new(f.skip)).collect(),
+            interrupted: AtomicBool::new(false),
+        }
+    }
+
+    /// Continue with the `offsets` the receiver reported after reconnecting,
+    /// the files are sent again from there (`None` if the file is complete)
+    fn resume(
+        &mut self,
+        files: &mut [TransferFile],
+        offsets: &[Option<u64>],
+    ) -> Result<(), SendError> {
+        if offsets.len() != files.len() {
+            return Err(InvalidSkipList::Length {
+                expected: files.len(),
+                got: offsets.len(),
             }
-            FileSendRecvTree::Dir { name, files } => {
-                let root_path = root_path.join(name);
-                if !send_directory(send, &root_path, files, write_callback, should_continue)? {
-                    return Ok(false);
-                };
+            .into());
+        }
+
+        self.pending.clear();
+        for (id, offset) in offsets.iter().enumerate() {
+            let Some(offset) = *offset else {
+                continue;
+            };
+
+            // The receiver can not have more than we sent
+            let sent = self.sent[id].load(Ordering::Relaxed);
+            if offset > sent {
+                return Err(SendError::InvalidResumeOffset {
+                    path: files[id].path.clone(),
+                    offset,
+                    sent,
+                });
             }
+
+            files[id].skip = offset;
+            self.sent[id].store(offset, Ordering::Relaxed);
+            self.pending.push(id);
         }
+
+        self.next.store(0, Ordering::Relaxed);
+
+        Ok(())
     }
+}
 
-    Ok(true)
+/// Send the next files of `progress` that are not taken yet on a new stream,
+/// every file is prefixed with its index and the compression it is sent with.
+/// The callbacks of `ctx` are shared by all streams
+async fn send_stream(
+    conn: &iroh::endpoint::Connection,
+    compression: Compression,
+    files: &[TransferFile],
+    progress: &SendProgress,
+    ctx: &TransferContext<Mutex<impl FnMut(usize, u64)>, Mutex<impl FnMut() -> bool>>,
+) -> Result<(), SendError> {
+    let mut send = conn.open_uni().await?;
+    let interrupted = &progress.interrupted;
+
+    while !interrupted.load(Ordering::Relaxed) {
+        let next = progress.next.fetch_add(1, Ordering::Relaxed);
+        let Some(&id) = progress.pending.get(next) else {
+            break;
+        };
+        let file = &files[id];
+
+        send.write_u32(id as u32).await?;
+
+        let algorithm = compression.for_file(&file.path).await?;
+        compression::write_tag(&mut send, algorithm).await?;
+        let mut encoder = compression.encoder(&mut send, algorithm);
+
+        let mut reader = tokio::fs::File::open(&file.path).await?;
+        let mut file_ctx = TransferContext {
+            rate_limit: ctx.rate_limit.clone(),
+            pause: ctx.pause.clone(),
+            progress: |n| {
+                progress.sent[id].fetch_add(n, Ordering::Relaxed);
+                (ctx.progress.lock().unwrap())(file.entry, n)
+            },
+            should_continue: || {
+                !interrupted.load(Ordering::Relaxed) && (ctx.should_continue.lock().unwrap())()
+            },
+        };
+        let continues = send_file(
+            &mut encoder,
+            &mut reader,
+            file.skip,
+            file.size,
+            &mut file_ctx,
+        )
+        .await?;
+        encoder.shutdown().await?;
+
+        if !continues {
+            interrupted.store(true, Ordering::Relaxed);
+        }
+    }
+
+    send.shutdown().await?;
+
+    Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -125,8 +293,16 @@ pub enum SendError {
     Connection(#[from] iroh::endpoint::ConnectionError),
     #[error("read error: {0}")]
     Read(#[from] quinn::ReadError),
-    #[error("wrong version, the receiver expected: {0}, but got: {1}")]
-    WrongVersion(String, String),
+    #[error(
+        "incompatible versions, the receiver has {remote} ({remote_capabilities}), \
+         but this is {local} ({local_capabilities})"
+    )]
+    WrongVersion {
+        local: String,
+        local_capabilities: Capabilities,
+        remote: String,
+        remote_capabilities: Capabilities,
+    },
     #[error(
         "wrong roundezvous protocol version, the roundezvous server expected {0}, but got: {1}"
     )]
@@ -139,6 +315,65 @@ pub enum SendError {
     ReceivePacket(#[from] PacketRecvError),
     #[error("failed to fetch node addr: {0}")]
     NodeAddr(String),
+    #[error("invalid skip list: {0}")]
+    InvalidSkipList(#[from] InvalidSkipList),
+    #[error("the receiver resumed {path} at {offset} bytes, but only {sent} bytes were sent")]
+    InvalidResumeOffset {
+        path: PathBuf,
+        offset: u64,
+        sent: u64,
+    },
+    #[error("the receiver cancelled the transfer ({reason})")]
+    PeerCancelled { reason: String },
+    #[error("the receiver stopped because of an error ({kind}): {message}")]
+    PeerError { kind: ErrorKind, message: String },
+    #[error("the receiver does not support shared directories")]
+    ShareNotSupported,
+    #[error("the receiver does not support streams")]
+    StreamNotSupported,
+    #[error("the receiver does not support text")]
+    TextNotSupported,
+    #[error("several of the files would be received as {0:?}")]
+    DuplicateFileName(String),
+}
+
+impl SendError {
+    /// The kind of the error, as it is sent to the receiver
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SendError::FileDoesNotExists(_) | SendError::Io(_) => ErrorKind::Io,
+            SendError::WrongVersion { .. }
+            | SendError::UnexpectedDataPacket(_)
+            | SendError::ReceivePacket(_)
+            | SendError::InvalidSkipList(_)
+            | SendError::InvalidResumeOffset { .. } => ErrorKind::Protocol,
+            SendError::PeerError { kind, .. } => *kind,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<PeerStop> for SendError {
+    fn from(stop: PeerStop) -> Self {
+        match stop {
+            PeerStop::Cancelled { reason } => SendError::PeerCancelled { reason },
+            PeerStop::Failed { kind, message } => SendError::PeerError { kind, message },
+        }
+    }
+}
+
+/// Read a packet the receiver sends while the file data is transferred
+fn control_packet(packet: ReceiverToSender) -> Option<ControlPacket> {
+    match packet {
+        ReceiverToSender::Paused { paused } => Some(ControlPacket::Paused(paused)),
+        ReceiverToSender::Cancel { reason } => {
+            Some(ControlPacket::Stop(PeerStop::Cancelled { reason }))
+        }
+        ReceiverToSender::Error { kind, message } => {
+            Some(ControlPacket::Stop(PeerStop::Failed { kind, message }))
+        }
+        _ => None,
+    }
 }
 
 /// A client that can send files
@@ -151,10 +386,30 @@ pub struct Sender {
     endpoint: iroh::Endpoint,
 }
 
+/// Default number of streams the files are sent on
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// How long a reconnecting peer has to send its resume request
+const RESUME_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Arguments for the sender
+#[derive(Clone)]
 pub struct SenderArgs {
     /// Files/Directories to send
     pub files: Vec<PathBuf>,
+    /// How symlinks inside of the directories are handled
+    pub symlinks: SymlinkPolicy,
+    /// Number of streams the files are sent on concurrently
+    pub concurrency: usize,
+    /// Compression of the packets and the file data
+    pub compression: Compression,
+    /// Limit of the upload speed, keep a clone to change it during the transfer
+    pub rate_limit: RateLimit,
+    /// Keep a clone to pause and resume the transfer
+    pub pause: Pause,
+    /// How long to wait for the receiver to reconnect after the connection was lost,
+    /// zero to fail right away
+    pub reconnect_timeout: Duration,
 }
 
 impl Sender {
@@ -168,19 +423,28 @@ impl Sender {
 
             tracing::info!("receiver connected to sender");
 
-            return Ok(Self {
-                args,
-                conn,
-                endpoint: this_endpoint,
-            });
+            return Ok(Self::from_connection(this_endpoint, conn, args));
         }
 
         unreachable!();
     }
 
+    /// Send to a receiver that already connected to `endpoint`
+    pub(crate) fn from_connection(
+        endpoint: iroh::Endpoint,
+        conn: iroh::endpoint::Connection,
+        args: SenderArgs,
+    ) -> Self {
+        Self {
+            args,
+            conn,
+            endpoint,
+        }
+    }
+
     /// Close the connection
     pub async fn close(&mut self) {
-        self.conn.close(0u32.into(), &[0]);
+        CloseCode::Normal.close(&self.conn, "");
         self.endpoint.close().await;
     }
 
@@ -195,40 +459,460 @@ impl Sender {
         self.endpoint.conn_type(node_id).ok()?.get().ok()
     }
 
+    /// Wait for the receiver `peer` to reconnect and resume the session `session_id`,
+    /// other connections are closed
+    ///
+    /// The new connection has to agree on the same `capabilities` as the session.
+    ///
+    /// # Returns
+    /// * `Some((conn, control, offsets))` with the offsets the receiver resumes from
+    /// * `None` if the receiver did not come back within the reconnect timeout
+    async fn accept_resume(
+        &self,
+        session_id: u64,
+        peer: iroh::NodeId,
+        capabilities: Capabilities,
+    ) -> Option<(iroh::endpoint::Connection, ControlChannel, Vec<Option<u64>>)> {
+        let deadline = tokio::time::Instant::now() + self.args.reconnect_timeout;
+
+        loop {
+            let incoming = tokio::time::timeout_at(deadline, self.endpoint.accept())
+                .await
+                .ok()??;
+
+            let handshake = async {
+                let conn = incoming.accept()?.await?;
+                if conn.remote_node_id().ok() != Some(peer) {
+                    return Ok((conn, None));
+                }
+
+                // The versions are checked again, the receiver may have been restarted
+                let mut control = ControlChannel::accept(&conn).await?;
+                let hello = control.receive_hello().await?;
+                let ours = Hello::new(self.offered_capabilities());
+                control.send_hello(&ours).await?;
+                let packet = control.receive::<ReceiverToSender>().await?;
+
+                let compatible = is_compatible(&ours, &hello)
+                    && hello.capabilities.intersection(ours.capabilities) == capabilities;
+                Ok::<_, SendError>((conn, compatible.then_some((control, packet))))
+            };
+
+            match tokio::time::timeout(RESUME_HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok((
+                    conn,
+                    Some((
+                        control,
+                        ReceiverToSender::Resume {
+                            session_id: id,
+                            offsets,
+                        },
+                    )),
+                ))) if id == session_id => {
+                    return Some((conn, control, offsets));
+                }
+                Ok(Ok((conn, _))) => {
+                    tracing::warn!("rejected a connection that does not resume this session");
+                    CloseCode::UnknownSession.close(&conn, "unknown session");
+                }
+                Ok(Err(e)) => tracing::warn!("failed to accept the reconnect: {}", e),
+                Err(_) => tracing::warn!("the reconnect handshake timed out"),
+            }
+        }
+    }
+
     /// Send files
     /// # Arguments
     /// * `wait_for_other_peer_to_accept_files_callback` - Callback to wait for the other peer to accept the files
     /// * `files_decision_callback` - Callback with the decision of the other peer to accept the files
     /// * `initial_progress_callback` - Callback with the initial progress of each file to send (name, current, total)
-    /// * `write_callback` - Callback every time data is written to the connection
+    /// * `write_callback` - Callback every time data is written to the connection (index of the file/dir, bytes)
     /// * `should_continue` - Callback to check if the transfer should continue
+    /// * `connection_callback` - Callback when the connection is lost and resumed,
+    ///   and when one of the peers pauses or resumes the transfer
     ///
     /// # Returns
     /// * `Ok(true)` if the transfer was finished successfully
     /// * `Ok(false)` if the transfer was stopped
     pub async fn send_files(
         &mut self,
+        wait_for_other_peer_to_accept_files_callback: impl FnMut(),
+        files_decision_callback: impl FnMut(bool),
+        initial_progress_callback: impl FnMut(&[(String, u64, u64)]),
+        write_callback: &mut impl FnMut(usize, u64),
+        should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, SendError> {
+        let session = self.handshake().await?;
+        let ctx = self.transfer_context(write_callback, should_continue);
+
+        self.send_offer(
+            session,
+            wait_for_other_peer_to_accept_files_callback,
+            files_decision_callback,
+            initial_progress_callback,
+            ctx,
+            connection_callback,
+        )
+        .await
+    }
+
+    /// Share the directory `root`, the receiver lists it and requests the paths it wants.
+    /// These are sent like with [Sender::send_files], which the callbacks are the same as.
+    /// The receiver picked the paths itself, so there is no callback to wait for its decision
+    ///
+    /// Symlinks are skipped instead of followed, so nothing outside of the directory is sent.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the requested files were sent
+    /// * `Ok(false)` if the transfer was stopped, or the receiver only listed the directory
+    pub async fn share(
+        &mut self,
+        root: &Path,
+        files_decision_callback: impl FnMut(bool),
+        initial_progress_callback: impl FnMut(&[(String, u64, u64)]),
+        write_callback: &mut impl FnMut(usize, u64),
+        should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, SendError> {
+        let mut session = self.handshake().await?;
+
+        if !session.capabilities.contains(Capabilities::SHARE) {
+            CloseCode::Normal.close(&self.conn, "");
+            return Err(SendError::ShareNotSupported);
+        }
+
+        session.control.send(SenderToReceiver::Share).await?;
+
+        let invalid_path = |path: &str| SenderToReceiver::Error {
+            kind: ErrorKind::InvalidPath,
+            message: format!("{:?} does not exist in the shared directory", path),
+        };
+
+        loop {
+            let packet = match session.control.receive::<ReceiverToSender>().await {
+                Ok(packet) => packet,
+                // The receiver closes the connection once it listed what it wanted
+                Err(_) if self.conn.close_reason().is_some() && !connection_lost(&self.conn) => {
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match packet {
+                ReceiverToSender::ListDir { path } => {
+                    let reply = match resolve_shared_path(root, &path) {
+                        Some(dir) => match list_shared_dir(&dir) {
+                            Ok(entries) => SenderToReceiver::DirListing { entries },
+                            Err(e) => SenderToReceiver::Error {
+                                kind: ErrorKind::Io,
+                                message: format!("failed to list {:?}: {}", path, e),
+                            },
+                        },
+                        None => invalid_path(&path),
+                    };
+                    session.control.send(reply).await?;
+                }
+                ReceiverToSender::RequestFiles { paths } => {
+                    let files: Result<Vec<PathBuf>, &String> = paths
+                        .iter()
+                        .map(|p| resolve_shared_path(root, p).ok_or(p))
+                        .collect();
+
+                    match files {
+                        Ok(files) if !files.is_empty() => {
+                            // Each path is offered under its last component
+                            let mut names = HashSet::new();
+                            match paths
+                                .iter()
+                                .zip(&files)
+                                .find(|(_, file)| !names.insert(file.file_name()))
+                            {
+                                Some((path, _)) => {
+                                    let reply = SenderToReceiver::Error {
+                                        kind: ErrorKind::InvalidPath,
+                                        message: format!(
+                                            "{:?} has the same name as another requested path",
+                                            path
+                                        ),
+                                    };
+                                    session.control.send(reply).await?
+                                }
+                                None => {
+                                    self.args.files = files;
+                                    break;
+                                }
+                            }
+                        }
+                        Ok(_) => session.control.send(invalid_path("")).await?,
+                        Err(path) => session.control.send(invalid_path(path)).await?,
+                    }
+                }
+                p => return Err(SendError::UnexpectedDataPacket(p)),
+            }
+        }
+
+        if self.args.symlinks == SymlinkPolicy::Follow {
+            self.args.symlinks = SymlinkPolicy::Skip;
+        }
+
+        let ctx = self.transfer_context(write_callback, should_continue);
+        self.send_offer(
+            session,
+            || {},
+            files_decision_callback,
+            initial_progress_callback,
+            ctx,
+            connection_callback,
+        )
+        .await
+    }
+
+    /// Stream the data of `reader` as `name`, its length does not have to be known (e.g. stdin)
+    /// # Arguments
+    /// * `wait_for_other_peer_to_accept_files_callback` - Callback to wait for the other peer to accept the stream,
+    ///   the data follows once it is accepted
+    /// * `write_callback` - Callback every time data is written to the connection (bytes)
+    /// * `should_continue` - Callback to check if the transfer should continue
+    /// * `connection_callback` - Callback when one of the peers pauses or resumes the transfer
+    ///
+    /// The data can not be read again, so a stream is not resumed when the connection is lost.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the stream was sent completely
+    /// * `Ok(false)` if the transfer was stopped
+    /// * `Err(SendError::FilesRejected)` if the receiver rejected the stream
+    pub async fn send_reader<R>(
+        &mut self,
+        name: FileName,
+        reader: &mut R,
         mut wait_for_other_peer_to_accept_files_callback: impl FnMut(),
-        mut files_decision_callback: impl FnMut(bool),
-        mut initial_progress_callback: impl FnMut(&[(String, u64, u64)]),
         write_callback: &mut impl FnMut(u64),
         should_continue: &mut impl FnMut() -> bool,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, SendError>
+    where
+        R: tokio::io::AsyncReadExt + Unpin,
+    {
+        let Session {
+            mut control,
+            capabilities,
+            ..
+        } = self.handshake().await?;
+
+        if !capabilities.contains(Capabilities::STREAM) {
+            CloseCode::Normal.close(&self.conn, "");
+            return Err(SendError::StreamNotSupported);
+        }
+
+        control.send(SenderToReceiver::Stream { name }).await?;
+
+        wait_for_other_peer_to_accept_files_callback();
+
+        match control.receive::<ReceiverToSender>().await? {
+            ReceiverToSender::AcceptStream => {}
+            ReceiverToSender::RejectFiles => {
+                CloseCode::Normal.close(&self.conn, "");
+                return Err(SendError::FilesRejected);
+            }
+            p => return Err(SendError::UnexpectedDataPacket(p)),
+        }
+
+        let compression = control.compression;
+        let cancellable = capabilities.contains(Capabilities::CANCEL);
+
+        let transfer = async {
+            let mut send = self.conn.open_uni().await?;
+
+            compression::write_tag(&mut send, compression.algorithm).await?;
+            let mut encoder = compression.encoder(&mut send, compression.algorithm);
+            let mut ctx = self.transfer_context(write_callback, should_continue);
+            let continues = send_chunked(&mut encoder, reader, &mut ctx).await?;
+            encoder.shutdown().await?;
+            drop(encoder);
+
+            send.shutdown().await?;
+            Ok::<_, SendError>(continues)
+        };
+        let result = tokio::select! {
+            result = transfer => result,
+            stop = exchange_control(
+                &mut control,
+                &self.args.pause,
+                capabilities,
+                |paused| SenderToReceiver::Paused { paused },
+                control_packet,
+                connection_callback,
+            ) => Err(stop.into()),
+        };
+
+        let continues = match result {
+            Ok(continues) => continues,
+            Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+        };
+
+        if continues {
+            // The receiver closes the connection once it verified the data
+            self.wait_for_close().await;
+            if let Some(stop) = PeerStop::from_close(&self.conn) {
+                return Err(stop.into());
+            }
+        } else {
+            tracing::info!("the transfer was interrupted");
+            send_stop(
+                &self.conn,
+                &mut control,
+                cancellable,
+                SenderToReceiver::Cancel {
+                    reason: CANCEL_REASON.to_string(),
+                },
+                CloseCode::Cancelled,
+                CANCEL_REASON,
+            )
+            .await;
+        }
+
+        Ok(continues)
+    }
+
+    /// Send the text `content` instead of files
+    /// # Arguments
+    /// * `wait_for_other_peer_to_accept_files_callback` - Callback to wait for the other peer to accept the text
+    /// * `files_decision_callback` - Callback with the decision of the other peer to accept the text
+    ///
+    /// The text is part of the offer, so the receiver can show it before accepting it.
+    ///
+    /// # Returns
+    /// * `Ok(true)` if the receiver accepted the text
+    pub async fn send_text(
+        &mut self,
+        content: String,
+        mut wait_for_other_peer_to_accept_files_callback: impl FnMut(),
+        mut files_decision_callback: impl FnMut(bool),
     ) -> Result<bool, SendError> {
-        send_packet(
-            SenderToReceiver::ConnRequest {
-                version_num: QS_PROTO_VERSION.to_string(),
-            },
-            &self.conn,
-        )
-        .await?;
+        let Session {
+            mut control,
+            capabilities,
+            ..
+        } = self.handshake().await?;
+
+        if !capabilities.contains(Capabilities::TEXT) {
+            CloseCode::Normal.close(&self.conn, "");
+            return Err(SendError::TextNotSupported);
+        }
+
+        control.send(SenderToReceiver::Text { content }).await?;
 
-        match receive_packet::<ReceiverToSender>(&self.conn).await? {
-            ReceiverToSender::Ok => (),
-            ReceiverToSender::WrongVersion { expected } => {
-                return Err(SendError::WrongVersion(expected, QS_PROTO_VERSION.to_string()));
+        wait_for_other_peer_to_accept_files_callback();
+
+        match control.receive::<ReceiverToSender>().await? {
+            ReceiverToSender::AcceptText => {
+                files_decision_callback(true);
+                CloseCode::Normal.close(&self.conn, "");
+                Ok(true)
             }
+            ReceiverToSender::RejectFiles => {
+                files_decision_callback(false);
+                CloseCode::Normal.close(&self.conn, "");
+                Err(SendError::FilesRejected)
+            }
+            p => Err(SendError::UnexpectedDataPacket(p)),
+        }
+    }
+
+    /// Agree on the protocol with the receiver
+    async fn handshake(&mut self) -> Result<Session, SendError> {
+        if self.conn.alpn().as_deref() == Some(QS_LEGACY_ALPN) {
+            let remote = legacy_conn_request(&self.conn).await?;
+            return Err(SendError::WrongVersion {
+                local: QS_PROTO_VERSION.to_string(),
+                local_capabilities: Capabilities::supported(),
+                remote,
+                remote_capabilities: Capabilities::NONE,
+            });
+        }
+
+        let peer = self
+            .conn
+            .remote_node_id()
+            .map_err(|e| SendError::NodeAddr(e.to_string()))?;
+        let mut control = ControlChannel::open(&self.conn).await?;
+        let session_id = rand::random::<u64>();
+        let offered = self.offered_capabilities();
+
+        // The receiver only reads the request if it is compatible
+        let ours = Hello::new(offered);
+        control.send_hello(&ours).await?;
+        control
+            .send(SenderToReceiver::ConnRequest {
+                compression: self.args.compression,
+                session_id,
+            })
+            .await?;
+
+        let hello = control.receive_hello().await?;
+        let capabilities = hello.capabilities.intersection(offered);
+
+        if !is_compatible(&ours, &hello) {
+            return Err(SendError::WrongVersion {
+                local: QS_PROTO_VERSION.to_string(),
+                local_capabilities: Capabilities::supported(),
+                remote: hello.version.to_string(),
+                remote_capabilities: hello.capabilities,
+            });
+        }
+
+        let accepted = match control.receive::<ReceiverToSender>().await? {
+            ReceiverToSender::Compression { accepted } => accepted,
             p => return Err(SendError::UnexpectedDataPacket(p)),
+        };
+        control.compression = capabilities.negotiate_compression(self.args.compression, &accepted);
+
+        Ok(Session {
+            control,
+            capabilities,
+            id: session_id,
+            peer,
+        })
+    }
+
+    /// The features this sender offers to the receiver
+    fn offered_capabilities(&self) -> Capabilities {
+        // The receiver only tries to reconnect if the sender waits for it
+        if self.args.reconnect_timeout.is_zero() {
+            Capabilities::supported().without(Capabilities::RECONNECT)
+        } else {
+            Capabilities::supported()
         }
+    }
+
+    /// The [TransferContext] with the limits of the [SenderArgs]
+    fn transfer_context<P, C>(&self, progress: P, should_continue: C) -> TransferContext<P, C> {
+        TransferContext {
+            rate_limit: self.args.rate_limit.clone(),
+            pause: self.args.pause.clone(),
+            progress,
+            should_continue,
+        }
+    }
+
+    /// Offer the files to the receiver and send the ones it accepts
+    async fn send_offer(
+        &mut self,
+        session: Session,
+        mut wait_for_other_peer_to_accept_files_callback: impl FnMut(),
+        mut files_decision_callback: impl FnMut(bool),
+        mut initial_progress_callback: impl FnMut(&[(String, u64, u64)]),
+        ctx: TransferContext<&mut impl FnMut(usize, u64), &mut impl FnMut() -> bool>,
+        connection_callback: &mut impl FnMut(ConnectionState),
+    ) -> Result<bool, SendError> {
+        let Session {
+            mut control,
+            capabilities,
+            id: session_id,
+            peer,
+        } = session;
+        let compression = control.compression;
 
         let files_available = {
             let mut files = Vec::new();
@@ -236,105 +920,325 @@ impl Sender {
                 if !file.exists() {
                     return Err(SendError::FileDoesNotExists(file.clone()));
                 }
-                files.push(get_files_available(file)?);
+                files.push(get_files_available(file, self.args.symlinks)?);
             }
             files
         };
 
-        send_packet(
-            SenderToReceiver::FileInfo {
+        // The receiver would write both to the same path
+        if let Some(name) = find_duplicate_name(&files_available) {
+            return Err(SendError::DuplicateFileName(name.to_string()));
+        }
+
+        control
+            .send(SenderToReceiver::FileInfo {
                 files: files_available.clone(),
-            },
-            &self.conn,
-        )
-        .await?;
+            })
+            .await?;
 
         wait_for_other_peer_to_accept_files_callback();
 
-        let to_skip = match receive_packet::<ReceiverToSender>(&self.conn).await? {
+        let mut to_skip = match control.receive::<ReceiverToSender>().await? {
             ReceiverToSender::AcceptFilesSkip { files } => {
                 files_decision_callback(true);
                 files
             }
             ReceiverToSender::RejectFiles => {
                 files_decision_callback(false);
-                self.close().await;
+                // Only the connection, a server keeps using the endpoint
+                CloseCode::Normal.close(&self.conn, "");
                 return Err(SendError::FilesRejected);
             }
             p => return Err(SendError::UnexpectedDataPacket(p)),
         };
 
-        let to_send: Vec<Option<FileSendRecvTree>> = files_available
-            .iter()
-            .zip(&to_skip)
-            .map(|(file, skip)| {
-                if let Some(skip) = skip {
-                    file.remove_skipped(skip)
-                } else {
-                    Some(file.to_send_recv_tree())
-                }
+        let cancellable = capabilities.contains(Capabilities::CANCEL);
+
+        let to_send = match self.verify_skip(&files_available, &mut to_skip) {
+            Ok(to_send) => to_send,
+            Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+        };
+
+        let mut files = Vec::new();
+        for (entry, (path, file)) in self.args.files.iter().zip(&to_send).enumerate() {
+            if let Some(file) = file {
+                file.flatten(path, entry, &mut files);
+            }
+        }
+
+        let mut streams = self.args.concurrency.clamp(1, MAX_STREAMS).min(files.len());
+
+        control
+            .send(SenderToReceiver::SkipVerified {
+                files: to_skip.clone(),
+                streams: streams as u32,
             })
-            .collect();
+            .await?;
 
         let mut progress: Vec<(String, u64, u64)> = Vec::with_capacity(files_available.len());
         for (file, skip) in files_available.iter().zip(to_skip) {
+            // The totals only count the files that are sent
+            let fully_skipped = skip.as_ref().map(|s| file.fully_skipped(s)).unwrap_or(0);
             progress.push((
                 file.name().to_string(),
-                skip.as_ref().map(|s| s.skip()).unwrap_or(0),
-                file.size(),
+                skip.as_ref()
+                    .map(|s| s.skip())
+                    .unwrap_or(0)
+                    .saturating_sub(fully_skipped),
+                file.size() - fully_skipped,
             ));
         }
 
         initial_progress_callback(&progress);
 
-        let send = self.conn.open_uni().await?;
-        let mut send = GzipEncoder::new(send);
+        let reconnect = capabilities.contains(Capabilities::RECONNECT)
+            && !self.args.reconnect_timeout.is_zero();
 
-        let mut interrupted = false;
+        // Every stream takes the next file that is not sent yet,
+        // so small files are grouped and a large file does not block the others
+        let mut state = SendProgress::new(&files);
+        let ctx = TransferContext {
+            rate_limit: ctx.rate_limit,
+            pause: ctx.pause,
+            progress: Mutex::new(ctx.progress),
+            should_continue: Mutex::new(ctx.should_continue),
+        };
 
-        for (path, file) in self.args.files.iter().zip(to_send) {
-            if let Some(file) = file {
-                match file {
-                    FileSendRecvTree::File { skip, size, .. } => {
-                        let mut file = tokio::fs::File::open(&path).await?;
-                        if !send_file(
-                            &mut send,
-                            &mut file,
-                            skip,
-                            size,
-                            write_callback,
-                            should_continue,
-                        )
-                        .await?
-                        {
-                            interrupted = true;
-                            break;
-                        }
-                    }
-                    FileSendRecvTree::Dir { files, .. } => {
-                        if !send_directory(
-                            &mut send,
-                            path,
-                            &files,
-                            write_callback,
-                            should_continue,
-                        )? {
-                            interrupted = true;
-                            break;
-                        }
-                    }
-                }
+        loop {
+            let workers =
+                (0..streams).map(|_| send_stream(&self.conn, compression, &files, &state, &ctx));
+            let result = tokio::select! {
+                result = futures::future::try_join_all(workers) => result,
+                stop = exchange_control(
+                    &mut control,
+                    &self.args.pause,
+                    capabilities,
+                    |paused| SenderToReceiver::Paused { paused },
+                    control_packet,
+                    connection_callback,
+                ) => Err(stop.into()),
+            };
+
+            let error = match result {
+                Ok(_) if state.interrupted.load(Ordering::Relaxed) => break,
+                // The receiver closes the connection once it has all files
+                Ok(_) => match self.conn.closed().await {
+                    e if reconnect && connection_lost(&self.conn) => e.into(),
+                    _ => match PeerStop::from_close(&self.conn) {
+                        Some(stop) => return Err(stop.into()),
+                        None => break,
+                    },
+                },
+                Err(e) if reconnect && connection_lost(&self.conn) => e,
+                Err(e) => return Err(self.stopped(&mut control, e, cancellable).await),
+            };
+
+            tracing::warn!("connection lost: {}", error);
+            connection_callback(ConnectionState::Lost);
+
+            let Some((conn, new_control, offsets)) =
+                self.accept_resume(session_id, peer, capabilities).await
+            else {
+                return Err(error);
+            };
+
+            self.conn = conn;
+            control = new_control;
+            control.compression = compression;
+
+            if let Err(e) = state.resume(&mut files, &offsets) {
+                return Err(self.stopped(&mut control, e, cancellable).await);
             }
+            streams = self
+                .args
+                .concurrency
+                .clamp(1, MAX_STREAMS)
+                .min(state.pending.len());
+
+            control
+                .send(SenderToReceiver::Resumed {
+                    streams: streams as u32,
+                })
+                .await?;
+
+            let remaining = remaining_bytes(&files, &state.pending, progress.len());
+            for ((_, current, total), remaining) in progress.iter_mut().zip(remaining) {
+                *current = total.saturating_sub(remaining);
+            }
+
+            tracing::info!("resumed the transfer");
+            connection_callback(ConnectionState::Resumed {
+                progress: progress.clone(),
+            });
         }
 
-        send.shutdown().await?;
+        let interrupted = state.interrupted.into_inner();
 
         if !interrupted {
             self.wait_for_close().await;
         } else {
             tracing::info!("the transfer was interrupted");
+            send_stop(
+                &self.conn,
+                &mut control,
+                cancellable,
+                SenderToReceiver::Cancel {
+                    reason: CANCEL_REASON.to_string(),
+                },
+                CloseCode::Cancelled,
+                CANCEL_REASON,
+            )
+            .await;
         }
 
         Ok(!interrupted)
     }
+
+    /// Check the skip list of the receiver against the offered files,
+    /// and restart the files whose prefix does not match the local data
+    ///
+    /// # Returns
+    /// The parts of the offered files that are left to send,
+    /// `None` for the entries that are fully skipped
+    fn verify_skip(
+        &self,
+        files_available: &[FilesAvailable],
+        to_skip: &mut [Option<FilesToSkip>],
+    ) -> Result<Vec<Option<FileSendRecvTree>>, SendError> {
+        // The skip list comes from the other peer, so it has to match our offer
+        if to_skip.len() != files_available.len() {
+            return Err(InvalidSkipList::Length {
+                expected: files_available.len(),
+                got: to_skip.len(),
+            }
+            .into());
+        }
+
+        for (file, skip) in files_available.iter().zip(to_skip.iter()) {
+            if let Some(skip) = skip {
+                file.validate_skip(skip)?;
+            }
+        }
+
+        for (path, skip) in self.args.files.iter().zip(to_skip.iter_mut()) {
+            if let Some(skip) = skip {
+                skip.verify_prefixes(path)?;
+            }
+        }
+
+        let to_send = files_available
+            .iter()
+            .zip(to_skip.iter())
+            .map(|(file, skip)| match skip {
+                Some(skip) => file.remove_skipped(skip),
+                None => Ok(Some(file.to_send_recv_tree())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(to_send)
+    }
+
+    /// Find out why the transfer failed with `error`,
+    /// and tell the receiver about it unless the receiver stopped the transfer
+    async fn stopped(
+        &self,
+        control: &mut ControlChannel,
+        error: SendError,
+        cancellable: bool,
+    ) -> SendError {
+        let error = match error {
+            SendError::PeerCancelled { .. } | SendError::PeerError { .. } => error,
+            error => match peer_stop(&self.conn, control, cancellable, control_packet).await {
+                Some(stop) => stop.into(),
+                None => {
+                    let message = error.to_string();
+                    let packet = SenderToReceiver::Error {
+                        kind: error.kind(),
+                        message: message.clone(),
+                    };
+                    send_stop(
+                        &self.conn,
+                        control,
+                        cancellable,
+                        packet,
+                        CloseCode::Error,
+                        &message,
+                    )
+                    .await;
+                    return error;
+                }
+            },
+        };
+
+        CloseCode::Normal.close(&self.conn, "");
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, QS_ALPN};
+    use pretty_assertions::assert_eq;
+
+    /// Connect to the sender at `node_addr` and ask to resume `session_id`
+    async fn resume(
+        endpoint: &iroh::Endpoint,
+        node_addr: iroh::NodeAddr,
+        session_id: u64,
+    ) -> (iroh::endpoint::Connection, ControlChannel) {
+        let conn = endpoint.connect(node_addr, QS_ALPN).await.unwrap();
+        let mut control = ControlChannel::open(&conn).await.unwrap();
+        control
+            .send_hello(&Hello::new(Capabilities::supported()))
+            .await
+            .unwrap();
+        control
+            .send(ReceiverToSender::Resume {
+                session_id,
+                offsets: vec![Some(10)],
+            })
+            .await
+            .unwrap();
+
+        (conn, control)
+    }
+
+    #[tokio::test]
+    async fn test_accept_resume() {
+        let (sender_endpoint, node_addr) = test_utils::sender_endpoint().await;
+        let receiver = test_utils::local_endpoint().await;
+        let stranger = test_utils::local_endpoint().await;
+
+        let args = SenderArgs {
+            reconnect_timeout: Duration::from_secs(10),
+            ..test_utils::sender_args(Vec::new())
+        };
+        let (sender, _first) = tokio::join!(
+            Sender::connect(sender_endpoint, args),
+            receiver.connect(node_addr.clone(), QS_ALPN)
+        );
+        let sender = sender.unwrap();
+
+        let accept = sender.accept_resume(42, receiver.node_id(), Capabilities::supported());
+        let reconnect = async {
+            // Another node can't take over the session, even if it knows the id
+            let (conn, _control) = resume(&stranger, node_addr.clone(), 42).await;
+            match conn.closed().await {
+                iroh::endpoint::ConnectionError::ApplicationClosed(close) => assert_eq!(
+                    close.error_code.into_inner(),
+                    CloseCode::UnknownSession as u64
+                ),
+                e => panic!("expected the sender to reject the session, got {:?}", e),
+            }
+
+            let (conn, mut control) = resume(&receiver, node_addr, 42).await;
+            control.receive_hello().await.unwrap();
+            (conn, control)
+        };
+        let (resumed, _receiver) = tokio::join!(accept, reconnect);
+
+        let (_, _, offsets) = resumed.unwrap();
+        assert_eq!(offsets, vec![Some(10)]);
+    }
 }
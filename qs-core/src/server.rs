@@ -0,0 +1,310 @@
+use crate::{
+    common::{CloseCode, ConnectionState},
+    pause::Pause,
+    send::{SendError, Sender, SenderArgs},
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{path::PathBuf, sync::Mutex, time::Duration};
+
+/// How often `should_continue` is checked while the server waits for receivers
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Arguments for the server
+pub struct ServerArgs {
+    /// The files that are sent to every receiver, with the rate limit shared by all of them
+    ///
+    /// Every receiver gets its own [Pause] and lost connections are not resumed,
+    /// a receiver that connects again resumes from its local files.
+    pub sender: SenderArgs,
+    /// Receivers served at the same time, further receivers are turned away
+    pub max_peers: Option<usize>,
+    /// Stop once the files were received completely this many times
+    pub max_downloads: Option<usize>,
+    /// Share this directory instead of sending `sender.files`,
+    /// every receiver lists it and requests the paths it wants (see [Sender::share])
+    pub share: Option<PathBuf>,
+}
+
+/// What happens with the receivers of a server, each receiver is identified by a number
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// A receiver connected
+    Connected { peer: usize, node_id: iroh::NodeId },
+    /// A receiver was turned away, because `max_peers` receivers are served already
+    Busy { node_id: iroh::NodeId },
+    /// The receiver accepted or rejected the files
+    Decision { peer: usize, accepted: bool },
+    /// The initial progress of each file sent to the receiver (name, current, total)
+    InitialProgress {
+        peer: usize,
+        progress: Vec<(String, u64, u64)>,
+    },
+    /// Data was sent to the receiver (index of the file/dir, bytes)
+    Sent {
+        peer: usize,
+        index: usize,
+        bytes: u64,
+    },
+    /// The receiver paused or resumed the transfer
+    Connection { peer: usize, state: ConnectionState },
+    /// The transfer to the receiver is over, with the result of [Sender::send_files] or [Sender::share]
+    Finished {
+        peer: usize,
+        result: Result<bool, SendError>,
+    },
+}
+
+/// Sends the same files to every receiver that connects, using a single ticket,
+/// or shares a directory with them
+pub struct Server {
+    /// Server arguments
+    args: ServerArgs,
+    /// The local endpoint
+    endpoint: iroh::Endpoint,
+}
+
+impl Server {
+    pub fn new(endpoint: iroh::Endpoint, args: ServerArgs) -> Self {
+        Self { args, endpoint }
+    }
+
+    /// Close the endpoint
+    pub async fn close(&mut self) {
+        self.endpoint.close().await;
+    }
+
+    /// Serve the files until `max_downloads` is reached, or `should_continue` stops the server
+    /// # Arguments
+    /// * `event_callback` - Callback with every [ServerEvent]
+    /// * `should_continue` - Callback to check if the server should continue,
+    ///   the running transfers are cancelled once it returns false
+    ///
+    /// # Returns
+    /// The number of receivers that received all files
+    pub async fn serve(
+        &self,
+        event_callback: &mut impl FnMut(ServerEvent),
+        should_continue: &mut impl FnMut() -> bool,
+    ) -> usize {
+        let event_callback = Mutex::new(event_callback);
+        let should_continue = Mutex::new(should_continue);
+
+        let mut sessions = FuturesUnordered::new();
+        // Receivers are accepted while others are served, a slow handshake must not hold them up
+        let mut handshakes = FuturesUnordered::new();
+        let mut next_peer = 0;
+        let mut downloads = 0;
+        let mut running = true;
+        let mut stop_poll = tokio::time::interval(STOP_POLL_INTERVAL);
+
+        loop {
+            // Receivers that may still fail count towards the limit until they do
+            let accepting = running
+                && self
+                    .args
+                    .max_downloads
+                    .is_none_or(|max| downloads + sessions.len() + handshakes.len() < max);
+
+            if !accepting && sessions.is_empty() && handshakes.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                incoming = self.endpoint.accept(), if accepting => {
+                    let Some(incoming) = incoming else {
+                        break;
+                    };
+
+                    match incoming.accept() {
+                        Ok(connecting) => handshakes.push(connecting),
+                        Err(e) => tracing::warn!("failed to accept a receiver: {}", e),
+                    }
+                }
+                Some(conn) = handshakes.next(), if !handshakes.is_empty() => {
+                    let conn = match conn {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            tracing::warn!("failed to accept a receiver: {}", e);
+                            continue;
+                        }
+                    };
+                    let Ok(node_id) = conn.remote_node_id() else {
+                        continue;
+                    };
+
+                    if self.args.max_peers.is_some_and(|max| sessions.len() >= max) {
+                        tracing::info!("turned away {}, the server is busy", node_id);
+                        CloseCode::Busy.close(&conn, "the server is busy");
+                        (event_callback.lock().unwrap())(ServerEvent::Busy { node_id });
+                        continue;
+                    }
+
+                    let peer = next_peer;
+                    next_peer += 1;
+
+                    tracing::info!("receiver {} connected from {}", peer, node_id);
+                    (event_callback.lock().unwrap())(ServerEvent::Connected { peer, node_id });
+
+                    sessions.push(self.session(peer, conn, &event_callback, &should_continue));
+                }
+                Some((peer, result)) = sessions.next(), if !sessions.is_empty() => {
+                    if matches!(result, Ok(true)) {
+                        downloads += 1;
+                    }
+                    (event_callback.lock().unwrap())(ServerEvent::Finished { peer, result });
+                }
+                _ = stop_poll.tick(), if running => {
+                    running = (should_continue.lock().unwrap())();
+                    if !running {
+                        handshakes.clear();
+                    }
+                }
+            }
+        }
+
+        downloads
+    }
+
+    /// Send the files to one receiver
+    async fn session(
+        &self,
+        peer: usize,
+        conn: iroh::endpoint::Connection,
+        event_callback: &Mutex<&mut impl FnMut(ServerEvent)>,
+        should_continue: &Mutex<&mut impl FnMut() -> bool>,
+    ) -> (usize, Result<bool, SendError>) {
+        let args = SenderArgs {
+            pause: Pause::default(),
+            reconnect_timeout: Duration::ZERO,
+            ..self.args.sender.clone()
+        };
+        let mut sender = Sender::from_connection(self.endpoint.clone(), conn, args);
+
+        let event = |event: ServerEvent| (event_callback.lock().unwrap())(event);
+
+        let decision = |accepted| event(ServerEvent::Decision { peer, accepted });
+        let initial_progress = |progress: &[(String, u64, u64)]| {
+            event(ServerEvent::InitialProgress {
+                peer,
+                progress: progress.to_vec(),
+            })
+        };
+        let write_callback = &mut |index, bytes| event(ServerEvent::Sent { peer, index, bytes });
+        let should_continue = &mut || (should_continue.lock().unwrap())();
+        let connection_callback = &mut |state| event(ServerEvent::Connection { peer, state });
+
+        let result = match &self.args.share {
+            Some(root) => {
+                sender
+                    .share(
+                        root,
+                        decision,
+                        initial_progress,
+                        write_callback,
+                        should_continue,
+                        connection_callback,
+                    )
+                    .await
+            }
+            None => {
+                sender
+                    .send_files(
+                        || {},
+                        decision,
+                        initial_progress,
+                        write_callback,
+                        should_continue,
+                        connection_callback,
+                    )
+                    .await
+            }
+        };
+
+        (peer, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::Compression,
+        receive::{AcceptFiles, Receiver},
+        send::DEFAULT_CONCURRENCY,
+        test_utils,
+    };
+    use pretty_assertions::assert_eq;
+
+    async fn receive(node_addr: iroh::NodeAddr, output_path: PathBuf) -> bool {
+        let mut receiver = Receiver::connect(
+            test_utils::local_endpoint().await,
+            node_addr,
+            test_utils::receiver_args(),
+        )
+        .await
+        .unwrap();
+
+        let result = receiver
+            .receive_files(
+                |_| {},
+                |_, _| {
+                    Some(AcceptFiles {
+                        output_path: output_path.clone(),
+                        selection: None,
+                    })
+                },
+                &mut |_, _| {},
+                &mut || true,
+                &mut |_| {},
+            )
+            .await;
+        receiver.close().await;
+
+        result.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_two_receivers() {
+        let dir = std::env::temp_dir().join(format!("qs-test-serve-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("file.bin"), &data).unwrap();
+
+        let (endpoint, node_addr) = test_utils::sender_endpoint().await;
+        let server = Server::new(
+            endpoint,
+            ServerArgs {
+                sender: SenderArgs {
+                    concurrency: DEFAULT_CONCURRENCY,
+                    compression: Compression::default(),
+                    ..test_utils::sender_args(vec![dir.join("file.bin")])
+                },
+                max_peers: Some(2),
+                max_downloads: Some(2),
+                share: None,
+            },
+        );
+
+        let mut connected = 0;
+        let mut event_callback = |event| {
+            if let ServerEvent::Connected { .. } = event {
+                connected += 1;
+            }
+        };
+        let mut should_continue = || true;
+        let (downloads, first, second) = tokio::join!(
+            server.serve(&mut event_callback, &mut should_continue),
+            receive(node_addr.clone(), dir.join("a")),
+            receive(node_addr.clone(), dir.join("b")),
+        );
+
+        assert!(first && second);
+        assert_eq!(downloads, 2);
+        assert_eq!(connected, 2);
+        assert_eq!(std::fs::read(dir.join("a").join("file.bin")).unwrap(), data);
+        assert_eq!(std::fs::read(dir.join("b").join("file.bin")).unwrap(), data);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use crate::common::FileName;
+use bincode::{Decode, Encode};
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// An entry of a shared directory, as the receiver sees it when listing it
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct DirEntry {
+    pub name: FileName,
+    /// Size of a file, `None` for a directory
+    pub size: Option<u64>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.size.is_none()
+    }
+}
+
+/// Resolve a path the receiver requested from the shared directory `root`
+///
+/// The path is relative to the root with `/` as separator, an empty path is the root itself.
+/// It must not leave the root, neither with `..` nor through a symlink.
+///
+/// # Returns
+/// - [std::option::Option::None] if the path does not exist or is outside of the root
+pub fn resolve_shared_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+
+    let mut resolved = root.clone();
+    for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        // A single normal component, so `..`, prefixes and separators of other platforms are refused
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == part => resolved.push(name),
+            _ => return None,
+        }
+    }
+
+    resolved
+        .canonicalize()
+        .ok()?
+        .starts_with(&root)
+        .then_some(resolved)
+}
+
+/// The entries of the shared directory `dir`, sorted by name
+///
+/// Symlinks are left out, they are not sent from a shared directory.
+pub fn list_shared_dir(dir: &Path) -> io::Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+
+        let size = if metadata.is_dir() {
+            None
+        } else if metadata.is_file() {
+            Some(metadata.len())
+        } else {
+            continue;
+        };
+
+        entries.push(DirEntry {
+            name: FileName::from_os_str(&entry.file_name()),
+            size,
+        });
+    }
+
+    entries.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_shared_paths() {
+        let dir = std::env::temp_dir().join(format!("qs-test-share-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("file"), b"hello").unwrap();
+        std::fs::write(dir.join("secret"), b"secret").unwrap();
+
+        let root_canonical = root.canonicalize().unwrap();
+        assert_eq!(resolve_shared_path(&root, ""), Some(root_canonical.clone()));
+        assert_eq!(
+            resolve_shared_path(&root, "/sub//file"),
+            Some(root_canonical.join("sub").join("file"))
+        );
+
+        assert_eq!(resolve_shared_path(&root, "../secret"), None);
+        assert_eq!(resolve_shared_path(&root, "sub/../../secret"), None);
+        assert_eq!(resolve_shared_path(&root, "missing"), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret"), root.join("link")).unwrap();
+            assert_eq!(resolve_shared_path(&root, "link"), None);
+        }
+
+        let entries = list_shared_dir(&root).unwrap();
+        assert_eq!(
+            entries,
+            vec![DirEntry {
+                name: "sub".into(),
+                size: None,
+            }]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
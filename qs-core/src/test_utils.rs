@@ -0,0 +1,65 @@
+//! Helpers for the tests that connect a sender and receivers on localhost
+
+use crate::{
+    alpns,
+    common::SymlinkPolicy,
+    compression::{AcceptedCompression, Compression},
+    pause::Pause,
+    rate_limit::RateLimit,
+    receive::{CollisionPolicy, ReceiverArgs},
+    send::SenderArgs,
+};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// An endpoint without relays, it accepts the connections of receivers
+pub async fn local_endpoint() -> iroh::Endpoint {
+    iroh::Endpoint::builder()
+        .alpns(alpns())
+        .relay_mode(iroh::RelayMode::Disabled)
+        .bind()
+        .await
+        .unwrap()
+}
+
+/// The endpoint of a sender and the address receivers connect to it with
+pub async fn sender_endpoint() -> (iroh::Endpoint, iroh::NodeAddr) {
+    let endpoint = local_endpoint().await;
+    let node_addr =
+        iroh::NodeAddr::new(endpoint.node_id()).with_direct_addresses([SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            endpoint.bound_sockets().0.port(),
+        ))]);
+
+    (endpoint, node_addr)
+}
+
+/// Send `files` uncompressed on a single stream, without limits or reconnecting
+pub fn sender_args(files: Vec<PathBuf>) -> SenderArgs {
+    SenderArgs {
+        files,
+        symlinks: SymlinkPolicy::default(),
+        concurrency: 1,
+        compression: Compression::NONE,
+        rate_limit: RateLimit::default(),
+        pause: Pause::default(),
+        reconnect_timeout: Duration::ZERO,
+    }
+}
+
+/// Overwrite existing files, without a journal, limits or reconnecting
+pub fn receiver_args() -> ReceiverArgs {
+    ReceiverArgs {
+        collision: CollisionPolicy::Overwrite,
+        preserve_metadata: false,
+        ignore_free_space: true,
+        rate_limit: RateLimit::default(),
+        pause: Pause::default(),
+        reconnect_timeout: Duration::ZERO,
+        journal: false,
+        compression: AcceptedCompression::default(),
+    }
+}
@@ -0,0 +1,496 @@
+use crate::{
+    common::PacketRecvError,
+    compression::{self, AcceptedCompression, Compression, CompressionAlgorithm},
+    QS_PROTO_VERSION, QS_WIRE_VERSION,
+};
+use bincode::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Optional features of the protocol, both peers use the features they have in common
+///
+/// Unknown flags of newer versions are ignored, so only the wire version
+/// and the [Capabilities::REQUIRED] features decide if two peers can talk to each other at all.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Encode, Decode)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// No features, e.g. of a peer that predates them
+    pub const NONE: Self = Self(0);
+    /// Gzip compression
+    pub const GZIP: Self = Self(1 << 0);
+    /// Zstd compression
+    pub const ZSTD: Self = Self(1 << 1);
+    /// The file data is verified with BLAKE3 checksums
+    pub const CHECKSUMS: Self = Self(1 << 2);
+    /// Modification times and permissions can be applied
+    pub const METADATA: Self = Self(1 << 3);
+    /// A lost connection can be resumed
+    pub const RECONNECT: Self = Self(1 << 4);
+    /// The peers tell each other when they pause the transfer
+    pub const PAUSE: Self = Self(1 << 5);
+    /// The peers tell each other why they stop the transfer early
+    pub const CANCEL: Self = Self(1 << 6);
+    /// The sender can share a directory that the receiver lists and requests paths from
+    pub const SHARE: Self = Self(1 << 7);
+    /// The sender can stream data of unknown length, e.g. from a pipe
+    pub const STREAM: Self = Self(1 << 8);
+    /// The sender can offer a piece of text instead of files
+    pub const TEXT: Self = Self(1 << 9);
+
+    /// Features this version can not work without
+    pub const REQUIRED: Self = Self::CHECKSUMS;
+
+    /// Names of the features, for displaying them
+    const NAMES: &[(Self, &str)] = &[
+        (Self::GZIP, "gzip"),
+        (Self::ZSTD, "zstd"),
+        (Self::CHECKSUMS, "checksums"),
+        (Self::METADATA, "metadata"),
+        (Self::RECONNECT, "reconnect"),
+        (Self::PAUSE, "pause"),
+        (Self::CANCEL, "cancel"),
+        (Self::SHARE, "share"),
+        (Self::STREAM, "stream"),
+        (Self::TEXT, "text"),
+    ];
+
+    /// All features this version supports
+    pub const fn supported() -> Self {
+        Self(
+            Self::GZIP.0
+                | Self::ZSTD.0
+                | Self::CHECKSUMS.0
+                | Self::METADATA.0
+                | Self::RECONNECT.0
+                | Self::PAUSE.0
+                | Self::CANCEL.0
+                | Self::SHARE.0
+                | Self::STREAM.0
+                | Self::TEXT.0,
+        )
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These features without the ones of `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// The features both peers support
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// If data compressed with `algorithm` can be sent
+    pub fn supports(self, algorithm: CompressionAlgorithm) -> bool {
+        match algorithm {
+            CompressionAlgorithm::None => true,
+            CompressionAlgorithm::Gzip => self.contains(Self::GZIP),
+            CompressionAlgorithm::Zstd => self.contains(Self::ZSTD),
+        }
+    }
+
+    /// The compression to use, `requested` if both peers support it and the receiver `accepted` it,
+    /// otherwise the algorithm the receiver prefers among the usable ones.
+    /// The level of `requested` only applies to its own algorithm,
+    /// and it is lowered to the highest accepted level
+    pub fn negotiate_compression(
+        self,
+        requested: Compression,
+        accepted: &AcceptedCompression,
+    ) -> Compression {
+        let usable = |algorithm: CompressionAlgorithm| {
+            algorithm == CompressionAlgorithm::None
+                || (self.supports(algorithm) && accepted.algorithms.contains(&algorithm))
+        };
+
+        let (algorithm, level) = if usable(requested.algorithm) {
+            (requested.algorithm, requested.level)
+        } else {
+            let algorithm = accepted
+                .algorithms
+                .iter()
+                .copied()
+                .find(|algorithm| usable(*algorithm))
+                .unwrap_or(CompressionAlgorithm::None);
+            (algorithm, None)
+        };
+
+        let level = match (level, accepted.max_level) {
+            (Some(level), Some(max)) => Some(level.min(max)),
+            (None, Some(max)) if algorithm.default_level() > max => Some(max),
+            (level, _) => level,
+        };
+
+        Compression {
+            algorithm,
+            level,
+            ..requested
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+
+        if names.is_empty() {
+            write!(f, "no features")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// If two peers can talk to each other, they have to use the same wire version
+/// and both support the [Capabilities::REQUIRED] features.
+/// The protocol versions themselves may differ, e.g. in the minor version
+pub fn is_compatible(ours: &Hello, theirs: &Hello) -> bool {
+    ours.wire == theirs.wire
+        && ours
+            .capabilities
+            .intersection(theirs.capabilities)
+            .contains(Capabilities::REQUIRED)
+}
+
+/// Largest extension of a [Hello] that is accepted
+const MAX_HELLO_EXTENSION: u32 = 64 * 1024;
+
+/// The fixed-layout start of the control stream, both peers send it before any packet
+///
+/// Peers can always read each other's version and features this way,
+/// also if the packets that follow changed in between.
+/// The layout is big endian: major (u32), minor (u32), patch (u32), capabilities (u64),
+/// length of the extension (u32) and the extension itself.
+/// The extension starts with the wire version (u32), a hello without it is wire version 0.
+/// Newer versions append their fields to the extension, older versions skip them.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hello {
+    pub version: semver::Version,
+    pub wire: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// The hello of this version with `capabilities`
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: semver::Version::parse(QS_PROTO_VERSION).expect("valid protocol version"),
+            wire: QS_WIRE_VERSION,
+            capabilities,
+        }
+    }
+
+    pub async fn write<W>(&self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::with_capacity(28);
+        for part in [self.version.major, self.version.minor, self.version.patch] {
+            let part = u32::try_from(part).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("version {} does not fit into the hello", self.version),
+                )
+            })?;
+            buf.extend_from_slice(&part.to_be_bytes());
+        }
+        buf.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        buf.extend_from_slice(&4u32.to_be_bytes());
+        buf.extend_from_slice(&self.wire.to_be_bytes());
+
+        writer.write_all(&buf).await
+    }
+
+    pub async fn read<R>(reader: &mut R) -> Result<Self, PacketRecvError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let major = reader.read_u32().await?;
+        let minor = reader.read_u32().await?;
+        let patch = reader.read_u32().await?;
+        let capabilities = Capabilities(reader.read_u64().await?);
+
+        let mut extension = reader.read_u32().await?;
+        if extension > MAX_HELLO_EXTENSION {
+            return Err(PacketRecvError::FrameTooLarge(extension));
+        }
+        let wire = if extension >= 4 {
+            extension -= 4;
+            reader.read_u32().await?
+        } else {
+            0
+        };
+        // Fields of newer versions
+        tokio::io::copy(&mut reader.take(extension as u64), &mut tokio::io::sink()).await?;
+
+        Ok(Self {
+            version: semver::Version::new(major as u64, minor as u64, patch as u64),
+            wire,
+            capabilities,
+        })
+    }
+}
+
+/// Packets version 0.4 started a connection with, when it used [crate::QS_LEGACY_ALPN]
+#[derive(Debug, Encode, Decode)]
+enum LegacySenderToReceiver {
+    ConnRequest { version_num: String },
+}
+
+/// Answer of version 0.4 to a [LegacySenderToReceiver::ConnRequest] of another version
+#[derive(Debug, Encode, Decode)]
+enum LegacyReceiverToSender {
+    WrongVersion { expected: String },
+}
+
+/// Largest packet of version 0.4 that is read
+const MAX_LEGACY_PACKET: usize = 64 * 1024;
+
+/// Send a packet the way version 0.4 did, gzip compressed on its own unidirectional stream
+async fn send_legacy<P: Encode>(
+    conn: &iroh::endpoint::Connection,
+    packet: P,
+) -> Result<(), PacketRecvError> {
+    let data = bincode::encode_to_vec(packet, bincode::config::standard()).unwrap();
+    let gzip = Compression {
+        algorithm: CompressionAlgorithm::Gzip,
+        ..Compression::NONE
+    };
+
+    let mut compressed = Vec::new();
+    let mut encoder = gzip.encoder(&mut compressed, gzip.algorithm);
+    encoder.write_all(&data).await?;
+    encoder.shutdown().await?;
+    drop(encoder);
+
+    let mut send = conn.open_uni().await?;
+    AsyncWriteExt::write_all(&mut send, &compressed).await?;
+    send.finish().map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+/// Receive a packet sent with [send_legacy]
+async fn receive_legacy<P: Decode<()>>(
+    conn: &iroh::endpoint::Connection,
+) -> Result<P, PacketRecvError> {
+    let recv = conn.accept_uni().await?;
+
+    let mut compressed = Vec::new();
+    recv.take(MAX_LEGACY_PACKET as u64)
+        .read_to_end(&mut compressed)
+        .await?;
+
+    let mut data = Vec::new();
+    compression::decoder(compressed.as_slice(), CompressionAlgorithm::Gzip)
+        .take(MAX_LEGACY_PACKET as u64)
+        .read_to_end(&mut data)
+        .await?;
+
+    Ok(bincode::decode_from_slice(&data, bincode::config::standard())?.0)
+}
+
+/// Start the handshake of version 0.4 with a receiver that connected with [crate::QS_LEGACY_ALPN],
+/// it rejects the request because the versions differ
+/// # Returns
+/// The version of the receiver
+pub(crate) async fn legacy_conn_request(
+    conn: &iroh::endpoint::Connection,
+) -> Result<String, PacketRecvError> {
+    send_legacy(
+        conn,
+        LegacySenderToReceiver::ConnRequest {
+            version_num: QS_PROTO_VERSION.to_string(),
+        },
+    )
+    .await?;
+
+    let LegacyReceiverToSender::WrongVersion { expected } = receive_legacy(conn).await?;
+    Ok(expected)
+}
+
+/// Reject the handshake of a version 0.4 sender that was connected to with [crate::QS_LEGACY_ALPN]
+/// # Returns
+/// The version of the sender
+pub(crate) async fn legacy_wrong_version(
+    conn: &iroh::endpoint::Connection,
+) -> Result<String, PacketRecvError> {
+    let LegacySenderToReceiver::ConnRequest { version_num } = receive_legacy(conn).await?;
+
+    send_legacy(
+        conn,
+        LegacyReceiverToSender::WrongVersion {
+            expected: QS_PROTO_VERSION.to_string(),
+        },
+    )
+    .await?;
+
+    Ok(version_num)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_is_compatible() {
+        let hello = |version: &str, wire: u32, capabilities: Capabilities| Hello {
+            version: semver::Version::parse(version).unwrap(),
+            wire,
+            capabilities,
+        };
+        let ours = Hello::new(Capabilities::supported());
+
+        // Other versions with the same wire version only differ in their features
+        assert!(is_compatible(&ours, &ours));
+        assert!(is_compatible(
+            &ours,
+            &hello("0.5.3", QS_WIRE_VERSION, Capabilities::REQUIRED)
+        ));
+        assert!(is_compatible(
+            &ours,
+            &hello("0.9.0", QS_WIRE_VERSION, Capabilities(u64::MAX))
+        ));
+        assert!(is_compatible(
+            &hello("0.6.0", 1, Capabilities::supported()),
+            &hello("0.5.0", 1, Capabilities::supported())
+        ));
+
+        assert!(!is_compatible(
+            &ours,
+            &hello("0.7.0", QS_WIRE_VERSION + 1, Capabilities::supported())
+        ));
+        assert!(!is_compatible(
+            &ours,
+            &hello("0.7.0", 0, Capabilities::supported())
+        ));
+        assert!(!is_compatible(
+            &ours,
+            &hello("0.7.0", QS_WIRE_VERSION, Capabilities::GZIP)
+        ));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let ours = Capabilities::supported();
+        let theirs = Capabilities::GZIP | Capabilities::CHECKSUMS | Capabilities(1 << 40);
+        let common = ours.intersection(theirs);
+
+        assert_eq!(common, Capabilities::GZIP | Capabilities::CHECKSUMS);
+        assert_eq!(common.to_string(), "gzip, checksums");
+        assert!(common.contains(Capabilities::REQUIRED));
+
+        let no_reconnect = ours.without(Capabilities::RECONNECT);
+        assert!(!no_reconnect.contains(Capabilities::RECONNECT));
+        assert!(no_reconnect.contains(Capabilities::REQUIRED));
+
+        let zstd = Compression {
+            level: Some(19),
+            ..Compression::default()
+        };
+        let gzip = Compression {
+            algorithm: CompressionAlgorithm::Gzip,
+            level: None,
+            ..zstd
+        };
+        let accepted = AcceptedCompression::default();
+        assert_eq!(common.negotiate_compression(zstd, &accepted), gzip);
+        assert_eq!(common.negotiate_compression(gzip, &accepted), gzip);
+        assert_eq!(ours.negotiate_compression(zstd, &accepted), zstd);
+
+        let plain = Capabilities::CHECKSUMS;
+        assert_eq!(
+            plain.negotiate_compression(zstd, &accepted).algorithm,
+            CompressionAlgorithm::None
+        );
+
+        // The receiver limits the algorithms and the level
+        let gzip_only = AcceptedCompression {
+            algorithms: vec![CompressionAlgorithm::Gzip],
+            max_level: Some(4),
+        };
+        assert_eq!(
+            ours.negotiate_compression(zstd, &gzip_only),
+            Compression {
+                level: Some(4),
+                ..gzip
+            }
+        );
+
+        let low_level = AcceptedCompression {
+            max_level: Some(5),
+            ..AcceptedCompression::default()
+        };
+        assert_eq!(ours.negotiate_compression(zstd, &low_level).level, Some(5));
+        // The default level of zstd is low enough
+        assert_eq!(
+            ours.negotiate_compression(Compression::default(), &low_level),
+            Compression::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hello() {
+        let hello = Hello::new(Capabilities::supported());
+
+        let mut buf = Vec::new();
+        hello.write(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), 28);
+        assert_eq!(Hello::read(&mut buf.as_slice()).await.unwrap(), hello);
+
+        // A newer version with more fields in the extension and unknown features
+        let mut newer = Vec::new();
+        for part in [0u32, 9, 1] {
+            newer.extend_from_slice(&part.to_be_bytes());
+        }
+        newer.extend_from_slice(&(Capabilities::GZIP.0 | 1 << 40).to_be_bytes());
+        newer.extend_from_slice(&7u32.to_be_bytes());
+        newer.extend_from_slice(&QS_WIRE_VERSION.to_be_bytes());
+        newer.extend_from_slice(b"new");
+        newer.extend_from_slice(b"next packet");
+
+        let mut reader = newer.as_slice();
+        let hello = Hello::read(&mut reader).await.unwrap();
+        assert_eq!(hello.version, semver::Version::new(0, 9, 1));
+        assert_eq!(hello.wire, QS_WIRE_VERSION);
+        assert_eq!(
+            hello.capabilities.intersection(Capabilities::supported()),
+            Capabilities::GZIP
+        );
+        assert_eq!(reader, b"next packet");
+
+        // An older version without the wire version
+        let mut older = Vec::new();
+        for part in [0u32, 6, 0] {
+            older.extend_from_slice(&part.to_be_bytes());
+        }
+        older.extend_from_slice(&Capabilities::supported().0.to_be_bytes());
+        older.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(Hello::read(&mut older.as_slice()).await.unwrap().wire, 0);
+
+        // Versions that don't fit into the layout are not cut off
+        let large = Hello {
+            version: semver::Version::new(0, 1 << 32, 0),
+            ..Hello::new(Capabilities::supported())
+        };
+        let error = large.write(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -11,10 +11,13 @@ use std::{
 use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
 use iroh::{Endpoint, RelayMode, SecretKey};
 use qs_core::{
-    common::FilesAvailable,
-    receive::{Receiver, ReceiverArgs},
-    send::{Sender, SenderArgs},
-    QS_ALPN,
+    common::{ConnectionState, FilesAvailable, SymlinkPolicy, DEFAULT_RECONNECT_TIMEOUT},
+    compression::{AcceptedCompression, Compression},
+    pause::{Pause, PauseState},
+    rate_limit::RateLimit,
+    receive::{AcceptFiles, CollisionPolicy, ReceiveError, Receiver, ReceiverArgs},
+    send::{SendError, Sender, SenderArgs, DEFAULT_CONCURRENCY},
+    alpns,
 };
 use serde::Serialize;
 use tauri::{AppHandle, Emitter, Listener};
@@ -30,7 +33,11 @@ const TRANSFER_CANCELLED_EVENT: &str = "transfer-cancelled";
 const TRANSFER_FINISHED_EVENT: &str = "transfer-finished";
 const TICKET_EVENT: &str = "server-connection-code";
 const ACCEPT_FILES_EVENT: &str = "accept-files";
+const TEXT_OFFERED_EVENT: &str = "text-offered";
+const ACCEPT_TEXT_EVENT: &str = "accept-text";
 const CONNECTED_TO_SERVER_EVENT: &str = "connected-to-server";
+const CONNECTION_STATE_EVENT: &str = "connection-state";
+const TRANSFER_PAUSED_EVENT: &str = "transfer-paused";
 
 #[derive(Clone, Serialize)]
 struct InitialDownloadProgress {
@@ -38,12 +45,34 @@ struct InitialDownloadProgress {
     data: Vec<(String, u64, u64)>,
 }
 
+#[derive(Clone, Serialize)]
+struct TransferPaused {
+    /// Paused by this side
+    local: bool,
+    /// Paused by the other peer
+    remote: bool,
+}
+
+impl From<PauseState> for TransferPaused {
+    fn from(state: PauseState) -> Self {
+        Self {
+            local: state.local,
+            remote: state.remote,
+        }
+    }
+}
+
 #[derive(Clone, Serialize)]
 struct FilesOffered {
     /// Filename, size in bytes
     files: Vec<(String, u64, bool)>,
 }
 
+#[derive(Clone, Serialize)]
+struct TextOffered {
+    text: String,
+}
+
 #[tauri::command]
 fn exit(handle: AppHandle, code: i32) {
     tracing::info!("exiting with code {}", code);
@@ -55,6 +84,10 @@ fn exit(handle: AppHandle, code: i32) {
 // This seems to be faster than using tauri events
 lazy_static::lazy_static! {
     static ref BYTES_TRANSFERRED: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+    // Shared with the running transfer, so the limit can be changed at any time
+    static ref RATE_LIMIT: RateLimit = RateLimit::default();
+    // Shared with the running transfer, so it can be paused at any time
+    static ref PAUSE: Pause = Pause::default();
 }
 
 #[tauri::command(async)]
@@ -62,18 +95,61 @@ async fn bytes_transferred() -> u64 {
     BYTES_TRANSFERRED.load(std::sync::atomic::Ordering::Relaxed)
 }
 
+/// Tell the frontend that the connection was lost or resumed,
+/// a resumed transfer starts over with the progress of the receiver
+fn emit_connection_state(window: &tauri::Window, state: ConnectionState) {
+    match state {
+        ConnectionState::Lost => window.emit(CONNECTION_STATE_EVENT, "lost").unwrap(),
+        ConnectionState::Reconnecting { .. } => {
+            window.emit(CONNECTION_STATE_EVENT, "reconnecting").unwrap()
+        }
+        ConnectionState::Resumed { progress } => {
+            BYTES_TRANSFERRED.store(0, std::sync::atomic::Ordering::Relaxed);
+            window
+                .emit(
+                    INITIAL_PROGRESS_EVENT,
+                    InitialDownloadProgress { data: progress },
+                )
+                .unwrap();
+            window.emit(CONNECTION_STATE_EVENT, "resumed").unwrap();
+        }
+        ConnectionState::Paused(state) => window
+            .emit(TRANSFER_PAUSED_EVENT, TransferPaused::from(state))
+            .unwrap(),
+    }
+}
+
+/// Set the maximum transfer speed in bytes per second, `None` for no limit
+#[tauri::command(async)]
+async fn set_rate_limit(bytes_per_sec: Option<u64>) {
+    RATE_LIMIT.set(bytes_per_sec);
+}
+
+/// Pause the running transfer, the connection stays open
+#[tauri::command(async)]
+async fn pause_transfer() {
+    PAUSE.pause();
+}
+
+/// Continue the paused transfer
+#[tauri::command(async)]
+async fn resume_transfer() {
+    PAUSE.resume();
+}
+
 /// # Returns
 /// * `Ok(true)` if the download was successful
 /// * `Ok(false)` if the download was cancelled (by the user)
 #[tauri::command(async)]
 async fn download_files(window: tauri::Window, ticket: String) -> Result<bool, String> {
     BYTES_TRANSFERRED.store(0, std::sync::atomic::Ordering::Relaxed);
+    PAUSE.resume();
 
     let secret_key = SecretKey::generate(rand::rngs::OsRng);
 
     let endpoint = Endpoint::builder()
         .secret_key(secret_key)
-        .alpns(vec![QS_ALPN.to_vec()])
+        .alpns(alpns())
         .relay_mode(RelayMode::Default)
         .bind()
         .await
@@ -88,7 +164,16 @@ async fn download_files(window: tauri::Window, ticket: String) -> Result<bool, S
             .map_err(|_| "invalid ticket".to_string())?
             .0;
 
-    let receiver_args = ReceiverArgs { resume: true };
+    let receiver_args = ReceiverArgs {
+        collision: CollisionPolicy::Resume,
+        preserve_metadata: true,
+        ignore_free_space: false,
+        rate_limit: RATE_LIMIT.clone(),
+        pause: PAUSE.clone(),
+        reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+        journal: true,
+        compression: AcceptedCompression::default(),
+    };
     let mut receiver = Receiver::connect(endpoint, node_addr, receiver_args)
         .await
         .map_err(|e| format!("failed to connect to sender: {}", e))?;
@@ -106,7 +191,28 @@ async fn download_files(window: tauri::Window, ticket: String) -> Result<bool, S
         interrupted_clone.store(true, std::sync::atomic::Ordering::Relaxed);
     });
 
-    receiver
+    let text_window = window.clone();
+    receiver.on_text(move |text| {
+        text_window
+            .emit(
+                TEXT_OFFERED_EVENT,
+                TextOffered {
+                    text: text.to_string(),
+                },
+            )
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+
+        text_window.listen(ACCEPT_TEXT_EVENT, move |event| {
+            let _ = tx.send(event.payload() == "true");
+        });
+
+        rx.recv()
+            .expect("Failed to receive text acceptance decision")
+    });
+
+    let result = receiver
         .receive_files(
             |files| {
                 std::thread::sleep(Duration::from_millis(100));
@@ -119,7 +225,7 @@ async fn download_files(window: tauri::Window, ticket: String) -> Result<bool, S
                     )
                     .unwrap();
             },
-            |files_offered| {
+            |files_offered, _| {
                 let offered: Vec<(String, u64, bool)> = files_offered
                     .iter()
                     .map(|f| {
@@ -154,14 +260,23 @@ async fn download_files(window: tauri::Window, ticket: String) -> Result<bool, S
 
                 rx.recv()
                     .expect("Failed to receive file acceptance decision")
+                    .map(AcceptFiles::all)
             },
-            &mut |bytes_read| {
+            &mut |_, bytes_read| {
                 BYTES_TRANSFERRED.fetch_add(bytes_read, std::sync::atomic::Ordering::Relaxed);
             },
             &mut || !interrupted.load(std::sync::atomic::Ordering::Relaxed),
+            &mut |state| emit_connection_state(&window, state),
         )
-        .await
-        .map_err(|e| format!("failed to receive files: {}", e))?;
+        .await;
+
+    // Cancelled by the sender, rather than failed
+    if let Err(ReceiveError::PeerCancelled { .. }) = result {
+        window.emit(TRANSFER_CANCELLED_EVENT, ()).unwrap();
+        BYTES_TRANSFERRED.store(0, std::sync::atomic::Ordering::Relaxed);
+        return Ok(false);
+    }
+    result.map_err(|e| format!("failed to receive files: {}", e))?;
 
     let was_interrupted = interrupted.load(std::sync::atomic::Ordering::Relaxed);
 
@@ -181,6 +296,8 @@ enum UploadResult {
     Success,
     /// The file transfer was cancelled by the sender
     Cancelled,
+    /// The file transfer was cancelled by the receiver
+    PeerCancelled,
     /// The files were rejected by the receiver
     Rejected,
 }
@@ -188,12 +305,13 @@ enum UploadResult {
 #[tauri::command(async)]
 async fn upload_files(window: tauri::Window, files: Vec<PathBuf>) -> Result<UploadResult, String> {
     BYTES_TRANSFERRED.store(0, std::sync::atomic::Ordering::Relaxed);
+    PAUSE.resume();
 
     let secret_key = SecretKey::generate(rand::rngs::OsRng);
 
     let endpoint = Endpoint::builder()
         .secret_key(secret_key)
-        .alpns(vec![QS_ALPN.to_vec()])
+        .alpns(alpns())
         .relay_mode(RelayMode::Default)
         .bind()
         .await
@@ -209,7 +327,17 @@ async fn upload_files(window: tauri::Window, files: Vec<PathBuf>) -> Result<Uplo
 
     window.emit(TICKET_EVENT, ticket).unwrap();
 
-    let mut sender = Sender::connect(endpoint, SenderArgs { files })
+    let sender_args = SenderArgs {
+        files,
+        symlinks: SymlinkPolicy::default(),
+        concurrency: DEFAULT_CONCURRENCY,
+        compression: Compression::default(),
+        rate_limit: RATE_LIMIT.clone(),
+        pause: PAUSE.clone(),
+        reconnect_timeout: DEFAULT_RECONNECT_TIMEOUT,
+    };
+
+    let mut sender = Sender::connect(endpoint, sender_args)
         .await
         .map_err(|e| format!("failed to connect to receiver: {}", e))?;
 
@@ -228,7 +356,7 @@ async fn upload_files(window: tauri::Window, files: Vec<PathBuf>) -> Result<Uplo
         interrupted_clone.store(true, std::sync::atomic::Ordering::Relaxed);
     });
 
-    sender
+    let result = sender
         .send_files(
             || {},
             |accepted| {
@@ -250,13 +378,19 @@ async fn upload_files(window: tauri::Window, files: Vec<PathBuf>) -> Result<Uplo
                     )
                     .unwrap();
             },
-            &mut |bytes_sent| {
+            &mut |_, bytes_sent| {
                 BYTES_TRANSFERRED.fetch_add(bytes_sent, std::sync::atomic::Ordering::Relaxed);
             },
             &mut || !interrupted.load(std::sync::atomic::Ordering::Relaxed),
+            &mut |state| emit_connection_state(&window, state),
         )
-        .await
-        .map_err(|e| format!("failed to send files: {}", e))?;
+        .await;
+
+    if let Err(SendError::PeerCancelled { .. }) = result {
+        window.emit(TRANSFER_CANCELLED_EVENT, ()).unwrap();
+        return Ok(UploadResult::PeerCancelled);
+    }
+    result.map_err(|e| format!("failed to send files: {}", e))?;
 
     let was_interrupted = interrupted.load(std::sync::atomic::Ordering::Relaxed);
 
@@ -321,6 +455,9 @@ pub fn run() {
         .invoke_handler(tauri::generate_handler![
             exit,
             bytes_transferred,
+            set_rate_limit,
+            pause_transfer,
+            resume_transfer,
             download_files,
             file_info,
             upload_files,
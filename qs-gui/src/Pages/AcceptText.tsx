@@ -0,0 +1,34 @@
+interface AcceptTextProps {
+    text: string
+    acceptText: (accepted: boolean) => void
+}
+
+function AcceptText(props: AcceptTextProps) {
+    return (
+        <div class="accept-files">
+            <h3 class="text-center" style={{ "margin-top": "2rem" }}>
+                Text offered
+            </h3>
+            <textarea class="accept-text-content" readOnly value={props.text} />
+            <div class="file-choice">
+                <button
+                    class="file-choice-button file-choice-reject"
+                    onClick={() => props.acceptText(false)}
+                >
+                    Reject
+                </button>
+                <button
+                    class="file-choice-button file-choice-accept"
+                    onClick={() => {
+                        navigator.clipboard.writeText(props.text)
+                        props.acceptText(true)
+                    }}
+                >
+                    Copy
+                </button>
+            </div>
+        </div>
+    )
+}
+
+export default AcceptText
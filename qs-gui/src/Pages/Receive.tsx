@@ -3,6 +3,7 @@ import Loading from "../Components/Loading"
 import { invoke } from "@tauri-apps/api/core"
 import { Event, listen } from "@tauri-apps/api/event"
 import AcceptFiles from "./AcceptFiles"
+import AcceptText from "./AcceptText"
 import TransferFiles from "./TransferFiles"
 import { Window } from "@tauri-apps/api/window"
 
@@ -10,9 +11,11 @@ import { sendNotification } from "@tauri-apps/plugin-notification"
 import { setStore, store } from "../App"
 import {
     ACCEPT_FILES_EVENT,
+    ACCEPT_TEXT_EVENT,
     CONNECTED_TO_SERVER_EVENT,
     CONNECTED_WITH_CONN_TYPE,
     FILES_OFFERED_EVENT,
+    TEXT_OFFERED_EVENT,
 } from "../events"
 
 export enum ReceiveState {
@@ -20,6 +23,7 @@ export enum ReceiveState {
     ConnectingToSender = "R_connecting-to-sender",
     WaitingForFiles = "R_waiting-for-files",
     FilesOffered = "R_files-offered",
+    TextOffered = "R_text-offered",
     DownloadingFiles = "R_downloading-files",
 }
 
@@ -28,6 +32,10 @@ interface FilesOfferedEvent {
     files: [string, number, boolean][]
 }
 
+interface TextOfferedEvent {
+    text: string
+}
+
 interface ReceiveProps {
     code: string
     onError(error: string): void
@@ -35,6 +43,7 @@ interface ReceiveProps {
 
 function Receive(props: ReceiveProps) {
     const [files, setFiles] = createSignal<[string, number, boolean][]>([])
+    const [text, setText] = createSignal("")
     const [transferMode, setTransferMode] = createSignal<
         "direct" | "mixed" | "relay" | null
     >(null)
@@ -73,10 +82,19 @@ function Receive(props: ReceiveProps) {
         },
     )
 
+    const unlisten4 = listen(
+        TEXT_OFFERED_EVENT,
+        (event: Event<TextOfferedEvent>) => {
+            setStore("currentState", ReceiveState.TextOffered)
+            setText(event.payload.text)
+        },
+    )
+
     onCleanup(async () => {
         ;(await unlisten1)()
         ;(await unlisten2)()
         ;(await unlisten3)()
+        ;(await unlisten4)()
     })
 
     return (
@@ -104,6 +122,14 @@ function Receive(props: ReceiveProps) {
                         }
                     }}
                 />
+            ) : store.currentState === ReceiveState.TextOffered ? (
+                <AcceptText
+                    text={text()}
+                    acceptText={(accepted) => {
+                        Window.getCurrent().emit(ACCEPT_TEXT_EVENT, accepted)
+                        setStore("currentState", null)
+                    }}
+                />
             ) : store.currentState === ReceiveState.DownloadingFiles ? (
                 <TransferFiles
                     files={files()}
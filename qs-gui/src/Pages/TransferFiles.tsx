@@ -7,8 +7,10 @@ import { invoke } from "@tauri-apps/api/core"
 import { setStore } from "../App"
 import {
     CANCEL_TRANSFER_EVENT,
+    CONNECTION_STATE_EVENT,
     INITIAL_PROGRESS_EVENT,
     TRANSFER_FINISHED_EVENT,
+    TRANSFER_PAUSED_EVENT,
 } from "../events"
 
 const SPEED_HISTORY_WINDOW_MS = 30_000
@@ -30,6 +32,15 @@ interface TransferProgressEvent {
     data: [string, number, number][]
 }
 
+interface TransferPausedEvent {
+    /// Paused by this side
+    local: boolean
+    /// Paused by the other peer
+    remote: boolean
+}
+
+type ConnectionState = "lost" | "reconnecting" | "resumed"
+
 function TransferFiles(props: TransferFilesProps) {
     const [initialProgress, setInitialProgress] = createSignal<number>(0)
     const [downloaded, setDownloaded] = createSignal<number>(0)
@@ -47,6 +58,13 @@ function TransferFiles(props: TransferFilesProps) {
     const [transferSpeedBps, setTransferSpeedBps] = createSignal<number>(0)
     const [totalRemainingSecs, setTotalRemainingSecs] = createSignal<number>(0)
 
+    const [paused, setPaused] = createSignal<TransferPausedEvent>({
+        local: false,
+        remote: false,
+    })
+    const [connectionState, setConnectionState] =
+        createSignal<ConnectionState>("resumed")
+
     const speedIntervalId = setInterval(() => {
         const now = Date.now()
         const nowDownloaded = downloaded()
@@ -99,6 +117,34 @@ function TransferFiles(props: TransferFilesProps) {
         setDownloaded(totalSize() - initialProgress())
     })
 
+    const unlisten3 = listen(
+        TRANSFER_PAUSED_EVENT,
+        (event: Event<TransferPausedEvent>) => {
+            setPaused(event.payload)
+        },
+    )
+
+    const unlisten4 = listen(
+        CONNECTION_STATE_EVENT,
+        (event: Event<ConnectionState>) => {
+            setConnectionState(event.payload)
+        },
+    )
+
+    /// Shown above the files while the transfer is not running
+    const transferState = () => {
+        if (connectionState() != "resumed") {
+            return "Connection lost, reconnecting..."
+        }
+        if (paused().local) {
+            return "Paused"
+        }
+        if (paused().remote) {
+            return "Paused by the other peer"
+        }
+        return null
+    }
+
     const progressUpdaterId = setInterval(async () => {
         let downloaded: number = await invoke("bytes_transferred")
         setDownloaded(downloaded)
@@ -108,6 +154,8 @@ function TransferFiles(props: TransferFilesProps) {
         console.log("Start transfer cleanup")
         ;(await unlisten1)()
         ;(await unlisten2)()
+        ;(await unlisten3)()
+        ;(await unlisten4)()
 
         clearInterval(progressUpdaterId)
         clearInterval(speedIntervalId)
@@ -158,6 +206,9 @@ function TransferFiles(props: TransferFilesProps) {
             <h3 class="text-center" style={{ "margin-top": "2rem" }}>
                 {props.type == "send" ? "Sending files" : "Receiving files"}
             </h3>
+            {transferState() && (
+                <div class="transfer-state">{transferState()}</div>
+            )}
             <div class="file-list">
                 {props.files.map((file, index) => {
                     return (
@@ -197,6 +248,18 @@ function TransferFiles(props: TransferFilesProps) {
                 </div>
             </div>
             <div class="cancel-div">
+                <button
+                    class="file-choice-button transfer-pause"
+                    onClick={async () => {
+                        if (paused().local) {
+                            await invoke("resume_transfer")
+                        } else {
+                            await invoke("pause_transfer")
+                        }
+                    }}
+                >
+                    {paused().local ? "Resume" : "Pause"}
+                </button>
                 <button
                     class="file-choice-button file-choice-reject"
                     onClick={() => {
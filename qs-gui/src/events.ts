@@ -4,7 +4,11 @@ export const CONNECTED_WITH_CONN_TYPE = "receiver-connected"
 export const CANCEL_TRANSFER_EVENT = "cancel-transfer"
 export const FILES_DECISION_EVENT = "files-decision"
 export const ACCEPT_FILES_EVENT = "accept-files"
+export const TEXT_OFFERED_EVENT = "text-offered"
+export const ACCEPT_TEXT_EVENT = "accept-text"
 export const TRANSFER_CANCELLED_EVENT = "transfer-cancelled"
 export const TRANSFER_FINISHED_EVENT = "transfer-finished"
 export const TICKET_EVENT = "server-connection-code"
 export const CONNECTED_TO_SERVER_EVENT = "server-connected"
+export const CONNECTION_STATE_EVENT = "connection-state"
+export const TRANSFER_PAUSED_EVENT = "transfer-paused"